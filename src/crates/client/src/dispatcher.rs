@@ -0,0 +1,135 @@
+//! The dispatcher scripts the installer writes in place of each hook. Git only runs one executable
+//! per hook, so the dispatcher runs every hook in `hooks/<hook>.d/`: the Global Graph hook, and any
+//! hook the repository had before.
+
+use git2::ErrorCode;
+use git2::Repository;
+use failure::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The marker every dispatcher script carries, followed by its version since version 2.
+pub const DISPATCHER_MARKER: &str = "#HOOK_DISPATCH";
+
+/// The version of the dispatcher written by `dispatcher_script`. The installer replaces dispatchers
+/// with an older version.
+pub const DISPATCHER_VERSION: u32 = 3;
+
+/// The git config value recording the hooks directory `core.hooksPath` pointed to before the
+/// installer pointed it at the repository's hooks directory (see `crate::hooks_directory`).
+/// Dispatchers run the hook of the same name in that directory after their own, so shared hooks
+/// (eg. installed by a hook manager such as Husky) keep running.
+pub const CHAINED_HOOKS_PATH_CONFIG: &str = "globalgraph.chainedhookspath";
+
+/// The hooks directory the dispatchers chain to, or None if `core.hooksPath` wasn't set when the
+/// client was installed.
+pub fn chained_hooks_directory(repo: &Repository) -> Result<Option<PathBuf>, Error> {
+    match repo.config()?.get_path(CHAINED_HOOKS_PATH_CONFIG) {
+        Ok(path) => Ok(Some(resolve_hooks_path(repo, &path))),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Resolves a hooks directory read from the git config. Git runs hooks from the root of the working
+/// tree, so relative paths are relative to it.
+pub fn resolve_hooks_path(repo: &Repository, path: &Path) -> PathBuf {
+    match repo.workdir() {
+        Some(workdir) if path.is_relative() => workdir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Whether the path is the repository's hooks directory (see `crate::hooks_directory`).
+pub fn is_hooks_directory(repo: &Repository, path: &Path) -> bool {
+    let hooks_directory = crate::hooks_directory(repo);
+    match (fs::canonicalize(path), fs::canonicalize(&hooks_directory)) {
+        (Ok(path), Ok(hooks_directory)) => path == hooks_directory,
+        _ => path == hooks_directory,
+    }
+}
+
+/// Returns the version of a dispatcher script, or None if the script is not a dispatcher. The first
+/// dispatchers carried no version, and count as version 1.
+pub fn dispatcher_version(hook_contents: &str) -> Option<u32> {
+    for line in hook_contents.lines().map(|line| line.trim()) {
+        if !line.starts_with(DISPATCHER_MARKER) {
+            continue;
+        }
+
+        let version = line[DISPATCHER_MARKER.len()..].trim();
+        if version.is_empty() {
+            return Some(1);
+        }
+        if version.starts_with("version=") {
+            return version["version=".len()..].parse().ok();
+        }
+    }
+    None
+}
+
+/// Whether a failing hook should stop the git operation. Git ignores the exit code of the `post-*`
+/// hooks, so the dispatcher runs all of them regardless.
+fn is_blocking(hook_name: &str) -> bool {
+    !hook_name.starts_with("post-")
+}
+
+/// The dispatcher script for a hook. It passes the arguments and input git gave the hook to each
+/// hook in `<hook>.d/`, then to the chained hook if there is one (see `CHAINED_HOOKS_PATH_CONFIG`).
+/// It finds the hooks through git, so it works from any directory and in linked worktrees.
+pub fn dispatcher_script(hook_name: &str) -> String {
+    let on_failure = if is_blocking(hook_name) {
+        "exit \"$hook_status\""
+    } else {
+        "status=\"$hook_status\""
+    };
+
+    format!(r#"#!/bin/sh
+{marker} version={version}
+# Runs every hook in {hook}.d/, then the {hook} hook in {chained_config} if it is set,
+# with the arguments and input git passed to this hook.
+# Written by the Global Graph installer, which replaces it when a newer version is available.
+
+hook_directory="$(git rev-parse --git-path hooks)/{hook}.d"
+chained_hooks="$(git config --path {chained_config})"
+input="$(mktemp)" || exit 1
+trap 'rm -f "$input"' EXIT
+cat > "$input"
+
+status=0
+run_hook() {{
+    hook_path="$1"
+    shift
+    if [ -f "$hook_path" ] && [ -x "$hook_path" ]; then
+        "$hook_path" "$@" < "$input"
+        hook_status=$?
+        if [ "$hook_status" -ne 0 ]; then
+            {on_failure}
+        fi
+    fi
+}}
+
+for hook in "$hook_directory"/*; do
+    run_hook "$hook" "$@"
+done
+if [ -n "$chained_hooks" ]; then
+    run_hook "$chained_hooks/{hook}" "$@"
+fi
+exit "$status"
+"#, marker = DISPATCHER_MARKER, version = DISPATCHER_VERSION, hook = hook_name, on_failure = on_failure,
+       chained_config = CHAINED_HOOKS_PATH_CONFIG)
+}
+
+/// The name of the Global Graph hook in each `<hook>.d/` directory.
+pub const HOOK_SCRIPT_NAME: &str = "globalgraph";
+
+/// The Global Graph hook the installer writes to `<hook>.d/` (see `HOOK_SCRIPT_NAME`). It runs the
+/// hook with the client executable installed in the hooks directory (see
+/// `crate::client_executable_path`), so there is a single executable to install and update.
+pub fn hook_script(hook_name: &str) -> String {
+    format!(r#"#!/bin/sh
+# Runs the Global Graph {hook} hook. Written by the Global Graph installer.
+exec "$(dirname "$0")/../{executable}" hook {hook} "$@"
+"#, hook = hook_name, executable = crate::CLIENT_EXECUTABLE_NAME)
+}
@@ -0,0 +1,461 @@
+//! Configuration diagnostics for a client repository. Each check reports what is wrong and the
+//! command that fixes it, since setup problems otherwise only show up as hook errors.
+
+use git2::BranchType;
+use git2::Direction;
+use git2::Oid;
+use git2::Repository;
+use failure::Error;
+use shared::ClientSyncConfig;
+use shared::ReferencePath;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use url::Url;
+use crate::dispatcher::{dispatcher_version, hook_script, DISPATCHER_VERSION, HOOK_SCRIPT_NAME};
+
+/// The hooks the installer can set up, and whether synchronization depends on them.
+const HOOKS: &[(&str, bool)] = &[
+    ("post-commit", true),
+    ("post-merge", false),
+    ("post-rewrite", false),
+    ("pre-commit", false),
+    ("pre-push", false),
+    ("reference-transaction", false),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Passed,
+    /// Global Graph works, but something is missing or out of date.
+    Warning,
+    /// Global Graph can't work until this is fixed.
+    Failed,
+}
+
+/// The result of a single check.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub check: String,
+    pub status: CheckStatus,
+    pub message: String,
+    /// What to do about a warning or failure.
+    pub fix: Option<String>,
+}
+
+impl Diagnostic {
+    fn passed(check: &str, message: String) -> Diagnostic {
+        Diagnostic { check: check.to_string(), status: CheckStatus::Passed, message, fix: None }
+    }
+
+    fn warning(check: &str, message: String, fix: String) -> Diagnostic {
+        Diagnostic { check: check.to_string(), status: CheckStatus::Warning, message, fix: Some(fix) }
+    }
+
+    fn failed(check: &str, message: String, fix: String) -> Diagnostic {
+        Diagnostic { check: check.to_string(), status: CheckStatus::Failed, message, fix: Some(fix) }
+    }
+}
+
+/// Runs every check against the repository. Checks that depend on a failed check are skipped.
+pub fn run_diagnostics(repo: &Repository) -> Vec<Diagnostic> {
+    let mut diagnostics = vec!();
+
+    diagnostics.push(check_user_name(repo));
+    diagnostics.push(check_team_config(repo));
+    let repo_uuid = check_repo_uuid(repo, &mut diagnostics);
+    let server_ok = check_server_config(repo, &mut diagnostics);
+
+    if server_ok {
+        diagnostics.push(check_query_server(repo));
+    }
+
+    if let Some(remote_references) = check_remote(repo, &mut diagnostics) {
+        if let Some(repo_uuid) = repo_uuid {
+            diagnostics.push(check_namespace(repo, &ClientSyncConfig { repo_uuid, worktree: crate::linked_worktree_name(repo) }, &remote_references));
+        }
+    }
+
+    diagnostics.push(check_hooks_path(repo));
+    for &(hook_name, required) in HOOKS {
+        diagnostics.extend(check_hook(repo, hook_name, required));
+    }
+    diagnostics.push(check_client_executable(repo));
+    diagnostics.push(check_client_update(repo));
+    diagnostics.push(check_install_manifest(repo));
+
+    diagnostics
+}
+
+fn check_user_name(repo: &Repository) -> Diagnostic {
+    let check = "user.name";
+    match repo.config().and_then(|config| config.get_string("user.name")) {
+        Ok(ref name) if !name.trim().is_empty() =>
+            Diagnostic::passed(check, format!("user.name is [{}].", name)),
+        _ => Diagnostic::failed(check,
+            "The git config value 'user.name' is not set. It is used to name this repository in the global graph.".to_string(),
+            "git config --global user.name \"Your Name\"".to_string()),
+    }
+}
+
+/// Returns the repository UUID if it is valid.
+fn check_repo_uuid(repo: &Repository, diagnostics: &mut Vec<Diagnostic>) -> Option<String> {
+    let check = "globalgraph.repouuid";
+    match repo.config().and_then(|config| config.get_string("globalgraph.repouuid")) {
+        Ok(repo_uuid) => match shared::break_repo_uuid(&repo_uuid) {
+            Ok(_) => {
+                diagnostics.push(Diagnostic::passed(check, format!("The repository UUID is [{}].", repo_uuid)));
+                Some(repo_uuid)
+            }
+            Err(_) => {
+                diagnostics.push(Diagnostic::failed(check,
+                    format!("The repository UUID [{}] is not of the form <username>_<machine>_<id>.", repo_uuid),
+                    "git config --unset globalgraph.repouuid (a new UUID is generated on the next commit)".to_string()));
+                None
+            }
+        },
+        Err(_) => {
+            diagnostics.push(Diagnostic::warning(check,
+                "The repository has no UUID yet, so it hasn't been synchronized with the global graph.".to_string(),
+                "Make a commit, or run the sync agent: .git/hooks/globalgraph sync run".to_string()));
+            None
+        }
+    }
+}
+
+/// Checks that `.globalgraph.toml`, if the repository has one, can be read.
+fn check_team_config(repo: &Repository) -> Diagnostic {
+    let check = crate::team_config::TEAM_CONFIG_FILE;
+    match crate::team_config::read_team_config(repo) {
+        Ok(_) if crate::team_config::team_config_path(repo).map_or(false, |path| path.exists()) =>
+            Diagnostic::passed(check, "The team configuration is valid. Local git config values override it.".to_string()),
+        Ok(_) => Diagnostic::passed(check, "The repository has no team configuration, every setting comes from the local git config.".to_string()),
+        Err(e) => Diagnostic::failed(check, crate::error_chain(&e), format!("Fix the syntax of [{}].", check)),
+    }
+}
+
+/// Returns whether the query server url, `globalgraph.server` or `server` in `.globalgraph.toml`, is
+/// a valid url.
+fn check_server_config(repo: &Repository, diagnostics: &mut Vec<Diagnostic>) -> bool {
+    let check = "globalgraph.server";
+    let fix = format!("git config globalgraph.server <query server url, eg. https://server.com:12345>, or set `server` in [{}]", crate::team_config::TEAM_CONFIG_FILE);
+    match crate::team_config::query_server(repo) {
+        Ok(Some(server)) => match Url::parse(&server) {
+            Ok(_) => {
+                diagnostics.push(Diagnostic::passed(check, format!("The query server is [{}].", server)));
+                true
+            }
+            Err(e) => {
+                diagnostics.push(Diagnostic::failed(check, format!("[{}] is not a valid url: {}", server, e), fix));
+                false
+            }
+        },
+        Ok(None) => {
+            diagnostics.push(Diagnostic::failed(check, "The query server url is not set.".to_string(), fix));
+            false
+        }
+        Err(e) => {
+            diagnostics.push(Diagnostic::failed(check, crate::error_chain(&e), fix));
+            false
+        }
+    }
+}
+
+fn check_query_server(repo: &Repository) -> Diagnostic {
+    let check = "query server";
+    // Asking about no files still exercises the whole request.
+    let result = crate::query::head_commit(repo)
+        .and_then(|head| crate::query::find_unintegrated_changes(repo, crate::query::NextCommit::on(head), vec!()));
+
+    match result {
+        Ok(_) => Diagnostic::passed(check, "The query server answered.".to_string()),
+        Err(e) => Diagnostic::failed(check,
+            format!("The query server didn't answer: {}", crate::error_chain(&e)),
+            "Check that the query server is running and reachable, and that globalgraph.server points to it.".to_string()),
+    }
+}
+
+/// Connects to the global graph remote for pushing. Returns the references it advertised, if the
+/// connection succeeded.
+fn check_remote(repo: &Repository, diagnostics: &mut Vec<Diagnostic>) -> Option<HashMap<String, Oid>> {
+    let check = "globalgraph remote";
+    let mut remote = match crate::team_config::global_graph_remote(repo) {
+        Ok(remote) => remote,
+        Err(e) => {
+            diagnostics.push(Diagnostic::failed(check, crate::error_chain(&e),
+                format!("git remote add {} <global graph repository url>, or set `remote` in [{}]", shared::GLOBALGRAPH_REPO_NAME, crate::team_config::TEAM_CONFIG_FILE)));
+            return None;
+        }
+    };
+    let url = remote.url().unwrap_or("<invalid UTF8>").to_string();
+
+    if let Err(e) = remote.connect(Direction::Push) {
+        diagnostics.push(Diagnostic::failed(check,
+            format!("Could not connect to [{}] for pushing: {}", url, e),
+            format!("Check the url (git remote set-url {} <url>), your network connection and your credentials. \
+                `git push --dry-run {} HEAD:refs/heads/doctor` shows what git itself reports.", shared::GLOBALGRAPH_REPO_NAME, shared::GLOBALGRAPH_REPO_NAME)));
+        return None;
+    }
+
+    let references = match remote.list() {
+        Ok(heads) => heads.iter().map(|head| (head.name().to_string(), head.oid())).collect(),
+        Err(e) => {
+            diagnostics.push(Diagnostic::failed(check,
+                format!("Connected to [{}], but could not list its references: {}", url, e),
+                "Check that the url points to the global graph repository.".to_string()));
+            return None;
+        }
+    };
+    remote.disconnect();
+
+    diagnostics.push(Diagnostic::passed(check, format!("Connected to [{}] for pushing, and listed its references.", url)));
+    Some(references)
+}
+
+/// Compares the local branches with this repository's namespace in the global graph.
+fn check_namespace(repo: &Repository, config: &ClientSyncConfig, remote_references: &HashMap<String, Oid>) -> Diagnostic {
+    let check = "global graph namespace";
+    let mut problems = vec!();
+    let mut expected = vec!();
+
+    let branches = match repo.branches(Some(BranchType::Local)) {
+        Ok(branches) => branches,
+        Err(e) => return Diagnostic::failed(check, format!("Could not list local branches: {}", e), "Check the repository with `git fsck`.".to_string()),
+    };
+    let branch_filter = match crate::team_config::branch_filter(repo) {
+        Ok(branch_filter) => branch_filter,
+        Err(e) => return Diagnostic::failed(check, crate::error_chain(&e), "Fix the branch patterns in the team configuration or the local git config.".to_string()),
+    };
+
+    for branch in branches {
+        let (branch, _) = match branch {
+            Ok(branch) => branch,
+            Err(_) => continue,
+        };
+        match branch.get().shorthand().map(|name| branch_filter.is_synchronized(name)) {
+            Some(Ok(true)) => {}
+            _ => continue,
+        }
+        let (local, target) = match (branch.get().name(), branch.get().target()) {
+            (Some(name), Some(target)) => (ReferencePath::new(name), target),
+            _ => continue,
+        };
+        let global = match config.map_branch_to_global(&local) {
+            Ok(global) => global,
+            Err(_) => continue,
+        };
+
+        match remote_references.get(&*global) {
+            Some(global_target) if *global_target == target => {}
+            Some(_) => problems.push(format!("[{}] points to a different commit than [{}].", global, local)),
+            None => problems.push(format!("[{}] is missing for the local branch [{}].", global, local)),
+        }
+        expected.push(global.to_string());
+    }
+
+    let namespace = format!("refs/heads/{}/", config.repo_uuid);
+    for name in remote_references.keys() {
+        if name.starts_with(&namespace) && !expected.contains(name) {
+            problems.push(format!("[{}] has no local branch. It was deleted locally since the last synchronization.", name));
+        }
+    }
+
+    if problems.is_empty() {
+        return Diagnostic::passed(check, format!("All local branches are synchronized under [{}].", namespace));
+    }
+
+    let mut message = "The global graph is out of date:".to_string();
+    for problem in problems {
+        message.push_str("\n  ");
+        message.push_str(&problem);
+    }
+    if let Ok(state) = crate::sync_queue::SyncQueue::open(repo).and_then(|queue| queue.state()) {
+        if let Some(error) = state.last_error {
+            message.push_str(&format!("\n  The last synchronization failed with: {}", error));
+        }
+    }
+
+    Diagnostic::warning(check, message,
+        format!("Run the sync agent: {} sync run (and `sync status` to see the sync queue)", crate::client_executable_path(repo).to_string_lossy()))
+}
+
+/// Checks that git runs the hooks in the directory the installer puts them in. Hook managers set
+/// `core.hooksPath` to a directory of their own, which the installer chains to instead.
+fn check_hooks_path(repo: &Repository) -> Diagnostic {
+    let check = "core.hooksPath";
+    let hooks_directory = crate::hooks_directory(repo);
+    let hooks_path = match repo.config().and_then(|config| config.get_path("core.hooksPath")) {
+        Ok(path) => crate::dispatcher::resolve_hooks_path(repo, &path),
+        Err(_) => return Diagnostic::passed(check, format!("Git runs the hooks in [{}].", hooks_directory.to_string_lossy())),
+    };
+
+    if !crate::dispatcher::is_hooks_directory(repo, &hooks_path) {
+        return Diagnostic::failed(check,
+            format!("core.hooksPath is [{}], so git runs the hooks there instead of the Global Graph hooks in [{}]. A hook manager may have set it again.",
+                    hooks_path.to_string_lossy(), hooks_directory.to_string_lossy()),
+            "Re-run the Global Graph installer, which keeps running the hooks in core.hooksPath.".to_string());
+    }
+
+    match crate::dispatcher::chained_hooks_directory(repo) {
+        Ok(Some(chained)) => Diagnostic::passed(check,
+            format!("Git runs the hooks in [{}], which also run the hooks in [{}].", hooks_directory.to_string_lossy(), chained.to_string_lossy())),
+        _ => Diagnostic::passed(check, format!("Git runs the hooks in [{}].", hooks_directory.to_string_lossy())),
+    }
+}
+
+/// Checks the dispatcher script and the Global Graph hook script of a hook.
+fn check_hook(repo: &Repository, hook_name: &str, required: bool) -> Vec<Diagnostic> {
+    let check = format!("{} hook", hook_name);
+    let hooks_directory = crate::hooks_directory(repo);
+    let dispatcher_path = hooks_directory.join(hook_name);
+    let hook_path = hooks_directory.join(format!("{}.d", hook_name)).join(HOOK_SCRIPT_NAME);
+    let reinstall = "Re-run the Global Graph installer for this repository.".to_string();
+
+    let hook_contents = match fs::read(&hook_path) {
+        Ok(contents) => contents,
+        Err(_) => {
+            let message = format!("The {} hook is not installed at [{}].", hook_name, hook_path.to_string_lossy());
+            return vec!(if required {
+                Diagnostic::failed(&check, message, reinstall)
+            } else {
+                Diagnostic::warning(&check, message, format!("{} (optional: {})", reinstall, hook_purpose(hook_name)))
+            });
+        }
+    };
+
+    let mut diagnostics = vec!(if hook_contents != hook_script(hook_name).as_bytes() {
+        Diagnostic::warning(&check,
+            format!("[{}] is not the current hook script. It was installed by an older client, which installed a binary per hook.", hook_path.to_string_lossy()),
+            reinstall.clone())
+    } else if !is_executable(&hook_path) {
+        Diagnostic::failed(&check,
+            format!("[{}] is not executable, so the dispatcher skips it.", hook_path.to_string_lossy()),
+            format!("chmod +x {}", hook_path.to_string_lossy()))
+    } else {
+        Diagnostic::passed(&check, format!("[{}] runs the client executable.", hook_path.to_string_lossy()))
+    });
+
+    let dispatcher_check = format!("{} dispatcher", hook_name);
+    diagnostics.push(match fs::read_to_string(&dispatcher_path) {
+        Ok(contents) => {
+            let version = dispatcher_version(&contents);
+            if version.is_none() || !contents.contains(&format!("{}.d", hook_name)) {
+                Diagnostic::failed(&dispatcher_check,
+                    format!("[{}] doesn't run the hooks in [{}.d/], so the Global Graph hook never runs.", dispatcher_path.to_string_lossy(), hook_name),
+                    format!("Move any custom hook into [{}.d/], delete [{}], and re-run the installer.", hook_name, dispatcher_path.to_string_lossy()))
+            } else if version < Some(DISPATCHER_VERSION) {
+                Diagnostic::warning(&dispatcher_check,
+                    format!("[{}] is an outdated dispatcher (version [{}], the current version is [{}]). Dispatchers before version 2 don't pass the hook's arguments and input on, and ignore failures. Before version 3, they don't run the hooks in core.hooksPath.",
+                        dispatcher_path.to_string_lossy(), version.unwrap_or(1), DISPATCHER_VERSION),
+                    reinstall.clone())
+            } else if !is_executable(&dispatcher_path) {
+                Diagnostic::failed(&dispatcher_check,
+                    format!("[{}] is not executable, so git skips it.", dispatcher_path.to_string_lossy()),
+                    format!("chmod +x {}", dispatcher_path.to_string_lossy()))
+            } else {
+                Diagnostic::passed(&dispatcher_check, format!("[{}] dispatches to [{}.d/].", dispatcher_path.to_string_lossy(), hook_name))
+            }
+        }
+        Err(_) => Diagnostic::failed(&dispatcher_check,
+            format!("The dispatcher script [{}] is missing, so git never runs the Global Graph hook.", dispatcher_path.to_string_lossy()),
+            reinstall),
+    });
+
+    diagnostics
+}
+
+fn hook_purpose(hook_name: &str) -> &'static str {
+    match hook_name {
+        "pre-commit" => "checks commits for conflicts",
+        "pre-push" => "checks pushes for conflicts",
+        "post-merge" => "synchronizes after merges and pulls",
+        "post-rewrite" => "synchronizes after rebases and amends",
+        "reference-transaction" => "synchronizes after resets and other branch moves",
+        _ => "synchronizes with the global graph",
+    }
+}
+
+/// Checks the executable every hook runs, which also runs the sync agent.
+fn check_client_executable(repo: &Repository) -> Diagnostic {
+    let check = "client executable";
+    let executable_path = crate::client_executable_path(repo);
+    if !executable_path.exists() {
+        return Diagnostic::failed(check,
+            format!("The client executable is not installed at [{}], so none of the hooks can run.", executable_path.to_string_lossy()),
+            "Re-run the Global Graph installer for this repository.".to_string());
+    }
+    check_binary_version(check, &executable_path)
+}
+
+/// Reports whether the client updates itself, and why the last update failed.
+fn check_client_update(repo: &Repository) -> Diagnostic {
+    let check = "client update";
+    if crate::update::UPDATE_PUBLIC_KEY.is_none() {
+        return Diagnostic::passed(check, "This client was built without an update key, so it only changes when the installer runs.".to_string());
+    }
+
+    if let Some(error) = crate::update::read_state(repo).last_error {
+        return Diagnostic::warning(check, format!("The last client update failed: {}", error),
+            format!("Update now to retry: {} update run", crate::client_executable_path(repo).to_string_lossy()));
+    }
+
+    match crate::update::pinned_version(repo) {
+        Ok(Some(version)) => Diagnostic::passed(check, format!("The client is pinned to version [{}] ({}).", version, crate::update::PINNED_VERSION_CONFIG)),
+        _ => Diagnostic::passed(check, "The client updates itself to the version the query server hosts.".to_string()),
+    }
+}
+
+/// Compares the installation with the install manifest the installer wrote (see `crate::manifest`).
+fn check_install_manifest(repo: &Repository) -> Diagnostic {
+    let check = "install manifest";
+    let repair = "Restore the installation: installer repair".to_string();
+    let manifest = match crate::manifest::read_manifest(repo) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => return Diagnostic::warning(check, "Global Graph was installed without an install manifest, so changes to the installation can't be detected.".to_string(),
+            "Re-run the Global Graph installer for this repository.".to_string()),
+        Err(e) => return Diagnostic::failed(check, crate::error_chain(&e), "Re-run the Global Graph installer for this repository.".to_string()),
+    };
+
+    match crate::manifest::find_problems(repo, &manifest) {
+        Ok(ref problems) if problems.is_empty() =>
+            Diagnostic::passed(check, format!("The [{}] installed files and [{}] config values are unchanged.", manifest.files.len(), manifest.config.len())),
+        Ok(problems) => Diagnostic::warning(check,
+            problems.iter().map(|problem| problem.to_string()).collect::<Vec<_>>().join(" "),
+            repair),
+        Err(e) => Diagnostic::failed(check, crate::error_chain(&e), repair),
+    }
+}
+
+/// Compares the version embedded in an installed binary with the version of this binary.
+fn check_binary_version(check: &str, binary_path: &PathBuf) -> Diagnostic {
+    let reinstall = "Re-run the Global Graph installer for this repository.".to_string();
+    match installed_binary_version(binary_path) {
+        Ok(Some(ref version)) if version == crate::VERSION =>
+            Diagnostic::passed(check, format!("[{}] is version [{}].", binary_path.to_string_lossy(), version)),
+        Ok(Some(version)) => Diagnostic::warning(check,
+            format!("[{}] is version [{}], but this client is version [{}].", binary_path.to_string_lossy(), version, crate::VERSION),
+            reinstall),
+        Ok(None) => Diagnostic::warning(check,
+            format!("[{}] has no version marker. It was installed by an older client, or is not a Global Graph binary.", binary_path.to_string_lossy()),
+            reinstall),
+        Err(e) => Diagnostic::failed(check, format!("Could not read [{}]: {}", binary_path.to_string_lossy(), e), reinstall),
+    }
+}
+
+/// Reads the client version embedded in a binary (see `VERSION_MARKER` in the `globalgraph`
+/// executable), or None if it has none.
+pub fn installed_binary_version(binary_path: &Path) -> Result<Option<String>, Error> {
+    Ok(shared::find_client_version(&fs::read(binary_path)?))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).map(|metadata| metadata.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
@@ -0,0 +1,73 @@
+//! The git hooks of the client. They are all run by the `globalgraph` executable (see
+//! `src/tools/globalgraph.rs`), either through `globalgraph hook <hook>`, which the hook scripts the
+//! installer writes call (see `crate::dispatcher::hook_script`), or when the executable itself is
+//! named after the hook.
+
+use crate::team_config::FailurePolicy;
+use failure::Error;
+use failure::format_err;
+use git2::Repository;
+use log::warn;
+use std::env;
+
+mod post_commit;
+mod post_merge;
+mod post_rewrite;
+mod pre_commit;
+mod pre_push;
+mod reference_transaction;
+
+/// The hooks the client implements, by the name git runs them under.
+pub const HOOK_NAMES: &[&str] = &["pre-commit", "pre-push", "post-commit", "post-merge", "post-rewrite", "reference-transaction"];
+
+/// Whether the client implements a hook of this name.
+pub fn is_hook(name: &str) -> bool {
+    HOOK_NAMES.contains(&name)
+}
+
+/// Whether a hook run has nothing to do, eg. the reference-transaction hook for a transaction that
+/// isn't committed yet. Such runs don't need the repository configured.
+pub fn does_nothing(hook_name: &str, arguments: &[String]) -> bool {
+    hook_name == "reference-transaction" && !reference_transaction::is_committed(arguments)
+}
+
+/// Runs a hook with the arguments git passed to it (the hook reads its input from stdin).
+/// Returns false if the hook found conflicts, and the git operation should be stopped.
+pub fn run_hook(hook_name: &str, arguments: &[String]) -> Result<bool, Error> {
+    match hook_name {
+        "pre-commit" => pre_commit::run(),
+        "pre-push" => pre_push::run(arguments),
+        "post-commit" => post_commit::run().map(|()| true),
+        "post-merge" => post_merge::run().map(|()| true),
+        "post-rewrite" => post_rewrite::run().map(|()| true),
+        "reference-transaction" => reference_transaction::run(arguments).map(|()| true),
+        _ => Err(format_err!("[{}] is not a Global Graph hook. The hooks are: {}", hook_name, HOOK_NAMES.join(", "))),
+    }
+}
+
+/// Asks the sync agent to synchronize the repository after a hook changed it. Never fails the hook
+/// when the global graph is unreachable, the sync agent retries later.
+fn request_synchronization(hook_name: &str) -> Result<(), Error> {
+    if let Err(e) = crate::sync_queue::request_synchronization(env::current_dir()?, hook_name) {
+        warn!("[Global Graph]: Could not synchronize with the global graph: {}", e);
+    }
+    Ok(())
+}
+
+/// A conflicts check that failed to run stops the git operation, unless the failure policy allows
+/// it (see `crate::team_config::failure_policy`).
+fn apply_failure_policy(result: Result<bool, Error>) -> Result<bool, Error> {
+    let error = match result {
+        Ok(no_conflicts) => return Ok(no_conflicts),
+        Err(error) => error,
+    };
+
+    let policy = env::current_dir().ok()
+        .and_then(|directory| Repository::open(directory).ok())
+        .and_then(|repo| crate::team_config::failure_policy(&repo).ok());
+    if policy == Some(FailurePolicy::Allow) {
+        warn!("[Global Graph]: Could not check for conflicts, continuing as the failure policy allows: {}", error);
+        return Ok(true);
+    }
+    Err(error)
+}
@@ -1,22 +1,46 @@
 
-#[macro_use]
-extern crate log;
-extern crate client;
+//! The post-commit hook. Synchronizes the new commit, and checks the commits replayed by a rebase.
 
-use std::error::Error;
+use crate::sync_queue::SyncQueue;
+use failure::Error;
+use log::{debug, error, warn};
 use std::env;
 
-/// precommit hook entry point
-fn main() -> Result<(), Box<Error>>{
-    client::init_logging();
-
+/// post-commit hook entry point
+pub fn run() -> Result<(), Error> {
     debug!("Starting post-commit.");
 
-    client::synchronize_local_repository(env::current_dir()?)
-        .expect("Synchronization with server failed. \n");
+    let repo = git2::Repository::open(env::current_dir()?)?;
+    if crate::rebase::is_replaying(&repo)? {
+        // Commits replayed by a rebase skip the pre-commit hook, so check them here. The hook can't
+        // stop the rebase, but the rebase can still be aborted.
+        match crate::rebase::check_replayed_commit(&repo) {
+            Ok(ref conflicts) if conflicts.is_empty() => (),
+            Ok(conflicts) => {
+                error!("[Global Graph]: The replayed commit conflicts with work in other repositories:");
+                for conflict in conflicts {
+                    for line in crate::query::describe_conflict(&conflict) {
+                        error!("{}", line);
+                    }
+                }
+                error!("[Global Graph]: Run `git rebase --abort` to undo the rebase.");
+            }
+            Err(e) => warn!("[Global Graph]: Could not check the replayed commit for conflicts: {}", e),
+        }
+    }
+
+    // The reference-transaction hook (git 2.28 or later) already queued a synchronization for the
+    // branch moving to the new commit.
+    if let Some(head) = repo.head().ok().and_then(|head| head.target()) {
+        if SyncQueue::open(&repo)?.is_tip_queued(&head.to_string())? {
+            debug!("The reference-transaction hook already queued a synchronization for [{}].", head);
+            return Ok(());
+        }
+    }
+
+    super::request_synchronization("post-commit")?;
 
     debug!("Finished post-commit.");
 
     Ok(())
-
 }
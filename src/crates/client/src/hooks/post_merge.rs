@@ -1,20 +1,13 @@
+//! The post-merge hook. Synchronizes the merged branch.
 
-#[macro_use]
-extern crate log;
-extern crate client;
-
-use std::error::Error;
-use std::env;
-
-/// precommit hook entry point
-fn main() -> Result<(), Box<Error>>{
-    client::init_logging();
+use failure::Error;
+use log::debug;
 
+/// post-merge hook entry point
+pub fn run() -> Result<(), Error> {
     debug!("Starting post-merge.");
-    client::synchronize_local_repository(env::current_dir()?)
-        .expect("Synchronization with server failed. \n");
+    super::request_synchronization("post-merge")?;
     debug!("Finished post-merge.");
 
     Ok(())
-
 }
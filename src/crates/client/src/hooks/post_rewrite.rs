@@ -1,18 +1,44 @@
-#[macro_use]
-extern crate log;
-extern crate client;
+//! The post-rewrite hook. Records which commits an amend or rebase replaced, and synchronizes the
+//! rewritten branch.
 
-use std::error::Error;
+use failure::Error;
+use git2::Repository;
+use log::{debug, warn};
+use shared::CommitRewrite;
+use shared::CommitSha;
 use std::env;
+use std::io;
+use std::io::BufRead;
 
-/// precommit hook entry point
-fn main() -> Result<(), Box<Error>>{
-    client::init_logging();
-
+/// post-rewrite hook entry point
+/// Git passes one line per rewritten commit on stdin:
+///     <old-sha> SP <new-sha> [ SP <extra-info> ] LF
+pub fn run() -> Result<(), Error> {
     debug!("Starting post-rewrite.");
 
-    client::synchronize_local_repository(env::current_dir()?)
-        .expect("Synchronization with server failed. \n");
+    let mut rewrites = vec!();
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 {
+            warn!("[Global Graph]: Unexpected line passed to the post-rewrite hook: [{}]", line);
+            continue;
+        }
+
+        rewrites.push(CommitRewrite {
+            original: CommitSha::new(fields[0]),
+            rewritten: CommitSha::new(fields[1]),
+        });
+    }
+
+    // The rewrites are uploaded once the rewritten commits are synchronized.
+    let repo = Repository::open(env::current_dir()?)?;
+    if let Err(e) = crate::rewrites::record_rewrites(&repo, &rewrites) {
+        warn!("[Global Graph]: Could not record the rewritten commits: {}", e);
+    }
+
+    super::request_synchronization("post-rewrite")?;
 
     debug!("Finished post-rewrite.");
 
@@ -1,27 +1,13 @@
-#[macro_use]
-extern crate log;
-extern crate client;
-#[macro_use]
-extern crate failure;
-extern crate reqwest;
-extern crate url;
-extern crate git2;
-extern crate serde_json;
-extern crate shared;
-extern crate http;
+//! The pre-commit hook. Stops the commit if it would conflict with work in other repositories.
 
 use std::env;
-use url::Url;
 
+use crate::query;
 use failure::Error;
 use failure::ResultExt;
 use git2::Repository;
-use shared::CommitSha;
-use shared::RepositoryExtensions;
-use http::StatusCode;
 use git2::Status;
-use git2::CheckAttributeFlags;
-use git2::AttributeType;
+use log::{debug, error, info, warn};
 use shared::GitPath;
 
 /// precommit hook entry point
@@ -29,31 +15,18 @@ use shared::GitPath;
 ///    Ok(true) -- everything is successful.
 ///    Ok(false) -- the hook executed successfully, but conflicts were found.
 ///    Err(_) -- the hook failed to execute, conflicts not checked.
-fn main_internal() -> Result<bool, Error> {
-    client::init_logging();
-
+fn check_commit() -> Result<bool, Error> {
     debug!("Starting pre-commit.");
 
     debug!("Synchronizing the repository.");
-    client::synchronize_local_repository(env::current_dir()?)
+    crate::synchronize_local_repository(env::current_dir()?)
         .context("Synchronization with server failed. \n")?;
 
     info!("[Global Graph]: Checking for conflicts in the Global Graph.");
     let repo = Repository::open(env::current_dir()?)?;
-    let global_graph_url = repo.config()?.get_string("globalgraph.server")
-        .context("The local git config value 'globalgraph.server' is missing or invalid. \
-        Set it to your global graph query server url.")?;
-    let host_url = Url::parse(&global_graph_url)
-        .context(format!("The local git config value 'globalgraph.server' is not a valid url: [{}]", &global_graph_url))?;
-
-    let find_unintegrated_changes_url = host_url.join("v1/conflicts_after_commit")?;
 
     // Make a request to the server to check if we can commit the changed files.
 
-    let repo_uuid = repo.config()?.get_string("globalgraph.repouuid")
-        .context("The local git config value 'globalgraph.repouuid' is missing or invalid.")?;
-
-
     // TODO(john): Get the file names by running: git status --porcelain
 
     // TODO (john): Verify a number of cases:
@@ -80,42 +53,14 @@ fn main_internal() -> Result<bool, Error> {
 
     debug!("Modified Paths: [{:#?}]", modified_paths);
 
-    let payload = shared::ConflictsAfterCommitRequest {
-        repo_uuid: repo_uuid,
-        repo_head_commit: match repo.head_safe()? {
-            Some(reference) => Some(CommitSha(reference.peel_to_commit()?.id().to_string())),
-            None => None
-        },
-        files: modified_paths,
-    };
-    let client = reqwest::Client::new();
-    let mut response = client.post(find_unintegrated_changes_url.as_str())
-        .json(&payload)
-        .send().context("Could not send request to the Global Graph server.")?;
-
-    if response.status() != StatusCode::OK {
-        return Err(format_err!("The Global Graph server returned a non-200 status code: [{}].", response.status()));
-    }
-
-    trace!("Global Graph conflicts check returned status code: [{}]", response.status());
-    let response_payload: shared::ConflictsAfterCommitResponse = response.json()
-        .context("The Global Graph server returned invalid json.")?;
+    let conflicts = query::find_unintegrated_changes(&repo, query::NextCommit::in_progress(&repo)?, modified_paths)?;
 
-    if !response_payload.conflicts.is_empty() {
+    if !conflicts.is_empty() {
         error!("[Global Graph]: Found one or more conflicting commits on other branches:");
-        for conflict in response_payload.conflicts {
-            let username = match shared::break_repo_uuid(&conflict.repo_uuid) {
-                Ok(info) => info.username,
-                Err(_) => {
-                    error!("    Note: Couldn't parse the conflicting Repository name: [{}]", &conflict.repo_uuid);
-                    "<Unknown User>".to_string()
-                }
-            };
-
-            error!("    Local file [{}] is in conflict with another version of the file, committed by user [{}]. Conflicting version:", conflict.file, username);
-            error!("      Repository UUID: [{}]", conflict.repo_uuid);
-            error!("      Branch: [{}]", conflict.branch);
-            error!("      Commit: [{}]", conflict.commit);
+        for conflict in conflicts {
+            for line in query::describe_conflict(&conflict) {
+                error!("{}", line);
+            }
 
             return Ok(false);
         }
@@ -128,21 +73,11 @@ fn main_internal() -> Result<bool, Error> {
     Ok(true)
 }
 
-// The hook only returns a 0 error code if there are no conflicts. 
-fn main() -> Result<(), Error> {
-    let result = main_internal();
-    match result {
-        Ok(true) => std::process::exit(0),
-
-        // Either of these cases stops the git commit operation.
-        Ok(false) => {
-            error!("Conflicts found. Exiting with status: [2].");
-            std::process::exit(2);
-        }
-        Err(e) => {
-            // TODO(john): Implement GG_CONFLICTS_IGNORE_ONCE
-            error!("An unrecoverable error occurred when checking this commit for conflicts on the Global Graph. This may mean the local repository is configured incorrectly, the server is unreachable, or the server returned an invalid response.\nIf you want to force a commit (and potentially put this repo in conflict with another commit, set the environment flag: \"GG_CONFLICTS_IGNORE_ONCE=1\")");
-            return Err(e);
-        }
-    }
+/// Checks the commit. Returns false if conflicts were found, which stops the commit.
+pub fn run() -> Result<bool, Error> {
+    super::apply_failure_policy(check_commit()).map_err(|e| {
+        // TODO(john): Implement GG_CONFLICTS_IGNORE_ONCE
+        error!("An unrecoverable error occurred when checking this commit for conflicts on the Global Graph. This may mean the local repository is configured incorrectly, the server is unreachable, or the server returned an invalid response.\nIf you want to force a commit (and potentially put this repo in conflict with another commit, set the environment flag: \"GG_CONFLICTS_IGNORE_ONCE=1\")");
+        e
+    })
 }
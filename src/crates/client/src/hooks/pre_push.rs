@@ -0,0 +1,142 @@
+//! The pre-push hook. Stops the push if an outgoing commit conflicts with work in other repositories.
+
+use std::collections::HashSet;
+use std::env;
+use std::io;
+use std::io::BufRead;
+
+use crate::query;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use git2::Oid;
+use git2::Repository;
+use log::{debug, error, info};
+use shared::CommitSha;
+use shared::GitPath;
+
+/// A ref being pushed, as git passes it to the hook on stdin:
+///     <local ref> SP <local sha1> SP <remote ref> SP <remote sha1> LF
+struct PushedRef {
+    local_ref: String,
+    local_commit: Oid,
+    remote_commit: Oid,
+}
+
+/// prepush hook entry point
+/// Commits can reach the shared branch without passing the pre-commit hook (eg. `--no-verify`, `git am`,
+/// or commits made before Global Graph was installed), so every outgoing commit is checked again here.
+///
+/// Returns 3 potential values:
+///    Ok(true) -- everything is successful.
+///    Ok(false) -- the hook executed successfully, but conflicts were found.
+///    Err(_) -- the hook failed to execute, conflicts not checked.
+fn check_push(arguments: &[String]) -> Result<bool, Error> {
+    debug!("Starting pre-push.");
+
+    // Git passes the name of the remote (or its url, if pushing to a url) as the first argument.
+    let remote_name = arguments.first().cloned().unwrap_or_default();
+    if remote_name == shared::GLOBALGRAPH_REPO_NAME {
+        debug!("Pushing to the global graph itself, nothing to check.");
+        return Ok(true);
+    }
+
+    let mut pushed_refs = vec!();
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(format_err!("Git passed an unexpected line to the pre-push hook: [{}]", line));
+        }
+
+        pushed_refs.push(PushedRef {
+            local_ref: fields[0].to_string(),
+            local_commit: Oid::from_str(fields[1])?,
+            remote_commit: Oid::from_str(fields[3])?,
+        });
+    }
+
+    debug!("Synchronizing the repository.");
+    crate::synchronize_local_repository(env::current_dir()?)
+        .context("Synchronization with server failed. \n")?;
+
+    let repo = Repository::open(env::current_dir()?)?;
+    let mut found_conflicts = false;
+
+    for pushed_ref in pushed_refs {
+        // Deleting a remote branch can't introduce conflicts.
+        if pushed_ref.local_commit.is_zero() {
+            continue;
+        }
+
+        let files = outgoing_lockable_files(&repo, &remote_name, &pushed_ref)?;
+        debug!("Outgoing lockable files for [{}]: [{:#?}]", pushed_ref.local_ref, files);
+        if files.is_empty() {
+            continue;
+        }
+
+        info!("[Global Graph]: Checking [{}] for conflicts in the Global Graph.", pushed_ref.local_ref);
+        let head = Some(CommitSha::new(&pushed_ref.local_commit.to_string()));
+        let conflicts = query::find_unintegrated_changes(&repo, query::NextCommit::on(head), files)?;
+
+        if !conflicts.is_empty() {
+            error!("[Global Graph]: Pushing [{}] would publish changes that conflict with work in other repositories:", pushed_ref.local_ref);
+            for conflict in conflicts {
+                for line in query::describe_conflict(&conflict) {
+                    error!("{}", line);
+                }
+            }
+            found_conflicts = true;
+        }
+    }
+
+    if !found_conflicts {
+        info!("[Global Graph]: No conflicts found. Clear to push.");
+    }
+
+    debug!("Pre-push finished.");
+
+    Ok(!found_conflicts)
+}
+
+/// Lists the lockable files changed by the commits being pushed, ie. the commits reachable from the
+/// local commit that the remote doesn't have yet.
+fn outgoing_lockable_files(repo: &Repository, remote_name: &str, pushed_ref: &PushedRef) -> Result<Vec<GitPath>, Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(pushed_ref.local_commit)?;
+
+    // The remote may have commits we haven't fetched, so the remote commit can be unknown locally.
+    if !pushed_ref.remote_commit.is_zero() && repo.find_commit(pushed_ref.remote_commit).is_ok() {
+        revwalk.hide(pushed_ref.remote_commit)?;
+    }
+    for reference in repo.references()? {
+        let reference = reference?;
+        let is_remote_branch = reference.name()
+            .map(|name| name.starts_with(&format!("refs/remotes/{}/", remote_name)))
+            .unwrap_or(false);
+        if is_remote_branch {
+            if let Ok(commit) = reference.peel_to_commit() {
+                revwalk.hide(commit.id())?;
+            }
+        }
+    }
+
+    let mut files = HashSet::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        files.extend(crate::changed_paths(repo, &commit)?);
+    }
+
+    let mut files: Vec<GitPath> = files.into_iter().collect();
+    files.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    crate::lockable_paths(repo, files)
+}
+
+/// Checks the push. Returns false if conflicts were found, which stops the push.
+pub fn run(arguments: &[String]) -> Result<bool, Error> {
+    super::apply_failure_policy(check_push(arguments)).map_err(|e| {
+        error!("An unrecoverable error occurred when checking this push for conflicts on the Global Graph. This may mean the local repository is configured incorrectly, the server is unreachable, or the server returned an invalid response.\nIf you want to force the push (and potentially publish a conflict), run `git push --no-verify`.");
+        e
+    })
+}
@@ -0,0 +1,62 @@
+//! The reference-transaction hook. Synchronizes branches that move without a commit.
+
+use crate::sync_queue::SyncQueue;
+use failure::Error;
+use git2::Repository;
+use log::{debug, warn};
+use std::env;
+use std::io;
+use std::io::BufRead;
+
+/// reference-transaction hook entry point
+/// Git runs this hook for every reference update, with the state of the transaction as the first
+/// argument, and one line per updated reference on stdin:
+///     <old-value> SP <new-value> SP <ref-name> LF
+///
+/// Branches that move without a commit (`reset`, `branch -f`, `update-ref`, fetching into a branch)
+/// don't run any other hook, so this keeps the global graph from lagging behind them.
+pub fn run(arguments: &[String]) -> Result<(), Error> {
+    // Only synchronize changes that actually happened.
+    if !is_committed(arguments) {
+        return Ok(());
+    }
+
+    let stdin = io::stdin();
+    let mut moved_branches = vec!();
+    let mut new_tips = vec!();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            warn!("[Global Graph]: Unexpected line passed to the reference-transaction hook: [{}]", line);
+            continue;
+        }
+
+        let (old_value, new_value, reference) = (fields[0], fields[1], fields[2]);
+        if reference.starts_with("refs/heads/") && old_value != new_value {
+            moved_branches.push(reference.to_string());
+            new_tips.push(new_value.to_string());
+        }
+    }
+
+    if moved_branches.is_empty() {
+        return Ok(());
+    }
+
+    debug!("Branches moved: [{:?}]", moved_branches);
+
+    super::request_synchronization("reference-transaction")?;
+
+    // Lets the post-commit hook that follows a commit know the synchronization is already queued.
+    let tips: Vec<&str> = new_tips.iter().map(|tip| &**tip).collect();
+    if let Err(e) = SyncQueue::open(&Repository::open(env::current_dir()?)?).and_then(|queue| queue.record_queued_tips(&tips)) {
+        warn!("[Global Graph]: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Whether the hook runs for a committed transaction, rather than a `prepared` or `aborted` one.
+pub(super) fn is_committed(arguments: &[String]) -> bool {
+    arguments.first().map(|state| &**state) == Some("committed")
+}
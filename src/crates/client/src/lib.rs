@@ -1,24 +1,54 @@
 use git2::Repository;
 use git2::BranchType;
+use git2::Commit;
 use git2::ErrorCode;
+use git2::Oid;
+use std::collections::HashSet;
 use std::sync::{Once, ONCE_INIT};
+use std::fs;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
 use shared::ClientSyncConfig;
 use shared::ReferencePath;
+use shared::GitPath;
+use git2::AttributeType;
+use git2::CheckAttributeFlags;
 use failure::Error;
 use failure::ResultExt;
 use log::{debug, trace, info};
 use failure::format_err;
 use log::Level;
 
+pub mod dispatcher;
+pub mod doctor;
+pub mod hooks;
+pub mod manifest;
+pub mod query;
+pub mod query_client;
+pub mod rebase;
+pub mod rewrites;
+pub mod sync_queue;
+pub mod team_config;
+pub mod template;
+pub mod update;
+pub mod watcher;
+mod snapshots;
+
+pub use crate::snapshots::worktree_sync_enabled;
+
 /// Given a path to a repository on the local file system, synchronizes this repository as a
 /// client on the sync server.
 ///
 /// Every branch `b` in the local repository will be mapped to a branch like `developer_repo/b`.
+///
+/// Only branches whose tips have moved since the last synchronization are pushed. The last tip
+/// pushed for each branch is recorded in a tracking reference (see
+/// `ClientSyncConfig::map_branch_to_tracking`), and all changed branches are sent in a single atomic
+/// push.
 pub fn synchronize_local_repository<P: AsRef<Path>>(repository_path: P) -> Result<(), Error> {
     init_logging();
 
-    // First push all branches
     let repo = Repository::open(repository_path)?;
     debug!("Loaded repo at: {:?}", repo.path());
 
@@ -26,36 +56,322 @@ pub fn synchronize_local_repository<P: AsRef<Path>>(repository_path: P) -> Resul
     let uuid = get_or_create_client_uuid(&repo)?;
 
     debug!("Syncing repo [{}] to the global server.", uuid);
-    debug!("Global Graph remote url: [{}]", repo.find_remote(shared::GLOBALGRAPH_REPO_NAME)?.url().ok_or(format_err!("The global graph remote url is not valid UTF8."))?);
+    debug!("Global Graph remote url: [{}]", team_config::global_graph_remote(&repo)?.url().ok_or(format_err!("The global graph remote url is not valid UTF8."))?);
 
     // Verify the repository is setup for git-sync
     let config = get_config(&repo)
         .context("The local repository is improperly configured.")?;
 
-    let branches = repo.branches(None)?
-        .filter_map(Result::ok)
-        .filter(|&(_, t)| t == BranchType::Local)
-        .map(|(branch, _type)| branch);
+    let mut updates = find_branch_updates(&repo, &config)?;
+
+    // Then synchronize the uncommitted changes to lockable files.
+    updates.extend(snapshots::find_index_update(&repo, &config)?);
+    updates.extend(snapshots::find_worktree_update(&repo, &config)?);
+    updates.extend(snapshots::find_removed_worktree_updates(&repo, &config)?);
+
+    if updates.is_empty() {
+        debug!("All branches are already synchronized with the global graph.");
+        return Ok(());
+    }
+
+    push_branch_updates(&repo, &updates)
+}
+
+/// A single branch that differs between the local repository and the global graph.
+#[derive(Debug)]
+struct BranchUpdate {
+    /// The local branch, or None if the branch has been deleted locally.
+    local: Option<ReferencePath>,
+    /// The branch in the global graph. ie. refs/heads/<repo_uuid>/<branch>
+    global: ReferencePath,
+    /// The local reference recording the last tip pushed to `global`.
+    tracking: ReferencePath,
+    /// The current tip of the local branch, or None if the branch has been deleted locally.
+    target: Option<Oid>,
+}
+
+impl BranchUpdate {
+    fn refspec(&self) -> String {
+        match self.local {
+            // Always force push the branch to the sync server, as we are the only user.
+            Some(ref local) => format!("+{}:{}", local, self.global),
+            None => format!(":{}", self.global),
+        }
+    }
+}
 
-    for branch in branches {
+/// Compares every local branch against its tracking reference, and returns the branches that have
+/// been created, moved or deleted since the last synchronization. Branches the branch filter leaves
+/// out (see `team_config::branch_filter`) count as deleted.
+fn find_branch_updates(repo: &Repository, config: &ClientSyncConfig) -> Result<Vec<BranchUpdate>, Error> {
+    let mut updates = vec!();
+    let mut local_branches = HashSet::new();
+    let branch_filter = team_config::branch_filter(repo)?;
+
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
         let reference = branch.into_reference();
-        debug!("Syncing branch: {:?}", reference.name());
+        let branch_name = ReferencePath::new(reference.name()
+            .ok_or(format_err!("A local branch name is not valid UTF8."))?);
+        if !branch_filter.is_synchronized(reference.shorthand().unwrap_or_default())? {
+            trace!("Branch [{}] is not synchronized with the global graph.", branch_name);
+            continue;
+        }
+        let target = reference.resolve()?.target()
+            .ok_or(format_err!("The local branch [{}] does not point to a commit.", branch_name))?;
 
-        // Always force push the branch to the sync server, as we are the only user.
-        let branch_name = ReferencePath::new(reference.name().unwrap());
-        let refspec = format!("+{}:{}",
-                              branch_name,
-                              config.map_branch_to_global(&branch_name)?);
+        let tracking = config.map_branch_to_tracking(&branch_name)?;
+        let last_pushed = find_reference_target(repo, &tracking)?;
 
-        debug!("Pushing refspec: {}", &refspec);
+        local_branches.insert(branch_name.to_string());
+        if last_pushed == Some(target) {
+            trace!("Branch [{}] is unchanged since the last synchronization.", branch_name);
+            continue;
+        }
 
-        let _ = repo.find_remote(shared::GLOBALGRAPH_REPO_NAME)?.push(&[&refspec], None)?;
+        debug!("Branch [{}] moved from [{:?}] to [{}].", branch_name, last_pushed, target);
+        updates.push(BranchUpdate {
+            global: config.map_branch_to_global(&branch_name)?,
+            local: Some(branch_name),
+            tracking,
+            target: Some(target),
+        });
+    }
+
+    // Any tracking reference without a local branch was deleted locally since the last synchronization.
+    // NOTE: references_glob fails if the namespace directory doesn't exist yet, so filter by hand.
+    let tracking_namespace = config.tracking_namespace();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let tracking = match reference.name() {
+            Some(name) if name.starts_with(&tracking_namespace) => ReferencePath::new(name),
+            _ => continue,
+        };
+        let branch_name = config.map_tracking_to_branch(&tracking)?;
+
+        if !local_branches.contains(&*branch_name) {
+            debug!("Branch [{}] was deleted since the last synchronization.", branch_name);
+            updates.push(BranchUpdate {
+                local: None,
+                global: config.map_branch_to_global(&branch_name)?,
+                tracking,
+                target: None,
+            });
+        }
+    }
+
+    Ok(updates)
+}
+
+/// Returns the commit a reference points to, or None if the reference doesn't exist.
+fn find_reference_target(repo: &Repository, reference: &ReferencePath) -> Result<Option<Oid>, Error> {
+    match repo.refname_to_id(reference) {
+        Ok(oid) => Ok(Some(oid)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Sends every update to the global graph in a single atomic push, then records the pushed tips in
+/// the tracking references. If the global graph rejects any update, none of them are applied, and
+/// they are all retried on the next synchronization.
+///
+/// libgit2 can't push atomically, so this runs `git push --atomic`.
+fn push_branch_updates(repo: &Repository, updates: &[BranchUpdate]) -> Result<(), Error> {
+    let remote = team_config::global_graph_remote(repo)?;
+    let url = remote.pushurl().or(remote.url())
+        .ok_or(format_err!("The global graph remote url is not valid UTF8."))?
+        .to_owned();
+
+    // Deleting a branch that isn't in the global graph (anymore) would fail the whole push.
+    let existing = existing_remote_references(repo, &url, updates)?;
+    let (pushed, already_deleted): (Vec<&BranchUpdate>, Vec<&BranchUpdate>) = updates.iter()
+        .partition(|update| update.local.is_some() || existing.contains(&*update.global));
+
+    if !pushed.is_empty() {
+        let refspecs: Vec<String> = pushed.iter().map(|update| update.refspec()).collect();
+        debug!("Pushing refspecs: {:?}", &refspecs);
+
+        let output = git_command(repo)
+            .args(&["push", "--atomic", "--no-verify", "--quiet"])
+            .arg(&url)
+            .args(&refspecs)
+            .output()
+            .context("Could not run git to push to the global graph.")?;
+        if !output.status.success() {
+            return Err(format_err!("The global graph rejected the push, so no branch was updated: {}",
+                String::from_utf8_lossy(&output.stderr).trim()));
+        }
+    }
+
+    for update in pushed.into_iter().chain(already_deleted) {
+        match update.target {
+            Some(target) => {
+                repo.reference(&update.tracking, target, true, "globalgraph: synchronized branch")?;
+            }
+            None => {
+                if let Ok(mut reference) = repo.find_reference(&update.tracking) {
+                    reference.delete()?;
+                }
+            }
+        }
     }
 
     Ok(())
+}
+
+/// The references deleted by the updates that exist in the global graph at `url`. Only asks the
+/// global graph if there are any deletions.
+fn existing_remote_references(repo: &Repository, url: &str, updates: &[BranchUpdate]) -> Result<HashSet<String>, Error> {
+    let deleted: Vec<&str> = updates.iter()
+        .filter(|update| update.local.is_none())
+        .map(|update| &*update.global)
+        .collect();
+    if deleted.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let output = git_command(repo)
+        .args(&["ls-remote", "--refs"])
+        .arg(url)
+        .args(&deleted)
+        .output()
+        .context("Could not run git to list the branches of the global graph.")?;
+    if !output.status.success() {
+        return Err(format_err!("Could not list the branches of the global graph: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    // Each line is `<sha>\t<reference>`.
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// A git command run on the repository, whatever the environment of the hook that started it.
+fn git_command(repo: &Repository) -> Command {
+    let mut command = Command::new("git");
+    command.arg("--git-dir").arg(repo.path());
+    command
+}
+
+/// Whether the file at the given path has the `lockable` git attribute set, or matches the lockable
+/// patterns of the team configuration (see `team_config::lockable_patterns`). Only lockable files
+/// take part in conflicts detection.
+pub fn is_lockable(repo: &Repository, path: &GitPath) -> Result<bool, Error> {
+    is_lockable_with(repo, &team_config::lockable_patterns(repo)?, path)
+}
+
+fn is_lockable_with(repo: &Repository, lockable_patterns: &[String], path: &GitPath) -> Result<bool, Error> {
+    Ok(repo.get_attr(CheckAttributeFlags::empty(), path.as_str(), "lockable")? == AttributeType::True
+        || team_config::matches_any(lockable_patterns, path.as_str())?)
+}
+
+/// Filters a set of paths down to the lockable ones.
+pub fn lockable_paths<I: IntoIterator<Item=GitPath>>(repo: &Repository, paths: I) -> Result<Vec<GitPath>, Error> {
+    let lockable_patterns = team_config::lockable_patterns(repo)?;
+    let mut lockable = vec!();
+    for path in paths {
+        if is_lockable_with(repo, &lockable_patterns, &path)? {
+            lockable.push(path);
+        }
+    }
+    Ok(lockable)
+}
+
+/// The files a commit changed. For a merge, only the files that differ from every parent, since the
+/// rest were changed by commits on the merged branches, which are checked on their own.
+pub fn changed_paths(repo: &Repository, commit: &Commit) -> Result<HashSet<GitPath>, Error> {
+    let tree = commit.tree()?;
+    if commit.parent_count() == 0 {
+        let diff = repo.diff_tree_to_tree(None, Some(&tree), None)?;
+        return Ok(diff_paths(&diff));
+    }
+
+    let mut changed: Option<HashSet<GitPath>> = None;
+    for parent in commit.parents() {
+        let diff = repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&tree), None)?;
+        let paths = diff_paths(&diff);
+        changed = Some(match changed {
+            Some(changed) => changed.intersection(&paths).cloned().collect(),
+            None => paths,
+        });
+    }
 
-    // Then push synchronize the index file.
-    // TODO(john)
+    Ok(changed.unwrap_or_default())
+}
+
+fn diff_paths(diff: &git2::Diff) -> HashSet<GitPath> {
+    diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .filter_map(|path| path.to_str())
+        .map(|path| GitPath::new(&path.replace('\\', "/")))
+        .collect()
+}
+
+/// Converts an absolute path under the working tree to a git path, or None for paths outside the
+/// working tree or inside the git directory.
+pub fn to_git_path(workdir: &Path, absolute_path: &Path) -> Option<GitPath> {
+    let relative = absolute_path.strip_prefix(workdir).ok()?;
+    if relative.starts_with(".git") {
+        return None;
+    }
+
+    let components: Vec<&str> = relative.iter().map(|component| component.to_str()).collect::<Option<_>>()?;
+    Some(GitPath::new(&components.join("/")))
+}
+
+/// The git directory shared by every worktree of the repository. A linked worktree has its own git
+/// directory (`.git/worktrees/<name>/`) for its HEAD, index and in-progress operations, which points
+/// to the common directory through its `commondir` file.
+pub fn common_git_directory(repo: &Repository) -> PathBuf {
+    if repo.is_worktree() {
+        if let Ok(commondir) = fs::read_to_string(repo.path().join("commondir")) {
+            return repo.path().join(commondir.trim());
+        }
+    }
+    repo.path().to_path_buf()
+}
+
+/// The name of the linked worktree the repository was opened in, or None in the main working tree.
+pub fn linked_worktree_name(repo: &Repository) -> Option<String> {
+    if !repo.is_worktree() {
+        return None;
+    }
+    repo.path().file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// The directory the installer puts the hooks and the client executable in. Every worktree runs the
+/// same hooks.
+pub fn hooks_directory(repo: &Repository) -> PathBuf {
+    common_git_directory(repo).join("hooks")
+}
+
+/// The name of the client executable. It runs the hooks, the sync agent, the edit watcher and the
+/// `git globalgraph` commands.
+pub const CLIENT_EXECUTABLE_NAME: &str = "globalgraph";
+
+/// Returns the path the client executable is installed to for this repository.
+pub fn client_executable_path(repo: &Repository) -> PathBuf {
+    hooks_directory(repo).join(format!("{}{}", CLIENT_EXECUTABLE_NAME, std::env::consts::EXE_SUFFIX))
+}
+
+/// The git config value holding the secret the repository claimed its namespace in the global graph
+/// with (see `query::claim_namespace`). Only this clone knows it.
+pub const NAMESPACE_SECRET_CONFIG: &str = "globalgraph.namespacesecret";
+
+/// Gets the secret the repository proves it owns its namespace with, generating it if it isn't set.
+pub fn get_or_create_namespace_secret(repo: &Repository) -> Result<String, Error> {
+    match repo.config()?.get_string(NAMESPACE_SECRET_CONFIG) {
+        Ok(ref secret) if !secret.is_empty() => Ok(secret.clone()),
+        Ok(_) => Err(format_err!("The local git config value '{}' is empty. Unset it to generate a new one.", NAMESPACE_SECRET_CONFIG)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => {
+            let secret = uuid::Uuid::new_v4().to_simple().to_string();
+            repo.config()?.set_str(NAMESPACE_SECRET_CONFIG, &secret)?;
+            Ok(secret)
+        }
+        Err(e) => Err(Error::from(e)),
+    }
 }
 
 /// Gets the UUID associated with the given repository, or if it isn't set, generates one and sets
@@ -86,22 +402,34 @@ pub fn get_or_create_client_uuid(repo: &Repository) -> Result<String, Error> {
 }
 
 fn get_config(repo: &Repository) -> Result<ClientSyncConfig, Error> {
-    let repo_uuid = repo.config()?.get_string("globalgraph.repouuid")?;
+    let repo_uuid = repo.config()?.get_string("globalgraph.repouuid")
+        .context("The local git config value 'globalgraph.repouuid' is missing. Run `git globalgraph doctor` for help.")?;
 
     if repo_uuid.is_empty() {
-        return Err(format_err!("Test"));
+        return Err(format_err!("The local git config value 'globalgraph.repouuid' is empty. Run `git globalgraph doctor` for help."));
     }
 
     Ok(ClientSyncConfig {
         repo_uuid,
+        worktree: linked_worktree_name(repo),
     })
 }
 
 
+/// The version of the client binaries.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
 static INIT_LOGGING: Once = ONCE_INIT;
 
+/// Sets up logging for the client binaries. Programs that set up their own logger first (eg. the
+/// installer) keep it.
 pub fn init_logging() {
     INIT_LOGGING.call_once(|| {
-        simple_logger::init_with_level(Level::Warn).unwrap();
+        let _ = simple_logger::init_with_level(Level::Warn);
     })
 }
+
+/// Formats an error with every error that caused it, on a single line.
+pub fn error_chain(error: &Error) -> String {
+    error.iter_chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ")
+}
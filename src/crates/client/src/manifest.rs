@@ -0,0 +1,243 @@
+//! The install manifest: a record of every file the installer wrote to a repository, with its
+//! checksum, and of the git config values it set. It is kept in the git directory rather than in
+//! `hooks/`, so it survives the hooks directory being wiped (eg. by a Git for Windows reinstall),
+//! and lets `installer verify` and `installer repair` find and restore what changed since.
+//!
+//! Client updates keep it current (see `record_files`), so an update doesn't count as tampering.
+
+use git2::ErrorCode;
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use ring::digest;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::dispatcher::{dispatcher_version, DISPATCHER_VERSION, HOOK_SCRIPT_NAME};
+
+/// What an installed file is, which tells how to restore it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "hook")]
+pub enum InstalledFileKind {
+    /// The client executable (see `crate::client_executable_path`).
+    ClientExecutable,
+    /// The Global Graph hook script of a hook, `hooks/<hook>.d/globalgraph`.
+    HookScript(String),
+    /// The dispatcher of a hook, `hooks/<hook>`.
+    Dispatcher(String),
+}
+
+impl InstalledFileKind {
+    /// Where the file is installed.
+    pub fn path(&self, repo: &Repository) -> PathBuf {
+        let hooks_directory = crate::hooks_directory(repo);
+        match *self {
+            InstalledFileKind::ClientExecutable => crate::client_executable_path(repo),
+            InstalledFileKind::HookScript(ref hook_name) => hooks_directory.join(format!("{}.d", hook_name)).join(HOOK_SCRIPT_NAME),
+            InstalledFileKind::Dispatcher(ref hook_name) => hooks_directory.join(hook_name),
+        }
+    }
+}
+
+/// A file the installer wrote.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstalledFile {
+    #[serde(flatten)]
+    pub kind: InstalledFileKind,
+    /// The path of the file, relative to the git directory.
+    pub path: String,
+    /// The hex encoded SHA-256 of the file as it was installed.
+    pub sha256: String,
+}
+
+/// Everything the installer set up in a repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InstallManifest {
+    /// The version of the installed client executable.
+    pub client_version: Option<String>,
+    /// The version of the installed dispatchers (see `crate::dispatcher::DISPATCHER_VERSION`).
+    pub dispatcher_version: u32,
+    pub files: Vec<InstalledFile>,
+    /// The git config values the installer set, by name.
+    pub config: Vec<(String, String)>,
+}
+
+impl InstallManifest {
+    pub fn new() -> InstallManifest {
+        InstallManifest { dispatcher_version: DISPATCHER_VERSION, ..Default::default() }
+    }
+
+    /// Records the file as it is installed now, replacing an earlier record of it.
+    pub fn add_file(&mut self, repo: &Repository, kind: InstalledFileKind) -> Result<(), Error> {
+        let path = kind.path(repo);
+        let contents = fs::read(&path).context(format!("Could not read the installed file [{:?}].", path))?;
+        if kind == InstalledFileKind::ClientExecutable {
+            self.client_version = shared::find_client_version(&contents);
+        }
+
+        let relative_path = path.strip_prefix(crate::common_git_directory(repo)).unwrap_or(&path)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        self.files.retain(|file| file.kind != kind);
+        self.files.push(InstalledFile { kind, path: relative_path, sha256: checksum(&contents) });
+        Ok(())
+    }
+
+    /// Removes the records of a hook's script and dispatcher.
+    pub fn forget_hook(&mut self, hook_name: &str) {
+        self.files.retain(|file| match file.kind {
+            InstalledFileKind::HookScript(ref name) | InstalledFileKind::Dispatcher(ref name) => name != hook_name,
+            InstalledFileKind::ClientExecutable => true,
+        });
+    }
+
+    /// Records the current value of a git config value the installer set. Unset values aren't recorded.
+    pub fn add_config(&mut self, repo: &Repository, name: &str) -> Result<(), Error> {
+        self.config.retain(|(recorded_name, _)| recorded_name != name);
+        if let Some(value) = config_value(repo, name)? {
+            self.config.push((name.to_string(), value));
+        }
+        Ok(())
+    }
+}
+
+/// Something that changed since the installer set up the repository.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstallationProblem {
+    /// An installed file was deleted.
+    MissingFile(InstalledFile),
+    /// An installed file's contents changed.
+    ModifiedFile(InstalledFile),
+    /// The client executable was replaced by another version than the one installed.
+    StaleClient { file: InstalledFile, version: Option<String> },
+    /// A dispatcher was replaced by an older version.
+    StaleDispatcher { file: InstalledFile, version: u32 },
+    /// A config value was changed or removed.
+    ChangedConfig { name: String, expected: String, actual: Option<String> },
+}
+
+impl fmt::Display for InstallationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InstallationProblem::MissingFile(ref file) => write!(f, "[{}] is missing.", file.path),
+            InstallationProblem::ModifiedFile(ref file) => write!(f, "[{}] was modified since it was installed.", file.path),
+            InstallationProblem::StaleClient { ref file, version: Some(ref version) } =>
+                write!(f, "[{}] is version [{}], not the installed version.", file.path, version),
+            InstallationProblem::StaleClient { ref file, version: None } =>
+                write!(f, "[{}] was replaced by a client without a version.", file.path),
+            InstallationProblem::StaleDispatcher { ref file, version } =>
+                write!(f, "[{}] was replaced by an older dispatcher, version [{}].", file.path, version),
+            InstallationProblem::ChangedConfig { ref name, ref expected, actual: Some(ref actual) } =>
+                write!(f, "Config value [{}] is [{}], but was installed as [{}].", name, actual, expected),
+            InstallationProblem::ChangedConfig { ref name, ref expected, actual: None } =>
+                write!(f, "Config value [{}] was removed, it was installed as [{}].", name, expected),
+        }
+    }
+}
+
+/// The path of the install manifest, shared by every worktree of the repository.
+pub fn manifest_path(repo: &Repository) -> PathBuf {
+    crate::common_git_directory(repo).join(shared::GLOBALGRAPH_REPO_NAME).join("install_manifest.json")
+}
+
+/// Reads the install manifest, or None if the repository was installed without one.
+pub fn read_manifest(repo: &Repository) -> Result<Option<InstallManifest>, Error> {
+    let path = manifest_path(repo);
+    let contents = match fs::read(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e).context(format!("Could not read the install manifest [{:?}].", path)).into()),
+    };
+    let manifest = serde_json::from_slice(&contents)
+        .context(format!("The install manifest [{:?}] is corrupt. Re-run the Global Graph installer.", path))?;
+    Ok(Some(manifest))
+}
+
+pub fn write_manifest(repo: &Repository, manifest: &InstallManifest) -> Result<(), Error> {
+    let path = manifest_path(repo);
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(&path, serde_json::to_vec_pretty(manifest)?)
+        .context(format!("Could not write the install manifest to [{:?}].", path))?;
+    Ok(())
+}
+
+/// Records the files as they are installed now, after the client replaced them. Does nothing if the
+/// repository has no install manifest.
+pub fn record_files(repo: &Repository, kinds: &[InstalledFileKind]) -> Result<(), Error> {
+    let mut manifest = match read_manifest(repo)? {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+    for kind in kinds {
+        manifest.add_file(repo, kind.clone())?;
+    }
+    manifest.dispatcher_version = DISPATCHER_VERSION;
+    write_manifest(repo, &manifest)
+}
+
+/// Compares the repository with its install manifest.
+pub fn find_problems(repo: &Repository, manifest: &InstallManifest) -> Result<Vec<InstallationProblem>, Error> {
+    let mut problems = vec!();
+
+    for file in &manifest.files {
+        let contents = match fs::read(file.kind.path(repo)) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                problems.push(InstallationProblem::MissingFile(file.clone()));
+                continue;
+            }
+            Err(e) => return Err(Error::from(e).context(format!("Could not read the installed file [{}].", file.path)).into()),
+        };
+        if checksum(&contents) == file.sha256 {
+            continue;
+        }
+
+        let problem = match file.kind {
+            InstalledFileKind::ClientExecutable => {
+                let version = shared::find_client_version(&contents);
+                if version != manifest.client_version {
+                    InstallationProblem::StaleClient { file: file.clone(), version }
+                } else {
+                    InstallationProblem::ModifiedFile(file.clone())
+                }
+            }
+            InstalledFileKind::Dispatcher(_) => match dispatcher_version(&String::from_utf8_lossy(&contents)) {
+                Some(version) if version < manifest.dispatcher_version => InstallationProblem::StaleDispatcher { file: file.clone(), version },
+                _ => InstallationProblem::ModifiedFile(file.clone()),
+            },
+            InstalledFileKind::HookScript(_) => InstallationProblem::ModifiedFile(file.clone()),
+        };
+        problems.push(problem);
+    }
+
+    for (name, expected) in &manifest.config {
+        let actual = config_value(repo, name)?;
+        if actual.as_ref() != Some(expected) {
+            problems.push(InstallationProblem::ChangedConfig { name: name.clone(), expected: expected.clone(), actual });
+        }
+    }
+
+    Ok(problems)
+}
+
+fn config_value(repo: &Repository, name: &str) -> Result<Option<String>, Error> {
+    match repo.config()?.get_string(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// The hex encoded SHA-256 of the contents.
+pub fn checksum(contents: &[u8]) -> String {
+    digest::digest(&digest::SHA256, contents).as_ref().iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,221 @@
+//! Queries made by the client against the Global Graph query server.
+
+use git2::Repository;
+use git2::RepositoryState;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::{debug, trace};
+use crate::query_client::QueryClient;
+use shared::CommitRewrite;
+use shared::CommitSha;
+use shared::GitPath;
+use shared::HeadCommit;
+use shared::RepositoryExtensions;
+use shared::UnintegratedChange;
+use std::fs;
+use url::Url;
+
+/// Reads the query server url from the local git config value `globalgraph.server`, or else from
+/// the team configuration (see `crate::team_config`).
+pub fn query_server_url(repo: &Repository) -> Result<Url, Error> {
+    let global_graph_url = crate::team_config::query_server(repo)?
+        .ok_or(format_err!("The query server url is not set. Set the local git config value 'globalgraph.server', \
+        or `server` in [{}], to your global graph query server url.", crate::team_config::TEAM_CONFIG_FILE))?;
+    Ok(Url::parse(&global_graph_url)
+        .context(format!("The query server url is not a valid url: [{}]", &global_graph_url))?)
+}
+
+/// Returns the commit HEAD points to, or None if the repository has no commits yet.
+pub fn head_commit(repo: &Repository) -> Result<HeadCommit, Error> {
+    Ok(match repo.head_safe()? {
+        Some(reference) => Some(CommitSha(reference.peel_to_commit()?.id().to_string())),
+        None => None
+    })
+}
+
+/// What the next commit made in a repository will be built on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextCommit {
+    /// The first parent, or None if the repository has no commits yet.
+    pub head: HeadCommit,
+    /// The other parents, when committing a merge.
+    pub other_parents: Vec<CommitSha>,
+    /// Commits whose changes are copied into (or undone by) the next commit, when committing a
+    /// cherry-pick, a revert or a patch applied by `git am`.
+    pub applied_commits: Vec<CommitSha>,
+}
+
+impl NextCommit {
+    /// A plain commit on top of `head`.
+    pub fn on(head: HeadCommit) -> NextCommit {
+        NextCommit {
+            head,
+            other_parents: vec!(),
+            applied_commits: vec!(),
+        }
+    }
+
+    /// The next commit, taking an in-progress merge, cherry-pick, revert, `git am` or rebase into
+    /// account.
+    ///
+    /// During a rebase, the next commit is checked relative to the commit the branch is rebased onto:
+    /// the commits replayed so far aren't synchronized, and only bring changes from the rebased branch.
+    pub fn in_progress(repo: &Repository) -> Result<NextCommit, Error> {
+        if let Some(onto) = crate::rebase::onto(repo)? {
+            return Ok(NextCommit::on(Some(onto)));
+        }
+
+        let mut next_commit = NextCommit::on(head_commit(repo)?);
+
+        match repo.state() {
+            RepositoryState::Merge => {
+                // MERGE_HEAD lists one commit per line, more than one for an octopus merge.
+                let merge_heads = fs::read_to_string(repo.path().join("MERGE_HEAD"))
+                    .context("A merge is in progress, but MERGE_HEAD could not be read.")?;
+                next_commit.other_parents = merge_heads.lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(CommitSha::new)
+                    .collect();
+            }
+            RepositoryState::CherryPick | RepositoryState::CherryPickSequence => {
+                let cherry_pick_head = repo.refname_to_id("CHERRY_PICK_HEAD")
+                    .context("A cherry-pick is in progress, but CHERRY_PICK_HEAD could not be read.")?;
+                next_commit.applied_commits.push(CommitSha::new(&cherry_pick_head.to_string()));
+            }
+            RepositoryState::Revert | RepositoryState::RevertSequence => {
+                // Undoing a commit deals with its changes as much as copying them does.
+                let revert_head = repo.refname_to_id("REVERT_HEAD")
+                    .context("A revert is in progress, but REVERT_HEAD could not be read.")?;
+                next_commit.applied_commits.push(CommitSha::new(&revert_head.to_string()));
+            }
+            RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => {
+                if let Some(patch_commit) = mailbox_patch_commit(repo)? {
+                    next_commit.applied_commits.push(patch_commit);
+                }
+            }
+            state => trace!("Repository state [{:?}] adds no parents to the next commit.", state),
+        }
+
+        Ok(next_commit)
+    }
+}
+
+/// The commit the patch `git am` is applying was made from, or None if it doesn't say. Patches made
+/// by `git format-patch` start with a `From <sha> <date>` line.
+fn mailbox_patch_commit(repo: &Repository) -> Result<Option<CommitSha>, Error> {
+    let directory = repo.path().join("rebase-apply");
+    let next = match fs::read_to_string(directory.join("next")) {
+        Ok(next) => next,
+        Err(_) => return Ok(None),
+    };
+    let next = next.trim().parse::<u32>()
+        .context(format!("`git am` is in progress, but [{:?}] is not a patch number.", directory.join("next")))?;
+
+    let patch = fs::read_to_string(directory.join(format!("{:04}", next)))
+        .context("`git am` is in progress, but the patch it is applying could not be read.")?;
+    Ok(patch.lines().next()
+        .and_then(|line| line.split_whitespace().nth(1).filter(|_| line.starts_with("From ")))
+        .and_then(|sha| git2::Oid::from_str(sha).ok())
+        .map(|oid| CommitSha::new(&oid.to_string())))
+}
+
+/// Asks the query server for every change to `files` in the global graph that `next_commit` would
+/// not integrate.
+pub fn find_unintegrated_changes(repo: &Repository, next_commit: NextCommit, files: Vec<GitPath>) -> Result<Vec<UnintegratedChange>, Error> {
+    let payload = shared::ConflictsAfterCommitRequest {
+        repo_uuid: repo_uuid(repo)?,
+        repo_head_commit: next_commit.head,
+        other_parents: next_commit.other_parents,
+        applied_commits: next_commit.applied_commits,
+        files,
+    };
+    debug!("Querying the Global Graph for conflicts: [{:?}]", payload);
+
+    Ok(QueryClient::for_repository(repo)?.conflicts_after_commit(&payload)?.conflicts)
+}
+
+/// Asks the query server for every change in the global graph that `repo_head_commit` doesn't
+/// integrate, including the staged and working tree changes of other repositories.
+pub fn list_unintegrated_changes(repo: &Repository, repo_head_commit: HeadCommit) -> Result<Vec<UnintegratedChange>, Error> {
+    let payload = shared::UnintegratedChangesRequest {
+        repo_uuid: repo_uuid(repo)?,
+        repo_head_commit,
+    };
+    debug!("Querying the Global Graph for unintegrated changes: [{:?}]", payload);
+
+    Ok(QueryClient::for_repository(repo)?.unintegrated_changes(&payload)?.changes)
+}
+
+/// Uploads commit rewrites to the query server. Returns the number of rewrites the server recorded.
+pub fn upload_rewrites(repo: &Repository, rewrites: Vec<CommitRewrite>) -> Result<usize, Error> {
+    let payload = shared::RecordRewritesRequest {
+        repo_uuid: repo_uuid(repo)?,
+        namespace_secret: crate::get_or_create_namespace_secret(repo)?,
+        rewrites,
+    };
+    debug!("Uploading commit rewrites to the Global Graph: [{:?}]", payload.rewrites);
+
+    Ok(QueryClient::for_repository(repo)?.record_rewrites(&payload)?.recorded)
+}
+
+/// Asks the query server to delete the branches and snapshots this repository pushed to the global
+/// graph. Returns the number of references deleted.
+pub fn delete_namespace(repo: &Repository) -> Result<usize, Error> {
+    let payload = shared::DeleteNamespaceRequest {
+        repo_uuid: repo_uuid(repo)?,
+        namespace_secret: crate::get_or_create_namespace_secret(repo)?,
+    };
+    debug!("Deleting [{}] from the Global Graph.", payload.repo_uuid);
+
+    Ok(QueryClient::for_repository(repo)?.delete_namespace(&payload)?.deleted)
+}
+
+/// Claims this repository's namespace in the global graph with its namespace secret, so no other
+/// client can delete it or record rewrites for it. Fails if another clone claimed it first.
+pub fn claim_namespace(repo: &Repository) -> Result<(), Error> {
+    let payload = shared::ClaimNamespaceRequest {
+        repo_uuid: repo_uuid(repo)?,
+        namespace_secret: crate::get_or_create_namespace_secret(repo)?,
+    };
+    debug!("Claiming [{}] in the Global Graph.", payload.repo_uuid);
+
+    QueryClient::for_repository(repo)?.claim_namespace(&payload)?;
+    Ok(())
+}
+
+fn repo_uuid(repo: &Repository) -> Result<String, Error> {
+    Ok(repo.config()?.get_string("globalgraph.repouuid")
+        .context("The local git config value 'globalgraph.repouuid' is missing or invalid.")?)
+}
+
+/// Formats a conflict as the lines shown to the user.
+pub fn describe_conflict(conflict: &UnintegratedChange) -> Vec<String> {
+    let mut lines = vec!();
+    let username = match shared::break_repo_uuid(&conflict.repo_uuid) {
+        Ok(info) => info.username,
+        Err(_) => {
+            lines.push(format!("    Note: Couldn't parse the conflicting Repository name: [{}]", &conflict.repo_uuid));
+            "<Unknown User>".to_string()
+        }
+    };
+
+    lines.push(match conflict.kind {
+        shared::ChangeKind::Committed =>
+            format!("    Local file [{}] is in conflict with another version of the file, committed by user [{}]. Conflicting version:", conflict.file, username),
+        shared::ChangeKind::Staged =>
+            format!("    Local file [{}] is in conflict with changes staged (but not yet committed) by user [{}]. Conflicting version:", conflict.file, username),
+        shared::ChangeKind::WorkingTree =>
+            format!("    Local file [{}] is in conflict with another version of the file, user [{}] is currently editing this file. Conflicting version:", conflict.file, username),
+        shared::ChangeKind::Unknown =>
+            format!("    Local file [{}] is in conflict with another version of the file, changed by user [{}]. Conflicting version:", conflict.file, username),
+    });
+    lines.push(format!("      Repository UUID: [{}]", conflict.repo_uuid));
+    lines.push(format!("      Branch: [{}]", conflict.branch));
+    lines.push(format!("      Commit: [{}]", conflict.commit));
+    if let Some(ref rewritten_as) = conflict.rewritten_as {
+        lines.push(format!("      Rewritten as: [{}]", rewritten_as));
+    }
+    lines
+}
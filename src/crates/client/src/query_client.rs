@@ -0,0 +1,251 @@
+//! A typed client for the Global Graph query server, used by the hooks and commands, and by other
+//! tools that query the global graph. Every endpoint takes and returns the request and response
+//! types of `shared`:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), failure::Error> {
+//! use client::query_client::QueryClient;
+//!
+//! let mut client = QueryClient::new(url::Url::parse("https://server.com:12345")?)
+//!     .with_auth_token("token");
+//! client.negotiate_version()?;
+//! let changes = client.unintegrated_changes(&shared::UnintegratedChangesRequest {
+//!     repo_uuid: "me_machine_1234abcd".to_string(),
+//!     repo_head_commit: None,
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests time out after `DEFAULT_TIMEOUT`, and are retried with exponential backoff when the
+//! server can't be reached or answers that it is unavailable. Requests that time out aren't retried,
+//! as the server is likely still working on them. Servers that require authentication (see the
+//! server README) take a token, sent as `Authorization: Bearer <token>`.
+//!
+//! The endpoints of each version of the API are served under `v<version>/`. A client uses version 1,
+//! which every server serves, until `QueryClient::negotiate_version` picks the newest version both
+//! the client and the server support.
+
+use git2::ErrorCode;
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use http::StatusCode;
+use log::{debug, trace};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use shared::ClaimNamespaceRequest;
+use shared::ClaimNamespaceResponse;
+use shared::ClientRelease;
+use shared::ConflictsAfterCommitRequest;
+use shared::ConflictsAfterCommitResponse;
+use shared::DeleteNamespaceRequest;
+use shared::DeleteNamespaceResponse;
+use shared::InfoResponse;
+use shared::ProjectLookupRequest;
+use shared::ProjectLookupResponse;
+use shared::RecordRewritesRequest;
+use shared::RecordRewritesResponse;
+use shared::UnintegratedChangesRequest;
+use shared::UnintegratedChangesResponse;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// The git config value holding the token a repository authenticates to the query server with.
+pub const AUTH_TOKEN_CONFIG: &str = "globalgraph.authtoken";
+
+/// How long a request may take, unless the client was given another timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client release download may take.
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How many times a request is retried, unless the client was given another number.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// The delay before the first retry, doubled before each following one.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// A connection to a query server. Cheap to create, as it holds no open connection.
+#[derive(Debug, Clone)]
+pub struct QueryClient {
+    server_url: Url,
+    timeout: Duration,
+    retries: u32,
+    auth_token: Option<String>,
+    api_version: u32,
+}
+
+impl QueryClient {
+    /// A client for the query server at `server_url`, with the default timeout and retries, no auth
+    /// token, and version 1 of the API.
+    pub fn new(server_url: Url) -> QueryClient {
+        QueryClient {
+            server_url,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            auth_token: None,
+            api_version: 1,
+        }
+    }
+
+    /// A client for the query server of the repository (see `crate::query::query_server_url`),
+    /// authenticating with the token in `globalgraph.authtoken` if it is set.
+    pub fn for_repository(repo: &Repository) -> Result<QueryClient, Error> {
+        let client = QueryClient::new(crate::query::query_server_url(repo)?);
+        match repo.config()?.get_string(AUTH_TOKEN_CONFIG) {
+            Ok(token) => Ok(client.with_auth_token(token)),
+            Err(ref e) if e.code() == ErrorCode::NotFound => Ok(client),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    /// Sets how long each request may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> QueryClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a request is retried while the server can't be reached. 0 sends each
+    /// request once.
+    pub fn with_retries(mut self, retries: u32) -> QueryClient {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the token sent with every request, for servers that require authentication.
+    pub fn with_auth_token<S: Into<String>>(mut self, token: S) -> QueryClient {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    pub fn server_url(&self) -> &Url {
+        &self.server_url
+    }
+
+    /// The version of the API the requests use.
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
+    /// Asks the server which versions of the API it supports, and uses the newest one this client
+    /// supports too (see `shared::API_VERSIONS`). Returns the server's information.
+    pub fn negotiate_version(&mut self) -> Result<InfoResponse, Error> {
+        let info = self.info()?;
+        self.api_version = shared::API_VERSIONS.iter().rev()
+            .find(|version| info.api_versions.contains(version))
+            .cloned()
+            .ok_or(format_err!("The Global Graph server at [{}] supports the API versions {:?}, but this client only supports {:?}. \
+            Update the client or the server.", self.server_url, info.api_versions, shared::API_VERSIONS))?;
+        debug!("Using version [{}] of the API of the Global Graph server at [{}].", self.api_version, self.server_url);
+        Ok(info)
+    }
+
+    /// Information about the server: its version, the versions of the API it supports and the client
+    /// releases it hosts. Always requested from `v1/`, as it is how the version is negotiated.
+    pub fn info(&self) -> Result<InfoResponse, Error> {
+        let url = self.server_url.join("v1/info")?;
+        let mut response = self.send(&url, self.timeout, |http_client| http_client.get(url.as_str()))?;
+        Ok(response.json().context("The Global Graph server returned invalid json.")?)
+    }
+
+    /// Every change to the request's files in the global graph that the next commit would not integrate.
+    pub fn conflicts_after_commit(&self, request: &ConflictsAfterCommitRequest) -> Result<ConflictsAfterCommitResponse, Error> {
+        self.post("conflicts_after_commit", request)
+    }
+
+    /// Every change in the global graph that the request's head doesn't integrate, including the
+    /// staged and working tree changes of other repositories.
+    pub fn unintegrated_changes(&self, request: &UnintegratedChangesRequest) -> Result<UnintegratedChangesResponse, Error> {
+        self.post("unintegrated_changes", request)
+    }
+
+    /// Records the commits a repository rewrote.
+    pub fn record_rewrites(&self, request: &RecordRewritesRequest) -> Result<RecordRewritesResponse, Error> {
+        self.post("rewrites", request)
+    }
+
+    /// Deletes the branches and snapshots a repository pushed to the global graph.
+    pub fn delete_namespace(&self, request: &DeleteNamespaceRequest) -> Result<DeleteNamespaceResponse, Error> {
+        self.post("delete_namespace", request)
+    }
+
+    /// Claims a repository's namespace with its secret.
+    pub fn claim_namespace(&self, request: &ClaimNamespaceRequest) -> Result<ClaimNamespaceResponse, Error> {
+        self.post("claim_namespace", request)
+    }
+
+    /// Whether the server serves the project a repository was cloned from.
+    pub fn lookup_project(&self, request: &ProjectLookupRequest) -> Result<ProjectLookupResponse, Error> {
+        self.post("project_lookup", request)
+    }
+
+    /// Downloads the executable of a client release the server hosts. Its signature is not checked
+    /// (see `crate::update::verify_release`).
+    pub fn download_client(&self, release: &ClientRelease) -> Result<Vec<u8>, Error> {
+        let url = self.endpoint_url(&format!("clients/{}/{}", release.version, release.platform))?;
+        let mut response = self.send(&url, DOWNLOAD_TIMEOUT, |http_client| http_client.get(url.as_str()))?;
+
+        let mut executable = vec!();
+        response.read_to_end(&mut executable).context("Could not download the client from the Global Graph server.")?;
+        Ok(executable)
+    }
+
+    fn endpoint_url(&self, endpoint: &str) -> Result<Url, Error> {
+        Ok(self.server_url.join(&format!("v{}/{}", self.api_version, endpoint))?)
+    }
+
+    fn post<T: Serialize, R: DeserializeOwned>(&self, endpoint: &str, request: &T) -> Result<R, Error> {
+        let url = self.endpoint_url(endpoint)?;
+        let mut response = self.send(&url, self.timeout, |http_client| http_client.post(url.as_str()).json(request))?;
+        Ok(response.json().context("The Global Graph server returned invalid json.")?)
+    }
+
+    /// Sends the request `build` makes, retrying while the server can't be reached or is unavailable.
+    /// Fails unless the server answers 200.
+    fn send<F>(&self, url: &Url, timeout: Duration, build: F) -> Result<reqwest::Response, Error>
+        where F: Fn(&reqwest::Client) -> reqwest::RequestBuilder
+    {
+        let http_client = reqwest::Client::builder().timeout(timeout).build()?;
+        let mut retry_delay = INITIAL_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let mut request = build(&http_client);
+            if let Some(ref token) = self.auth_token {
+                request = request.bearer_auth(token);
+            }
+
+            let result = request.send();
+            let retryable = match result {
+                Ok(ref response) => is_unavailable(response.status()),
+                Err(ref e) => e.is_http() && !e.is_timeout(),
+            };
+            if retryable && attempt < self.retries {
+                attempt += 1;
+                debug!("The Global Graph server did not answer [{}], retrying in [{}ms].", url, retry_delay.as_millis());
+                thread::sleep(retry_delay);
+                retry_delay *= 2;
+                continue;
+            }
+
+            let response = result.context(format!("Could not send request to the Global Graph server at [{}].", self.server_url))?;
+            trace!("The Global Graph server answered [{}] with status code [{}].", url, response.status());
+            return match response.status() {
+                StatusCode::OK => Ok(response),
+                StatusCode::UNAUTHORIZED => Err(format_err!("The Global Graph server at [{}] requires authentication. Set the git config value '{}' \
+                to a token the server accepts.", self.server_url, AUTH_TOKEN_CONFIG)),
+                StatusCode::FORBIDDEN => Err(format_err!("The Global Graph server at [{}] refused the request: another clone claimed this \
+                repository's namespace. Check the git config values 'globalgraph.repouuid' and '{}'.", self.server_url, crate::NAMESPACE_SECRET_CONFIG)),
+                status => Err(format_err!("The Global Graph server returned a non-200 status code: [{}].", status)),
+            };
+        }
+    }
+}
+
+/// Whether the status means the server is temporarily unable to answer, eg. behind a proxy while it restarts.
+fn is_unavailable(status: StatusCode) -> bool {
+    status == StatusCode::BAD_GATEWAY || status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::GATEWAY_TIMEOUT
+}
@@ -0,0 +1,77 @@
+//! Support for rebases in progress.
+//!
+//! While git replays the commits of a rebase, HEAD is detached and moves once per replayed commit,
+//! running the post-commit and reference-transaction hooks each time. None of these intermediate
+//! states are worth publishing: only the rebased branch is synchronized once git re-attaches HEAD
+//! (before running the post-rewrite hook). Instead, each replayed commit is checked for conflicts
+//! against the commit the branch is being rebased onto.
+
+use git2::Oid;
+use git2::Repository;
+use git2::RepositoryState;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::debug;
+use shared::CommitSha;
+use shared::UnintegratedChange;
+use std::fs;
+use std::path::PathBuf;
+
+/// Whether a rebase (with any backend) is in progress.
+pub fn in_progress(repo: &Repository) -> bool {
+    match repo.state() {
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge => true,
+        // `git am` uses the same directory as the apply backend of rebase, but doesn't record a base.
+        RepositoryState::ApplyMailboxOrRebase => onto_file(repo).is_some(),
+        _ => false,
+    }
+}
+
+/// Whether git is replaying the commits of a rebase, ie. a rebase is in progress and HEAD is
+/// detached. These intermediate states are not synchronized.
+pub fn is_replaying(repo: &Repository) -> Result<bool, Error> {
+    Ok(in_progress(repo) && repo.head_detached()?)
+}
+
+/// The commit the rebase in progress replays commits onto, or None if no rebase is in progress.
+pub fn onto(repo: &Repository) -> Result<Option<CommitSha>, Error> {
+    if !in_progress(repo) {
+        return Ok(None);
+    }
+
+    let path = match onto_file(repo) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let contents = fs::read_to_string(&path)
+        .context(format!("A rebase is in progress, but [{:?}] could not be read.", path))?;
+    let onto = Oid::from_str(contents.trim())
+        .context(format!("A rebase is in progress, but [{:?}] is not a commit id.", path))?;
+    Ok(Some(CommitSha::new(&onto.to_string())))
+}
+
+fn onto_file(repo: &Repository) -> Option<PathBuf> {
+    ["rebase-merge", "rebase-apply"].iter()
+        .map(|directory| repo.path().join(directory).join("onto"))
+        .find(|path| path.exists())
+}
+
+/// Checks the lockable files changed by the commit git just replayed (HEAD) against the global graph,
+/// relative to the commit the branch is being rebased onto. Replayed commits only bring changes from
+/// the rebased branch itself, so the new base decides which changes from other repositories are
+/// integrated.
+pub fn check_replayed_commit(repo: &Repository) -> Result<Vec<UnintegratedChange>, Error> {
+    let onto = onto(repo)?.ok_or_else(|| format_err!("No rebase is in progress."))?;
+    let commit = repo.head()?.peel_to_commit()?;
+
+    let files = crate::lockable_paths(repo, crate::changed_paths(repo, &commit)?)?;
+    debug!("Replayed commit [{}] changed lockable files: [{:?}]", commit.id(), files);
+    if files.is_empty() {
+        return Ok(vec!());
+    }
+
+    crate::query::find_unintegrated_changes(repo, crate::query::NextCommit::on(Some(onto)), files)
+}
@@ -0,0 +1,67 @@
+//! Commit rewrites (amends, rebases) waiting to be uploaded to the Global Graph.
+//!
+//! The post-rewrite hook records the mapping from original to rewritten commits under
+//! `<git dir>/globalgraph/rewrites/`. The rewritten commits have to be synchronized before the server
+//! can record them, so pending rewrites are uploaded after each successful synchronization.
+
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use log::debug;
+use shared::CommitRewrite;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn rewrites_directory(repo: &Repository) -> PathBuf {
+    repo.path().join(shared::GLOBALGRAPH_REPO_NAME).join("rewrites")
+}
+
+/// Stores a batch of rewrites until it can be uploaded.
+pub fn record_rewrites(repo: &Repository, rewrites: &[CommitRewrite]) -> Result<(), Error> {
+    if rewrites.is_empty() {
+        return Ok(());
+    }
+
+    let directory = rewrites_directory(repo);
+    fs::create_dir_all(&directory)
+        .context(format!("Could not create the rewrites directory [{:?}].", directory))?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let batch_name = format!("{}{:09}-{}.json", now.as_secs(), now.subsec_nanos(), std::process::id());
+    fs::write(directory.join(&batch_name), serde_json::to_string(rewrites)?)
+        .context("Could not record commit rewrites.")?;
+
+    debug!("Recorded [{}] commit rewrites in [{}].", rewrites.len(), batch_name);
+    Ok(())
+}
+
+/// Uploads every pending rewrite to the query server, and removes the uploaded batches.
+pub fn upload_pending_rewrites(repo: &Repository) -> Result<(), Error> {
+    let directory = rewrites_directory(repo);
+    if !directory.exists() {
+        return Ok(());
+    }
+
+    let mut batches = vec!();
+    let mut rewrites: Vec<CommitRewrite> = vec!();
+    for entry in fs::read_dir(&directory)? {
+        let batch = entry?.path();
+        let contents = fs::read_to_string(&batch)?;
+        rewrites.extend(serde_json::from_str::<Vec<CommitRewrite>>(&contents)
+            .context(format!("The pending rewrites file [{:?}] is invalid.", batch))?);
+        batches.push(batch);
+    }
+
+    if rewrites.is_empty() {
+        return Ok(());
+    }
+
+    let recorded = crate::query::upload_rewrites(repo, rewrites)?;
+    debug!("The Global Graph recorded [{}] commit rewrites.", recorded);
+
+    for batch in batches {
+        fs::remove_file(&batch)?;
+    }
+    Ok(())
+}
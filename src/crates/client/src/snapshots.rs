@@ -0,0 +1,298 @@
+//! Snapshots of uncommitted work, synchronized to the global graph so the server can count it as
+//! in-flight work in conflict queries.
+//!
+//! A snapshot is a synthetic commit on top of HEAD, pushed to a per-clone reference outside of
+//! `refs/heads`. Snapshots are only made when lockable files have uncommitted changes, and the
+//! reference is deleted from the global graph once that work is committed or discarded.
+//!
+//! Each worktree of a repository has its own index and working tree, and so its own snapshots (see
+//! `ClientSyncConfig::worktree`).
+
+use crate::BranchUpdate;
+use git2::Commit;
+use git2::ErrorCode;
+use git2::Index;
+use git2::IndexEntry;
+use git2::IndexTime;
+use git2::Oid;
+use git2::Repository;
+use git2::Signature;
+use git2::Status;
+use git2::StatusOptions;
+use git2::Tree;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use shared::ClientSyncConfig;
+use shared::GitPath;
+use shared::ReferencePath;
+use shared::RepositoryExtensions;
+
+/// Returns the update needed to bring the global graph's snapshot of the index up to date, or None
+/// if it already is.
+pub(crate) fn find_index_update(repo: &Repository, config: &ClientSyncConfig) -> Result<Option<BranchUpdate>, Error> {
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        debug!("The index has unresolved conflicts. Skipping the index snapshot.");
+        return Ok(None);
+    }
+
+    let head = head_commit(repo)?;
+    let head_tree = match head {
+        Some(ref commit) => Some(commit.tree()?),
+        None => None,
+    };
+
+    let diff = repo.diff_tree_to_index(head_tree.as_ref(), Some(&index), None)?;
+    let staged_paths = crate::lockable_paths(repo, diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .filter_map(|path| path.to_str())
+        .map(GitPath::new))?;
+    debug!("Staged lockable paths: [{:?}]", staged_paths);
+
+    let tree = repo.find_tree(index.write_tree()?)?;
+    snapshot_update(repo, config.index_reference(), config.index_tracking_reference(),
+                    &staged_paths, "Global Graph snapshot of staged changes.", head.as_ref(), &tree)
+}
+
+/// Returns the update needed to bring the global graph's snapshot of the modified, unstaged lockable
+/// files up to date, or None if it already is. The snapshot is HEAD with the working tree version of
+/// those files, so files that are only staged are left to the index snapshot.
+///
+/// Working tree snapshots are only made when `globalgraph.worktreesync` is enabled. Otherwise a
+/// snapshot pushed earlier is deleted.
+pub(crate) fn find_worktree_update(repo: &Repository, config: &ClientSyncConfig) -> Result<Option<BranchUpdate>, Error> {
+    let reference = config.worktree_reference();
+    let tracking = config.worktree_tracking_reference();
+
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir.to_owned(),
+        None => return Ok(None),
+    };
+
+    let modified_paths = if worktree_sync_enabled(repo)? {
+        modified_lockable_paths(repo)?
+    } else {
+        vec!()
+    };
+    debug!("Modified lockable paths in the working tree: [{:?}]", modified_paths);
+
+    if modified_paths.is_empty() {
+        return remove_snapshot(repo, reference, tracking);
+    }
+
+    let head = head_commit(repo)?;
+    let mut snapshot_index = Index::new()?;
+    if let Some(ref commit) = head {
+        snapshot_index.read_tree(&commit.tree()?)?;
+    }
+
+    for path in &modified_paths {
+        let full_path = workdir.join(path.as_str());
+        if full_path.is_file() {
+            let blob = hash_working_tree_file(repo, &workdir, path)?;
+            let mode = match snapshot_index.get_path(Path::new(path.as_str()), 0) {
+                Some(entry) => entry.mode,
+                None => REGULAR_FILE_MODE,
+            };
+            snapshot_index.add(&blob_entry(path, blob, mode))?;
+        } else {
+            snapshot_index.remove_path(Path::new(path.as_str()))?;
+        }
+    }
+
+    let tree = repo.find_tree(snapshot_index.write_tree_to(repo)?)?;
+    snapshot_update(repo, reference, tracking, &modified_paths,
+                    "Global Graph snapshot of modified working tree files.", head.as_ref(), &tree)
+}
+
+/// Returns the updates that delete the snapshots of linked worktrees that were removed (see
+/// `git worktree remove`) since they were last synchronized. Worktrees share the references of the
+/// repository, so any worktree can clean up after the others.
+pub(crate) fn find_removed_worktree_updates(repo: &Repository, config: &ClientSyncConfig) -> Result<Vec<BranchUpdate>, Error> {
+    // The main working tree, and every linked worktree that still exists.
+    let mut worktrees = vec!(None);
+    if let Ok(entries) = fs::read_dir(crate::common_git_directory(repo).join("worktrees")) {
+        for entry in entries {
+            worktrees.push(Some(entry?.file_name().to_string_lossy().into_owned()));
+        }
+    }
+
+    let mut current_tracking = HashSet::new();
+    for worktree in worktrees {
+        let worktree_config = ClientSyncConfig { repo_uuid: config.repo_uuid.clone(), worktree };
+        current_tracking.insert(worktree_config.index_tracking_reference().to_string());
+        current_tracking.insert(worktree_config.worktree_tracking_reference().to_string());
+    }
+
+    // See `ClientSyncConfig::index_tracking_reference` and `worktree_tracking_reference`.
+    let namespaces = [
+        (format!("refs/remotes/{}/gg-index/", shared::GLOBALGRAPH_REPO_NAME), shared::INDEX_REFS_NAMESPACE),
+        (format!("refs/remotes/{}/gg-worktree/", shared::GLOBALGRAPH_REPO_NAME), shared::WORKTREE_REFS_NAMESPACE),
+    ];
+
+    let mut removed = vec!();
+    for reference in repo.references()? {
+        let name = match reference?.name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        for &(ref tracking_namespace, namespace) in &namespaces {
+            let tracking = ReferencePath::new(&name);
+            let is_own = shared::map_snapshot_to_client(tracking_namespace, &tracking)
+                .map_or(false, |repo_uuid| repo_uuid == config.repo_uuid);
+            if is_own && !current_tracking.contains(&name) {
+                let reference = ReferencePath::new(&format!("{}{}", namespace, &name[tracking_namespace.len()..]));
+                debug!("The worktree of snapshot [{}] was removed.", reference);
+                removed.push((reference, tracking));
+            }
+        }
+    }
+
+    let mut updates = vec!();
+    for (reference, tracking) in removed {
+        updates.extend(remove_snapshot(repo, reference, tracking)?);
+    }
+    Ok(updates)
+}
+
+/// Whether working tree snapshots are enabled, through the local git config value
+/// `globalgraph.worktreesync`. Defaults to false.
+pub fn worktree_sync_enabled(repo: &Repository) -> Result<bool, Error> {
+    match repo.config()?.get_bool("globalgraph.worktreesync") {
+        Ok(enabled) => Ok(enabled),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Returns the lockable files that differ between the index and the working tree, including new
+/// untracked files.
+fn modified_lockable_paths(repo: &Repository) -> Result<Vec<GitPath>, Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+
+    let mut paths = vec!();
+    for entry in repo.statuses(Some(&mut options))?.iter()
+        .filter(|entry| entry.status().intersects(Status::WT_NEW | Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_RENAMED | Status::WT_TYPECHANGE))
+    {
+        match entry.path() {
+            Some(path) => paths.push(GitPath::new(path)),
+            None => warn!("[Global Graph] Warning: Path is not valid UTF8 and will not be synchronized. [{}](lossy)", String::from_utf8_lossy(entry.path_bytes())),
+        }
+    }
+
+    crate::lockable_paths(repo, paths)
+}
+
+const REGULAR_FILE_MODE: u32 = 0o100644;
+
+/// Writes the working tree version of a file to the repository, the way `git add` would: through the
+/// clean filters of its attributes (eg. end of line conversion, or Git LFS), so the blob matches the
+/// one a commit of the file would have. libgit2 can't run filter drivers, so this runs `git hash-object`.
+fn hash_working_tree_file(repo: &Repository, workdir: &Path, path: &GitPath) -> Result<Oid, Error> {
+    // git only finds the attributes of the path from within the working tree.
+    let output = crate::git_command(repo)
+        .arg("--work-tree")
+        .arg(workdir)
+        .current_dir(workdir)
+        .args(&["hash-object", "-w", "--", path.as_str()])
+        .output()
+        .context(format!("Could not run git to snapshot [{}].", path))?;
+    if !output.status.success() {
+        return Err(format_err!("Could not snapshot [{}]: {}", path, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(Oid::from_str(String::from_utf8_lossy(&output.stdout).trim())?)
+}
+
+/// An entry for a blob in an index that is only used to write a tree, so it has no stat data.
+fn blob_entry(path: &GitPath, id: Oid, mode: u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+/// Creates or reuses the snapshot commit for `tree` on top of `head`, and returns the update that
+/// pushes it to `reference`. When `changed_paths` is empty, the snapshot is deleted instead.
+pub(crate) fn snapshot_update(repo: &Repository, reference: ReferencePath, tracking: ReferencePath,
+                              changed_paths: &[GitPath], description: &str,
+                              head: Option<&Commit>, tree: &Tree) -> Result<Option<BranchUpdate>, Error> {
+    if changed_paths.is_empty() {
+        return remove_snapshot(repo, reference, tracking);
+    }
+
+    let last_pushed = crate::find_reference_target(repo, &tracking)?;
+    let snapshot = find_or_create_snapshot(repo, &reference, description, head, tree)?;
+    if last_pushed == Some(snapshot) {
+        return Ok(None);
+    }
+
+    repo.reference(&reference, snapshot, true, "globalgraph: snapshot of uncommitted work")?;
+    Ok(Some(BranchUpdate {
+        local: Some(reference.clone()),
+        global: reference,
+        tracking,
+        target: Some(snapshot),
+    }))
+}
+
+/// Deletes the local snapshot, and returns the update that deletes it from the global graph if it
+/// was pushed.
+fn remove_snapshot(repo: &Repository, reference: ReferencePath, tracking: ReferencePath) -> Result<Option<BranchUpdate>, Error> {
+    if let Ok(mut local_snapshot) = repo.find_reference(&reference) {
+        local_snapshot.delete()?;
+    }
+
+    Ok(match crate::find_reference_target(repo, &tracking)? {
+        Some(_) => Some(BranchUpdate { local: None, global: reference, tracking, target: None }),
+        None => None,
+    })
+}
+
+/// Reuses the current snapshot if it already records the same tree on the same head, so unchanged
+/// work isn't pushed again.
+fn find_or_create_snapshot(repo: &Repository, reference: &ReferencePath, description: &str,
+                           head: Option<&Commit>, tree: &Tree) -> Result<Oid, Error> {
+    if let Some(existing) = crate::find_reference_target(repo, reference)? {
+        let existing = repo.find_commit(existing)?;
+        let same_parent = existing.parent_ids().next() == head.map(|commit| commit.id());
+        if existing.tree_id() == tree.id() && same_parent {
+            return Ok(existing.id());
+        }
+    }
+
+    let branch = match repo.head_safe()? {
+        Some(ref head_reference) if head_reference.is_branch() => head_reference.name().map(ReferencePath::new),
+        _ => None,
+    };
+
+    let signature = Signature::now("Global Graph", "globalgraph@localhost")?;
+    let parents: Vec<&Commit> = head.into_iter().collect();
+    Ok(repo.commit(None, &signature, &signature,
+                   &shared::snapshot_message(description, branch.as_ref()), tree, &parents)?)
+}
+
+fn head_commit(repo: &Repository) -> Result<Option<Commit>, Error> {
+    Ok(match repo.head_safe()? {
+        Some(reference) => Some(reference.peel_to_commit()?),
+        None => None,
+    })
+}
@@ -0,0 +1,512 @@
+//! A persistent queue of synchronization requests, drained by a background sync agent.
+//!
+//! Hooks enqueue a request and return immediately. The sync agent (`globalgraph sync run`) drains
+//! the queue, retrying with exponential backoff while the global graph is unreachable. Requests
+//! are stored as files under `<git dir>/globalgraph/`, so pending synchronizations survive reboots and
+//! are picked up again by the next hook that runs. Each linked worktree has its own git directory, and
+//! so its own queue and sync agent.
+
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The delay before the first retry. Doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The longest the agent will wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// After this many failed attempts in a row the agent exits. The requests stay queued, and the next
+/// hook that runs starts a new agent.
+const MAX_ATTEMPTS: u32 = 20;
+
+/// An agent lock that hasn't been refreshed for this long belongs to an agent that died.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// The state of the last synchronization attempts, persisted next to the queue.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    /// The error returned by the last failed attempt, cleared on success.
+    pub last_error: Option<String>,
+    /// Unix time of the last attempt.
+    pub last_attempt: Option<u64>,
+    /// Unix time of the last successful synchronization.
+    pub last_success: Option<u64>,
+    /// The number of attempts that failed since the last success.
+    pub consecutive_failures: u32,
+}
+
+/// A summary of the queue, as reported by `globalgraph sync status`.
+#[derive(Debug)]
+pub struct SyncStatus {
+    /// The number of synchronization requests waiting to be pushed.
+    pub queue_depth: usize,
+    /// Whether a sync agent currently holds the agent lock.
+    pub agent_running: bool,
+    pub state: SyncState,
+}
+
+/// The on-disk queue of synchronization requests for a single repository.
+pub struct SyncQueue {
+    directory: PathBuf,
+}
+
+impl SyncQueue {
+    /// Opens the queue for the given repository, creating its directory if needed.
+    pub fn open(repo: &Repository) -> Result<SyncQueue, Error> {
+        SyncQueue::open_in(repo.path())
+    }
+
+    /// Opens the queue kept in a git directory, creating its directory if needed. The main working
+    /// tree and each linked worktree keep their queue in their own git directory.
+    pub fn open_in(git_directory: &Path) -> Result<SyncQueue, Error> {
+        let directory = git_directory.join(shared::GLOBALGRAPH_REPO_NAME);
+        fs::create_dir_all(directory.join("queue"))
+            .context(format!("Could not create the sync queue directory under [{:?}].", directory))?;
+
+        Ok(SyncQueue { directory })
+    }
+
+    /// Adds a synchronization request to the queue. `reason` is recorded for diagnostics only, as
+    /// every synchronization pushes the full state of the repository.
+    pub fn enqueue(&self, reason: &str) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let request_name = format!("{}{:09}-{}", now.as_secs(), now.subsec_nanos(), std::process::id());
+        fs::write(self.directory.join("queue").join(&request_name), reason)
+            .context("Could not write a synchronization request to the sync queue.")?;
+
+        debug!("Queued synchronization request [{}] ({}).", request_name, reason);
+        Ok(())
+    }
+
+    /// Returns the paths of all queued requests.
+    pub fn pending(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut requests = vec!();
+        for entry in fs::read_dir(self.directory.join("queue"))? {
+            requests.push(entry?.path());
+        }
+        requests.sort();
+        Ok(requests)
+    }
+
+    /// Removes requests that were satisfied by a successful synchronization.
+    fn complete(&self, requests: &[PathBuf]) -> Result<(), Error> {
+        for request in requests {
+            if let Err(e) = fs::remove_file(request) {
+                // Another agent or hook may have completed this request already.
+                debug!("Could not remove completed request [{:?}]: {}", request, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the commits the reference-transaction hook queued a synchronization for, as branches
+    /// moved to them.
+    pub fn record_queued_tips(&self, tips: &[&str]) -> Result<(), Error> {
+        fs::write(self.directory.join("queued_tips"), tips.join("\n"))
+            .context("Could not record the branch tips queued for synchronization.")?;
+        Ok(())
+    }
+
+    /// Whether the last reference-transaction hook queued a synchronization for a branch moving to
+    /// `commit`. Git 2.28 and later run it before the post-commit hook, which then doesn't need to
+    /// queue another one.
+    pub fn is_tip_queued(&self, commit: &str) -> Result<bool, Error> {
+        match fs::read_to_string(self.directory.join("queued_tips")) {
+            Ok(tips) => Ok(tips.lines().any(|tip| tip == commit)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    pub fn state(&self) -> Result<SyncState, Error> {
+        let state_path = self.directory.join("sync_state.json");
+        if !state_path.exists() {
+            return Ok(SyncState::default());
+        }
+
+        let contents = fs::read_to_string(&state_path)?;
+        Ok(serde_json::from_str(&contents)
+            .context(format!("The sync state file [{:?}] is invalid.", state_path))?)
+    }
+
+    fn write_state(&self, state: &SyncState) -> Result<(), Error> {
+        fs::write(self.directory.join("sync_state.json"), serde_json::to_string_pretty(state)?)?;
+        Ok(())
+    }
+
+    fn record_success(&self) -> Result<(), Error> {
+        let mut state = self.state()?;
+        let now = unix_now()?;
+        state.last_attempt = Some(now);
+        state.last_success = Some(now);
+        state.last_error = None;
+        state.consecutive_failures = 0;
+        self.write_state(&state)
+    }
+
+    fn record_failure(&self, error: &Error) -> Result<(), Error> {
+        let mut state = self.state()?;
+        state.last_attempt = Some(unix_now()?);
+        state.last_error = Some(crate::error_chain(error));
+        state.consecutive_failures += 1;
+        self.write_state(&state)
+    }
+
+    pub fn status(&self) -> Result<SyncStatus, Error> {
+        let lock_path = self.lock_path();
+        Ok(SyncStatus {
+            queue_depth: self.pending()?.len(),
+            agent_running: lock_path.exists() && !is_lock_stale(&lock_path)?,
+            state: self.state()?,
+        })
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.directory.join("agent.lock")
+    }
+
+    /// Takes the agent lock, or returns None if another live agent already holds it.
+    fn lock_agent(&self) -> Result<Option<AgentLock>, Error> {
+        let lock_path = self.lock_path();
+        if lock_path.exists() && is_lock_stale(&lock_path)? {
+            warn!("Removing stale sync agent lock [{:?}].", lock_path);
+            fs::remove_file(&lock_path)?;
+        }
+
+        match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id())?;
+                Ok(Some(AgentLock { path: lock_path }))
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+}
+
+/// Held by the running sync agent. Removes the lock file when dropped.
+struct AgentLock {
+    path: PathBuf,
+}
+
+impl AgentLock {
+    /// Marks the agent as alive, so other agents don't consider the lock stale.
+    fn refresh(&self) -> Result<(), Error> {
+        fs::write(&self.path, format!("{}", std::process::id()))?;
+        Ok(())
+    }
+}
+
+impl Drop for AgentLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn is_lock_stale(lock_path: &Path) -> Result<bool, Error> {
+    let modified = fs::metadata(lock_path)?.modified()?;
+    Ok(match SystemTime::now().duration_since(modified) {
+        Ok(age) => age > STALE_LOCK_AGE,
+        // The lock was modified in the future, treat it as fresh.
+        Err(_) => false,
+    })
+}
+
+fn unix_now() -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Drains the sync queue of the repository at the given path, retrying with exponential backoff
+/// until the queue is empty or `MAX_ATTEMPTS` attempts failed in a row. Returns immediately if
+/// another agent is already draining this queue.
+pub fn run_sync_agent<P: AsRef<Path>>(repository_path: P) -> Result<(), Error> {
+    let repository_path = repository_path.as_ref();
+    let repo = Repository::open(repository_path)?;
+    let queue = SyncQueue::open(&repo)?;
+
+    loop {
+        let lock = match queue.lock_agent()? {
+            Some(lock) => lock,
+            None => {
+                debug!("Another sync agent is already draining the queue for [{:?}].", repository_path);
+                return Ok(());
+            }
+        };
+        drain_queue(repository_path, &queue, &lock)?;
+        drop(lock);
+
+        // A hook may have queued a request after the queue was found empty, and not started an agent
+        // because this one still held the lock. Nothing else would pick the request up.
+        if queue.pending()?.is_empty() {
+            debug!("The sync queue is empty. Sync agent exiting.");
+            return Ok(());
+        }
+        debug!("Requests were queued while the sync agent was exiting. Draining the queue again.");
+    }
+}
+
+/// Synchronizes until the queue is empty, retrying with exponential backoff. Fails after
+/// `MAX_ATTEMPTS` failed attempts in a row.
+fn drain_queue(repository_path: &Path, queue: &SyncQueue, lock: &AgentLock) -> Result<(), Error> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+    loop {
+        lock.refresh()?;
+
+        let requests = queue.pending()?;
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        match synchronize(repository_path) {
+            Ok(()) => {
+                info!("[Global Graph]: Synchronized [{}] queued request(s).", requests.len());
+                queue.complete(&requests)?;
+                queue.record_success()?;
+                backoff = INITIAL_BACKOFF;
+                attempts = 0;
+            }
+            Err(e) => {
+                queue.record_failure(&e)?;
+                attempts += 1;
+                if attempts >= MAX_ATTEMPTS {
+                    return Err(format_err!("Synchronization failed [{}] times in a row. Leaving [{}] request(s) queued. Last error: {}",
+                                           attempts, requests.len(), crate::error_chain(&e)));
+                }
+
+                warn!("[Global Graph]: Synchronization failed, retrying in [{}s]: {}", backoff.as_secs(), e);
+                thread::sleep(backoff);
+                backoff = next_backoff(backoff);
+            }
+        }
+    }
+}
+
+/// The delay before the next attempt, after one that was preceded by `backoff`.
+fn next_backoff(backoff: Duration) -> Duration {
+    std::cmp::min(backoff * 2, MAX_BACKOFF)
+}
+
+/// Synchronizes the repository, then uploads the commit rewrites that were waiting for the rewritten
+/// commits to reach the global graph.
+fn synchronize(repository_path: &Path) -> Result<(), Error> {
+    crate::synchronize_local_repository(repository_path)?;
+    crate::rewrites::upload_pending_rewrites(&Repository::open(repository_path)?)
+}
+
+/// Tries to drain the queue once in the current process. Used when background synchronization is
+/// disabled.
+fn drain_queue_once(repository_path: &Path, queue: &SyncQueue) -> Result<(), Error> {
+    let requests = queue.pending()?;
+    match synchronize(repository_path) {
+        Ok(()) => {
+            queue.complete(&requests)?;
+            queue.record_success()
+        }
+        Err(e) => {
+            queue.record_failure(&e)?;
+            Err(e)
+        }
+    }
+}
+
+/// Starts a detached sync agent for the repository, which keeps running after the hook exits.
+fn spawn_sync_agent(repo: &Repository, repository_path: &Path) -> Result<(), Error> {
+    let agent_path = crate::client_executable_path(repo);
+    if !agent_path.exists() {
+        return Err(format_err!("The client is not installed at [{:?}]. Re-run the Global Graph installer.", agent_path));
+    }
+
+    let mut command = Command::new(&agent_path);
+    command
+        .arg("sync")
+        .arg("run")
+        .arg("--git_path")
+        .arg(repository_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    detach(&mut command);
+
+    debug!("Spawning sync agent: {:?}", command);
+    command.spawn()
+        .context(format!("Could not start the sync agent [{:?}].", agent_path))?;
+    Ok(())
+}
+
+/// Detaches a process from the hook that starts it, so it keeps running after the hook exits.
+#[cfg(windows)]
+pub(crate) fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+/// Detaches a process from the hook that starts it, so it keeps running after the hook exits. The
+/// process starts a new session: it has no controlling terminal, and isn't sent the signals of the
+/// hook's session or process group.
+#[cfg(unix)]
+pub(crate) fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(any(windows, unix)))]
+pub(crate) fn detach(_command: &mut Command) {}
+
+/// Whether hooks hand synchronization to the background agent. Controlled by the local git config
+/// value `globalgraph.backgroundsync`, which defaults to true.
+fn background_sync_enabled(repo: &Repository) -> Result<bool, Error> {
+    match repo.config()?.get_bool("globalgraph.backgroundsync") {
+        Ok(enabled) => Ok(enabled),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(true),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Queues a synchronization of the repository with the global graph. Called by the post-* hooks.
+///
+/// With background synchronization the request is handed to the sync agent, and this returns
+/// without touching the network. Otherwise the queue is drained in this process, and only on failure
+/// is the sync agent started to retry.
+///
+/// Requests made while git replays the commits of a rebase are dropped, the rebased branch is
+/// synchronized once the rebase re-attaches HEAD.
+pub fn request_synchronization<P: AsRef<Path>>(repository_path: P, reason: &str) -> Result<(), Error> {
+    let repository_path = repository_path.as_ref();
+    let repo = Repository::open(repository_path)?;
+    if crate::rebase::is_replaying(&repo)? {
+        debug!("Not synchronizing the intermediate state of a rebase ({}).", reason);
+        return Ok(());
+    }
+
+    let queue = SyncQueue::open(&repo)?;
+    queue.enqueue(reason)?;
+
+    if background_sync_enabled(&repo)? {
+        match spawn_sync_agent(&repo, repository_path) {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("[Global Graph]: {} Synchronizing in the foreground instead.", e),
+        }
+    }
+
+    if let Err(e) = drain_queue_once(repository_path, &queue) {
+        warn!("[Global Graph]: Synchronization failed, it will be retried in the background: {}", e);
+        spawn_sync_agent(&repo, repository_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use failure::Error;
+    use super::*;
+
+    #[test]
+    fn queued_requests_are_kept_in_order() -> Result<(), Error> {
+        let git_directory = tempfile::tempdir()?;
+        let queue = SyncQueue::open_in(git_directory.path())?;
+        assert_eq!(queue.status()?.queue_depth, 0);
+
+        queue.enqueue("first")?;
+        queue.enqueue("second")?;
+        let pending = queue.pending()?;
+        assert_eq!(pending.len(), 2);
+        assert_eq!(fs::read_to_string(&pending[0])?, "first");
+        assert_eq!(fs::read_to_string(&pending[1])?, "second");
+
+        // Requests queued since the synchronization started stay queued.
+        queue.complete(&pending[..1])?;
+        queue.complete(&pending[..1])?;
+        assert_eq!(queue.pending()?, vec!(pending[1].clone()));
+
+        // The queue survives being reopened.
+        assert_eq!(SyncQueue::open_in(git_directory.path())?.status()?.queue_depth, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn queued_tips_are_remembered() -> Result<(), Error> {
+        let git_directory = tempfile::tempdir()?;
+        let queue = SyncQueue::open_in(git_directory.path())?;
+        assert!(!queue.is_tip_queued("1111111111111111111111111111111111111111")?);
+
+        queue.record_queued_tips(&["1111111111111111111111111111111111111111", "2222222222222222222222222222222222222222"])?;
+        assert!(queue.is_tip_queued("2222222222222222222222222222222222222222")?);
+
+        // Only the last transaction is remembered.
+        queue.record_queued_tips(&["3333333333333333333333333333333333333333"])?;
+        assert!(!queue.is_tip_queued("1111111111111111111111111111111111111111")?);
+        assert!(queue.is_tip_queued("3333333333333333333333333333333333333333")?);
+        Ok(())
+    }
+
+    #[test]
+    fn failures_are_counted_until_a_success() -> Result<(), Error> {
+        let git_directory = tempfile::tempdir()?;
+        let queue = SyncQueue::open_in(git_directory.path())?;
+
+        queue.record_failure(&format_err!("The global graph is unreachable."))?;
+        queue.record_failure(&format_err!("The global graph is still unreachable."))?;
+        let state = queue.state()?;
+        assert_eq!(state.consecutive_failures, 2);
+        assert_eq!(state.last_error, Some("The global graph is still unreachable.".to_string()));
+        assert_eq!(state.last_success, None);
+
+        queue.record_success()?;
+        let state = queue.state()?;
+        assert_eq!(state.consecutive_failures, 0);
+        assert_eq!(state.last_error, None);
+        assert!(state.last_success.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn only_one_agent_holds_the_lock() -> Result<(), Error> {
+        let git_directory = tempfile::tempdir()?;
+        let queue = SyncQueue::open_in(git_directory.path())?;
+
+        let lock = queue.lock_agent()?;
+        assert!(lock.is_some());
+        assert!(queue.lock_agent()?.is_none());
+        assert!(queue.status()?.agent_running);
+
+        drop(lock);
+        assert!(!queue.status()?.agent_running);
+        assert!(queue.lock_agent()?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut delays = vec!();
+        for _ in 0..10 {
+            delays.push(backoff.as_secs());
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(delays, vec!(2, 4, 8, 16, 32, 64, 128, 256, 300, 300));
+    }
+}
@@ -0,0 +1,187 @@
+//! Team-wide configuration, committed at the root of the repository as `.globalgraph.toml`, so every
+//! clone shares the same settings without passing them to the installer:
+//!
+//! ```toml
+//! # The query server, and the global graph repository.
+//! server = "https://server.com:12345"
+//! remote = "git@server.com:globalgraph.git"
+//!
+//! # Files that are lockable in addition to those with the `lockable` git attribute.
+//! lockable = ["*.uasset", "Content/Maps"]
+//!
+//! # What the pre-commit and pre-push hooks do when they can't check for conflicts, eg. because the
+//! # query server is unreachable: "block" the commit or push (the default), or "allow" it.
+//! failure_policy = "block"
+//!
+//! # The branches synchronized with the global graph. By default, every branch.
+//! [branches]
+//! include = ["master", "release/*"]
+//! exclude = ["wip/*"]
+//! ```
+//!
+//! Patterns are git pathspecs: `*` matches any characters including `/`, and a directory matches
+//! everything in it. Every setting can be overridden in the repository's git config, which takes
+//! precedence: `globalgraph.server`, the url of the `globalgraph` remote, and `globalgraph.lockable`,
+//! `globalgraph.failurepolicy`, `globalgraph.branches` and `globalgraph.excludebranches`. Lists are
+//! separated by spaces in git config.
+
+use git2::ErrorCode;
+use git2::Pathspec;
+use git2::PathspecFlags;
+use git2::Remote;
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use serde_derive::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The name of the team configuration file, at the root of the working tree.
+pub const TEAM_CONFIG_FILE: &str = ".globalgraph.toml";
+
+/// The contents of `.globalgraph.toml`. Every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TeamConfig {
+    /// The url of the query server.
+    pub server: Option<String>,
+    /// The url of the global graph repository.
+    pub remote: Option<String>,
+    /// Pathspecs of the files that are lockable, in addition to the `lockable` git attribute.
+    pub lockable: Vec<String>,
+    pub branches: BranchFilter,
+    pub failure_policy: Option<FailurePolicy>,
+}
+
+/// Which local branches are synchronized with the global graph.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct BranchFilter {
+    /// Pathspecs of the synchronized branches, by their short name. Empty means every branch.
+    pub include: Vec<String>,
+    /// Pathspecs of branches that aren't synchronized, even if they are included.
+    pub exclude: Vec<String>,
+}
+
+impl BranchFilter {
+    /// Whether the branch, by its short name (eg. `feature/a`), is synchronized.
+    pub fn is_synchronized(&self, branch_name: &str) -> Result<bool, Error> {
+        let included = self.include.is_empty() || matches_any(&self.include, branch_name)?;
+        Ok(included && !matches_any(&self.exclude, branch_name)?)
+    }
+}
+
+/// What the pre-commit and pre-push hooks do when they fail to check for conflicts.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Stop the commit or push.
+    Block,
+    /// Warn, and let the commit or push through.
+    Allow,
+}
+
+/// The path of the team configuration file, or None for a bare repository.
+pub fn team_config_path(repo: &Repository) -> Option<PathBuf> {
+    repo.workdir().map(|workdir| workdir.join(TEAM_CONFIG_FILE))
+}
+
+/// Reads `.globalgraph.toml` from the working tree. A missing file is an empty configuration.
+pub fn read_team_config(repo: &Repository) -> Result<TeamConfig, Error> {
+    let path = match team_config_path(repo) {
+        Some(path) => path,
+        None => return Ok(TeamConfig::default()),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(TeamConfig::default()),
+        Err(e) => return Err(Error::from(e).context(format!("Could not read [{:?}].", path)).into()),
+    };
+    Ok(toml::from_str(&contents).context(format!("[{:?}] is not a valid Global Graph configuration.", path))?)
+}
+
+/// The url of the query server: `globalgraph.server`, or `server` in `.globalgraph.toml`.
+pub fn query_server(repo: &Repository) -> Result<Option<String>, Error> {
+    match config_string(repo, "globalgraph.server")? {
+        Some(server) => Ok(Some(server)),
+        None => Ok(read_team_config(repo)?.server),
+    }
+}
+
+/// The url of the global graph repository: the url of the `globalgraph` remote, or `remote` in
+/// `.globalgraph.toml`.
+pub fn global_graph_url(repo: &Repository) -> Result<Option<String>, Error> {
+    match config_string(repo, &format!("remote.{}.url", shared::GLOBALGRAPH_REPO_NAME))? {
+        Some(url) => Ok(Some(url)),
+        None => Ok(read_team_config(repo)?.remote),
+    }
+}
+
+/// The remote branches are pushed to: the `globalgraph` remote if the repository has one, or else an
+/// anonymous remote for the url in `.globalgraph.toml`.
+pub fn global_graph_remote(repo: &Repository) -> Result<Remote, Error> {
+    if let Ok(remote) = repo.find_remote(shared::GLOBALGRAPH_REPO_NAME) {
+        return Ok(remote);
+    }
+    let url = read_team_config(repo)?.remote
+        .ok_or(format_err!("The repository has no [{}] remote, and [{}] sets no remote url. Re-run the Global Graph installer.", shared::GLOBALGRAPH_REPO_NAME, TEAM_CONFIG_FILE))?;
+    Ok(repo.remote_anonymous(&url).context(format!("[{}] is not a valid git remote url.", url))?)
+}
+
+/// The pathspecs of the files that are lockable in addition to the `lockable` git attribute:
+/// `globalgraph.lockable`, or `lockable` in `.globalgraph.toml`.
+pub fn lockable_patterns(repo: &Repository) -> Result<Vec<String>, Error> {
+    match config_list(repo, "globalgraph.lockable")? {
+        Some(patterns) => Ok(patterns),
+        None => Ok(read_team_config(repo)?.lockable),
+    }
+}
+
+/// The branches synchronized with the global graph: `globalgraph.branches` and
+/// `globalgraph.excludebranches`, or `[branches]` in `.globalgraph.toml`.
+pub fn branch_filter(repo: &Repository) -> Result<BranchFilter, Error> {
+    let mut filter = read_team_config(repo)?.branches;
+    if let Some(include) = config_list(repo, "globalgraph.branches")? {
+        filter.include = include;
+    }
+    if let Some(exclude) = config_list(repo, "globalgraph.excludebranches")? {
+        filter.exclude = exclude;
+    }
+    Ok(filter)
+}
+
+/// What the hooks do when they can't check for conflicts: `globalgraph.failurepolicy`, or
+/// `failure_policy` in `.globalgraph.toml`. Defaults to blocking.
+pub fn failure_policy(repo: &Repository) -> Result<FailurePolicy, Error> {
+    match config_string(repo, "globalgraph.failurepolicy")? {
+        Some(ref policy) if policy == "block" => Ok(FailurePolicy::Block),
+        Some(ref policy) if policy == "allow" => Ok(FailurePolicy::Allow),
+        Some(policy) => Err(format_err!("The git config value 'globalgraph.failurepolicy' is [{}], it must be 'block' or 'allow'.", policy)),
+        None => Ok(read_team_config(repo)?.failure_policy.unwrap_or(FailurePolicy::Block)),
+    }
+}
+
+/// Whether the path matches any of the pathspecs.
+pub fn matches_any(patterns: &[String], path: &str) -> Result<bool, Error> {
+    if patterns.is_empty() {
+        return Ok(false);
+    }
+    let pathspec = Pathspec::new(patterns.iter())
+        .context(format!("{:?} are not valid patterns.", patterns))?;
+    Ok(pathspec.matches_path(Path::new(path), PathspecFlags::DEFAULT))
+}
+
+fn config_string(repo: &Repository, name: &str) -> Result<Option<String>, Error> {
+    match repo.config()?.get_string(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn config_list(repo: &Repository, name: &str) -> Result<Option<Vec<String>>, Error> {
+    Ok(config_string(repo, name)?.map(|value| value.split_whitespace().map(|pattern| pattern.to_string()).collect()))
+}
@@ -0,0 +1,180 @@
+//! Configuration of repositories whose hooks came from a git template directory (see `installer
+//! install-template`). Git copies the template into every repository it clones or initializes, so
+//! the hooks run in repositories nobody installed Global Graph in. The first time a hook runs in such
+//! a repository, it asks the query servers listed in `globalgraph.knownservers` whether they serve
+//! the project the repository was cloned from (its `origin`), and configures the repository for the
+//! first one that does. A `.globalgraph.toml` naming one of the known servers has it asked first, but
+//! never configures a repository on its own. Repositories no server serves are marked with
+//! `globalgraph.unregistered`, and the hooks do nothing in them from then on. When a server can't be
+//! reached, the servers aren't asked again until `globalgraph.lookupretryafter`, so hooks don't keep
+//! waiting for a server that is down.
+
+use git2::ErrorCode;
+use git2::Repository;
+use failure::Error;
+use log::{debug, info, warn};
+use url::Url;
+use crate::manifest::{InstallManifest, InstalledFileKind};
+use crate::query_client::{QueryClient, AUTH_TOKEN_CONFIG};
+use shared::ProjectLookupRequest;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The git config value, usually global, listing the query servers new clones are looked up on,
+/// separated by spaces.
+pub const KNOWN_SERVERS_CONFIG: &str = "globalgraph.knownservers";
+
+/// The git config value marking a repository none of the known servers serves.
+pub const UNREGISTERED_CONFIG: &str = "globalgraph.unregistered";
+
+/// The git config value holding the Unix time before which the known servers aren't asked again,
+/// set when one of them couldn't be reached.
+pub const LOOKUP_RETRY_AFTER_CONFIG: &str = "globalgraph.lookupretryafter";
+
+/// The value of `globalGraph.installed` in repositories configured from a template.
+pub const TEMPLATE_INSTALLATION: &str = "template";
+
+/// How long a known server has to answer whether it serves a project.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the hooks wait before asking the known servers again, after one couldn't be reached.
+const LOOKUP_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Whether the hooks should run in the repository. Configures a repository the first time a hook
+/// runs in it, if it was cloned from a project a known server serves. Never fails, so the hooks of
+/// a template never get in the way of repositories that don't use Global Graph.
+pub fn ensure_configured(repo: &Repository) -> bool {
+    match try_ensure_configured(repo) {
+        Ok(configured) => configured,
+        Err(e) => {
+            warn!("[Global Graph]: Could not configure the repository from the known servers: {}", e);
+            false
+        }
+    }
+}
+
+fn try_ensure_configured(repo: &Repository) -> Result<bool, Error> {
+    if is_configured(repo)? {
+        return Ok(true);
+    }
+    if repo.is_bare() || repo.config()?.get_bool(UNREGISTERED_CONFIG).unwrap_or(false) {
+        return Ok(false);
+    }
+
+    let mut known_servers = known_servers(repo)?;
+    // The team configuration can only pick among the known servers: the one it names is asked first.
+    let team_server = crate::team_config::read_team_config(repo).ok().and_then(|team_config| team_config.server);
+    if let Some(position) = known_servers.iter().position(|server| Some(server) == team_server.as_ref()) {
+        let server = known_servers.remove(position);
+        known_servers.insert(0, server);
+    }
+    let auth_token = repo.config()?.get_string(AUTH_TOKEN_CONFIG).ok();
+    // A repository without an origin yet may get one later.
+    let origin_url = match repo.find_remote("origin") {
+        Ok(remote) => remote.url().map(|url| url.to_string()),
+        Err(_) => None,
+    };
+    let origin_url = match origin_url {
+        Some(origin_url) if !known_servers.is_empty() => origin_url,
+        _ => return Ok(false),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if let Ok(retry_after) = repo.config()?.get_i64(LOOKUP_RETRY_AFTER_CONFIG) {
+        if now < retry_after {
+            debug!("[Global Graph]: A known server could not be reached recently, not looking up [{}] again yet.", origin_url);
+            return Ok(false);
+        }
+    }
+
+    // An unreachable server may serve the project, so only give up once every server answered.
+    let mut unanswered = 0;
+    for server in &known_servers {
+        let server_url = match Url::parse(server) {
+            Ok(server_url) => server_url,
+            Err(e) => {
+                debug!("[Global Graph]: Skipping the known server [{}], which is not a valid url: {}", server, e);
+                continue;
+            }
+        };
+        // Hooks wait for the answer, so don't wait long for a server that is down.
+        let mut client = QueryClient::new(server_url).with_timeout(LOOKUP_TIMEOUT).with_retries(0);
+        if let Some(ref auth_token) = auth_token {
+            client = client.with_auth_token(auth_token.as_str());
+        }
+        let request = ProjectLookupRequest { origin_url: origin_url.clone() };
+        match client.lookup_project(&request).map(|response| response.global_graph_url) {
+            Ok(Some(global_graph_url)) => {
+                configure(repo, server, &global_graph_url)?;
+                return Ok(true);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!("[Global Graph]: Could not look up [{}] on [{}]: {}", origin_url, server, e);
+                unanswered += 1;
+            }
+        }
+    }
+
+    if unanswered == 0 {
+        debug!("[Global Graph]: No known server serves [{}], skipping this repository.", origin_url);
+        repo.config()?.set_bool(UNREGISTERED_CONFIG, true)?;
+    } else {
+        repo.config()?.set_i64(LOOKUP_RETRY_AFTER_CONFIG, now + LOOKUP_RETRY_INTERVAL.as_secs() as i64)?;
+    }
+    Ok(false)
+}
+
+/// Whether the installer or an earlier hook configured the repository. A `.globalgraph.toml` alone
+/// doesn't: the repository chose it, and may point at any server.
+fn is_configured(repo: &Repository) -> Result<bool, Error> {
+    match repo.config()?.get_string("globalGraph.installed") {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// The query servers listed in `globalgraph.knownservers`.
+pub fn known_servers(repo: &Repository) -> Result<Vec<String>, Error> {
+    match repo.config()?.get_string(KNOWN_SERVERS_CONFIG) {
+        Ok(servers) => Ok(servers.split_whitespace().map(|server| server.to_string()).collect()),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(vec!()),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Configures the repository the way the installer does, and records the hooks the template
+/// installed in the install manifest.
+fn configure(repo: &Repository, server_url: &str, global_graph_url: &str) -> Result<(), Error> {
+    info!("[Global Graph]: Configuring this repository for the global graph [{}].", global_graph_url);
+    // First, as it fails without user.name, before anything marks the repository as configured.
+    crate::get_or_create_client_uuid(repo)?;
+    if repo.find_remote(shared::GLOBALGRAPH_REPO_NAME).is_err() {
+        repo.remote(shared::GLOBALGRAPH_REPO_NAME, global_graph_url)?;
+    }
+    let mut config = repo.config()?;
+    config.set_str("globalgraph.server", server_url)?;
+    config.set_str("globalGraph.installed", TEMPLATE_INSTALLATION)?;
+    // Only set if an earlier lookup failed.
+    let _ = config.remove(LOOKUP_RETRY_AFTER_CONFIG);
+    // Hooks must not fail, and the namespace is claimed by its first secret anyway.
+    if let Err(e) = crate::query::claim_namespace(repo) {
+        warn!("[Global Graph]: Could not claim this repository's namespace: {}", crate::error_chain(&e));
+    }
+
+    let mut manifest = InstallManifest::new();
+    manifest.add_file(repo, InstalledFileKind::ClientExecutable)?;
+    for hook_name in crate::hooks::HOOK_NAMES {
+        let dispatcher = InstalledFileKind::Dispatcher(hook_name.to_string());
+        let hook_script = InstalledFileKind::HookScript(hook_name.to_string());
+        if dispatcher.path(repo).is_file() && hook_script.path(repo).is_file() {
+            manifest.add_file(repo, dispatcher)?;
+            manifest.add_file(repo, hook_script)?;
+        }
+    }
+    for name in &[format!("remote.{}.url", shared::GLOBALGRAPH_REPO_NAME), "globalgraph.server".to_string(),
+        "globalgraph.repouuid".to_string(), "globalGraph.installed".to_string()] {
+        manifest.add_config(repo, name)?;
+    }
+    crate::manifest::write_manifest(repo, &manifest)
+}
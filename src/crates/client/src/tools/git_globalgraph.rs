@@ -0,0 +1,150 @@
+//! The commands that answer questions about a repository (`globalgraph conflicts`, `status` and
+//! `doctor`). Git runs any `git-<name>` executable on the PATH as `git <name>`, so with the
+//! `globalgraph` executable on the PATH as `git-globalgraph`, developers can ask the Global Graph
+//! about conflicts with `git globalgraph` before they start or commit work.
+//!
+//! Each command returns whether it found conflicts or configuration problems, and the executable then
+//! exits with status 2, so it can be used from scripts.
+
+use client::doctor;
+use client::doctor::CheckStatus;
+use client::query;
+use failure::Error;
+use failure::format_err;
+use git2::Repository;
+use git2::Status;
+use log::warn;
+use shared::GitPath;
+use shared::UnintegratedChange;
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Opens the repository the current directory is in.
+pub fn open_repository() -> Result<Repository, Error> {
+    Ok(Repository::discover(env::current_dir()?)?)
+}
+
+/// Prints the conflicts for the given files, and returns whether there were any.
+pub fn check_conflicts(repo: &Repository, staged: bool, paths: Vec<PathBuf>) -> Result<bool, Error> {
+    synchronize(repo);
+
+    let files = if paths.is_empty() {
+        client::lockable_paths(repo, modified_paths(repo, staged)?)?
+    } else {
+        paths.iter().map(|path| resolve_path(repo, path)).collect::<Result<Vec<_>, _>>()?
+    };
+
+    if files.is_empty() {
+        println!("[Global Graph]: No modified lockable files to check.");
+        return Ok(false);
+    }
+
+    let conflicts = query::find_unintegrated_changes(repo, query::NextCommit::in_progress(repo)?, files)?;
+    if conflicts.is_empty() {
+        println!("[Global Graph]: No conflicts found.");
+        return Ok(false);
+    }
+
+    println!("[Global Graph]: Found one or more conflicting changes in other repositories:");
+    print_changes(&conflicts);
+    Ok(true)
+}
+
+/// Prints the lockable files changed elsewhere that HEAD doesn't integrate, and returns whether
+/// there were any.
+pub fn print_status(repo: &Repository) -> Result<bool, Error> {
+    synchronize(repo);
+
+    let mut changes = vec!();
+    for change in query::list_unintegrated_changes(repo, query::head_commit(repo)?)? {
+        if client::is_lockable(repo, &change.file)? {
+            changes.push(change);
+        }
+    }
+
+    if changes.is_empty() {
+        println!("[Global Graph]: Your HEAD integrates every change to lockable files.");
+        return Ok(false);
+    }
+
+    println!("[Global Graph]: Lockable files changed in other repositories that your HEAD doesn't integrate:");
+    print_changes(&changes);
+    Ok(true)
+}
+
+fn print_changes(changes: &[UnintegratedChange]) {
+    for change in changes {
+        for line in query::describe_conflict(change) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Prints the result of every configuration check, and returns whether any of them failed.
+pub fn print_diagnostics(repo: &Repository) -> bool {
+    let diagnostics = doctor::run_diagnostics(repo);
+    for diagnostic in &diagnostics {
+        let label = match diagnostic.status {
+            CheckStatus::Passed => "  OK",
+            CheckStatus::Warning => "WARN",
+            CheckStatus::Failed => "FAIL",
+        };
+        println!("[{}] {}: {}", label, diagnostic.check, diagnostic.message);
+        if let Some(ref fix) = diagnostic.fix {
+            println!("       Fix: {}", fix);
+        }
+    }
+
+    let failures = diagnostics.iter().filter(|diagnostic| diagnostic.status == CheckStatus::Failed).count();
+    let warnings = diagnostics.iter().filter(|diagnostic| diagnostic.status == CheckStatus::Warning).count();
+    println!("\n[Global Graph]: {} checks, {} failed, {} warnings.", diagnostics.len(), failures, warnings);
+    failures > 0
+}
+
+/// Pushes local work first, so the server's answer accounts for the latest state. The query is
+/// still useful with slightly stale data, so failures are only reported.
+fn synchronize(repo: &Repository) {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return,
+    };
+
+    if let Err(e) = client::synchronize_local_repository(workdir) {
+        warn!("[Global Graph]: Could not synchronize with the Global Graph, results may be out of date: {}", e);
+    }
+}
+
+/// Lists the paths of files changed in the index, and unless `staged_only`, in the working tree.
+fn modified_paths(repo: &Repository, staged_only: bool) -> Result<Vec<GitPath>, Error> {
+    let mut changed_status = Status::INDEX_NEW | Status::INDEX_MODIFIED | Status::INDEX_DELETED | Status::INDEX_RENAMED;
+    if !staged_only {
+        changed_status |= Status::WT_NEW | Status::WT_MODIFIED | Status::WT_DELETED | Status::WT_RENAMED;
+    }
+
+    let mut paths = vec!();
+    for entry in repo.statuses(None)?.iter().filter(|entry| changed_status.intersects(entry.status())) {
+        match entry.path() {
+            Some(path) => paths.push(GitPath::new(path)),
+            None => warn!("[Global Graph] Warning: Path is not valid UTF8 and will be ignored for conflict checks. [{}](lossy)", String::from_utf8_lossy(entry.path_bytes())),
+        }
+    }
+    Ok(paths)
+}
+
+/// Converts a path given on the command line to a git path. Paths that exist are resolved relative
+/// to the current directory; paths that don't (for example files deleted locally) are taken to be
+/// relative to the root of the working tree.
+fn resolve_path(repo: &Repository, path: &Path) -> Result<GitPath, Error> {
+    let workdir = repo.workdir()
+        .ok_or(format_err!("The repository [{:?}] has no working tree.", repo.path()))?
+        .canonicalize()?;
+
+    match env::current_dir()?.join(path).canonicalize() {
+        Ok(absolute_path) => client::to_git_path(&workdir, &absolute_path)
+            .ok_or(format_err!("The path [{:?}] is not in the working tree [{:?}].", path, workdir)),
+        Err(_) => path.to_str()
+            .map(|path| GitPath::new(&path.replace('\\', "/")))
+            .ok_or(format_err!("The path [{:?}] is not valid UTF8.", path)),
+    }
+}
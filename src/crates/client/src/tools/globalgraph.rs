@@ -0,0 +1,158 @@
+//! The Global Graph client executable. A single binary runs the hooks, the sync agent, the edit
+//! watcher and the commands developers use, so there is only one file to install and update:
+//!
+//!   globalgraph hook <hook> [<arguments>...]   Runs a git hook (see `client::hooks`).
+//!   globalgraph sync <run|worktree|status>     The sync agent.
+//!   globalgraph watch                          The edit watcher.
+//!   globalgraph update <run|status>            Updates the client from the query server.
+//!   globalgraph <conflicts|status|doctor>      Answers questions about the repository.
+//!
+//! It also behaves like the executable it is named (or linked) as: `git-globalgraph` on the PATH
+//! makes it `git globalgraph`, `globalgraph-sync` and `globalgraph-watch` take the arguments of the
+//! `sync` and `watch` commands, and a hook name (eg. `pre-commit`) runs that hook.
+//!
+//! Exits with status 2 if a hook found conflicts, or a command found conflicts or configuration
+//! problems, so it can be used from scripts.
+
+use failure::Error;
+use log::error;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+mod git_globalgraph;
+mod sync_agent;
+mod update;
+mod watcher;
+
+/// Embedded in the client executable, so the version of an installed executable can be read from its
+/// file (see `client::doctor::installed_binary_version`). It is defined in the executable and read by
+/// `main`, as the linker may drop statics of the `client` library that nothing references.
+#[used]
+static VERSION_MARKER: &str = concat!("globalgraph-client-version=", env!("CARGO_PKG_VERSION"), ";");
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "globalgraph")]
+enum Opt {
+    /// Checks whether committing the given files would conflict with work in another repository.
+    /// Without paths, checks every modified lockable file.
+    #[structopt(name = "conflicts")]
+    Conflicts {
+        /// Only check the lockable files staged in the index.
+        #[structopt(long = "staged")]
+        staged: bool,
+
+        /// The files to check, relative to the current directory.
+        #[structopt(parse(from_os_str))]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Lists the lockable files that other repositories changed and your HEAD doesn't integrate yet.
+    #[structopt(name = "status")]
+    Status,
+
+    /// Checks that this repository is set up correctly for the Global Graph, and explains how to fix
+    /// anything that isn't.
+    #[structopt(name = "doctor")]
+    Doctor,
+
+    /// Runs the sync agent, or shows the state of the sync queue.
+    #[structopt(name = "sync")]
+    Sync(sync_agent::Opt),
+
+    /// Warns as soon as you start editing a lockable file that conflicts with work in another
+    /// repository.
+    #[structopt(name = "watch")]
+    Watch(watcher::Opt),
+
+    /// Updates the client to the version the query server hosts, or shows the result of the last
+    /// update.
+    #[structopt(name = "update")]
+    Update(update::Opt),
+
+    /// Runs a git hook, with the arguments and input git passed to it. Run by the hook scripts the
+    /// installer writes.
+    #[structopt(name = "hook")]
+    Hook {
+        /// The name of the hook, eg. pre-commit.
+        hook_name: String,
+
+        /// The arguments git passed to the hook.
+        arguments: Vec<String>,
+    },
+}
+
+fn main() -> Result<(), Error> {
+    // The volatile read can't be optimized away, so the marker stays in the executable.
+    let _ = unsafe { std::ptr::read_volatile(&VERSION_MARKER) };
+    client::init_logging();
+
+    let arguments: Vec<OsString> = std::env::args_os().collect();
+    let program_name = arguments.first()
+        .and_then(|program| Path::new(program).file_stem())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if client::hooks::is_hook(&program_name) {
+        let hook_arguments: Vec<String> = arguments.iter().skip(1).map(|argument| argument.to_string_lossy().into_owned()).collect();
+        return run_hook(&program_name, &hook_arguments);
+    }
+
+    let args = match program_name.as_str() {
+        "globalgraph-sync" => Opt::Sync(sync_agent::Opt::from_iter(arguments)),
+        "globalgraph-watch" => Opt::Watch(watcher::Opt::from_iter(arguments)),
+        "git-globalgraph" => Opt::from_clap(&Opt::clap().bin_name("git globalgraph").get_matches_from(arguments)),
+        _ => Opt::from_iter(arguments),
+    };
+
+    let found_problems = match args {
+        Opt::Conflicts { staged, paths } => git_globalgraph::check_conflicts(&git_globalgraph::open_repository()?, staged, paths)?,
+        Opt::Status => git_globalgraph::print_status(&git_globalgraph::open_repository()?)?,
+        Opt::Doctor => git_globalgraph::print_diagnostics(&git_globalgraph::open_repository()?),
+        Opt::Sync(args) => sync_agent::run(args).map(|()| false)?,
+        Opt::Watch(args) => watcher::run(args).map(|()| false)?,
+        Opt::Update(args) => update::run(args).map(|()| false)?,
+        Opt::Hook { hook_name, arguments } => return run_hook(&hook_name, &arguments),
+    };
+
+    if found_problems {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Runs a hook, and stops the git operation if it found conflicts. Afterwards, starts an update of
+/// the client if one may be available. Hooks installed from a git template configure the repository
+/// first, and do nothing in repositories that don't take part in the Global Graph (see
+/// `client::template`).
+fn run_hook(hook_name: &str, arguments: &[String]) -> Result<(), Error> {
+    // Git runs some hooks several times per operation, eg. reference-transaction for each state of a
+    // transaction. The runs that do nothing shouldn't wait for the known servers or check for updates.
+    if client::hooks::does_nothing(hook_name, arguments) {
+        return Ok(());
+    }
+
+    // Git runs hooks from the root of the working tree, except while cloning, when it only sets
+    // GIT_DIR. The hooks find the repository from the current directory.
+    let repo = git2::Repository::open_from_env().ok();
+    if let Some(ref repo) = repo {
+        if let Some(workdir) = repo.workdir() {
+            std::env::set_current_dir(workdir)?;
+        }
+        if !client::template::ensure_configured(repo) {
+            return Ok(());
+        }
+    }
+
+    let result = client::hooks::run_hook(hook_name, arguments);
+    if let Some(ref repo) = repo {
+        client::update::check_for_update(repo);
+    }
+
+    if !result? {
+        error!("Conflicts found. Exiting with status: [2].");
+        std::process::exit(2);
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! The Global Graph sync agent (`globalgraph sync`). Started in the background by the hooks to drain
+//! the sync queue of a repository, and used by developers to inspect that queue.
+
+use client::sync_queue;
+use failure::Error;
+use log::{info, warn};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "globalgraph-sync")]
+pub enum Opt {
+    /// Synchronizes all queued requests with the global graph, retrying while the server is unreachable.
+    #[structopt(name = "run")]
+    Run {
+        /// The path to the git repository whose queue should be drained.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+    },
+
+    /// Periodically synchronizes the repository, including a snapshot of the modified lockable files
+    /// in the working tree, so other developers are told which files you are currently editing.
+    /// Enables `globalgraph.worktreesync` for the repository.
+    #[structopt(name = "worktree")]
+    Worktree {
+        /// The path to the git repository to synchronize.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+
+        /// The number of seconds between two snapshots.
+        #[structopt(long = "interval", default_value = "60")]
+        interval_seconds: u64,
+    },
+
+    /// Shows the number of queued synchronizations and the last error.
+    #[structopt(name = "status")]
+    Status {
+        /// The path to the git repository to inspect.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+    },
+}
+
+pub fn run(args: Opt) -> Result<(), Error> {
+    match args {
+        Opt::Run { git_directory } => sync_queue::run_sync_agent(&git_directory),
+        Opt::Worktree { git_directory, interval_seconds } => synchronize_worktree(&git_directory, interval_seconds),
+        Opt::Status { git_directory } => print_status(&git_directory),
+    }
+}
+
+fn synchronize_worktree(git_directory: &PathBuf, interval_seconds: u64) -> Result<(), Error> {
+    let repo = git2::Repository::open(git_directory)?;
+    if !client::worktree_sync_enabled(&repo)? {
+        info!("Enabling working tree synchronization for [{:?}].", repo.path());
+        repo.config()?.set_bool("globalgraph.worktreesync", true)?;
+    }
+
+    println!("[Global Graph]: Synchronizing modified lockable files every [{}s]. Press Ctrl+C to stop.", interval_seconds);
+    loop {
+        if let Err(e) = client::synchronize_local_repository(git_directory) {
+            warn!("[Global Graph]: Synchronization failed, retrying in [{}s]: {}", interval_seconds, e);
+        }
+        thread::sleep(Duration::from_secs(interval_seconds));
+    }
+}
+
+fn print_status(git_directory: &PathBuf) -> Result<(), Error> {
+    let repo = git2::Repository::open(git_directory)?;
+    let status = sync_queue::SyncQueue::open(&repo)?.status()?;
+
+    println!("Queued synchronizations: {}", status.queue_depth);
+    println!("Sync agent running:      {}", if status.agent_running { "yes" } else { "no" });
+    println!("Last attempt:            {}", format_time(status.state.last_attempt));
+    println!("Last success:            {}", format_time(status.state.last_success));
+    println!("Failures since success:  {}", status.state.consecutive_failures);
+    if let Some(error) = status.state.last_error {
+        println!("Last error:              {}", error);
+    }
+
+    Ok(())
+}
+
+pub fn format_time(unix_seconds: Option<u64>) -> String {
+    match unix_seconds {
+        Some(seconds) => {
+            let elapsed = (UNIX_EPOCH + Duration::from_secs(seconds)).elapsed()
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0);
+            format!("{}s ago", elapsed)
+        }
+        None => "never".to_string(),
+    }
+}
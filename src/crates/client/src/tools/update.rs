@@ -0,0 +1,82 @@
+//! Client self-update (`globalgraph update`). The hooks start `update run` in the background when the
+//! server may have a new version (see `client::update`), and developers use it to update right away
+//! or to see why an update failed.
+
+use client::update;
+use client::update::UpdateOutcome;
+use failure::Error;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "globalgraph-update")]
+pub enum Opt {
+    /// Updates the installed client to the pinned version (globalgraph.clientversion), or to the
+    /// newest version the query server hosts unless it is older than this client.
+    #[structopt(name = "run")]
+    Run {
+        /// The path to the git repository whose client should be updated.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+    },
+
+    /// Shows the installed and available versions, and the result of the last update.
+    #[structopt(name = "status")]
+    Status {
+        /// The path to the git repository to inspect.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+    },
+
+    /// Rewrites the installed hook scripts and outdated dispatchers with this version's. Run by the
+    /// update after installing a new version.
+    #[structopt(name = "refresh-hooks")]
+    RefreshHooks {
+        /// The path to the git repository whose hooks should be refreshed.
+        #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+        git_directory: PathBuf,
+    },
+}
+
+pub fn run(args: Opt) -> Result<(), Error> {
+    match args {
+        Opt::Run { git_directory } => {
+            match update::run_update(&git2::Repository::open(git_directory)?)? {
+                UpdateOutcome::NoRelease => println!("[Global Graph]: The query server hosts no client releases."),
+                UpdateOutcome::UpToDate(version) => println!("[Global Graph]: The client is up to date (version [{}]).", version),
+                UpdateOutcome::Updated(version) => println!("[Global Graph]: Updated the client to version [{}].", version),
+                UpdateOutcome::NotDowngraded(version) => println!("[Global Graph]: The query server's newest client [{}] is older than this one [{}]. \
+                Set the git config value '{}' to downgrade to it.", version, client::VERSION, update::PINNED_VERSION_CONFIG),
+            }
+            Ok(())
+        }
+        Opt::Status { git_directory } => print_status(&git_directory),
+        Opt::RefreshHooks { git_directory } => update::refresh_hooks(&git2::Repository::open(git_directory)?),
+    }
+}
+
+fn print_status(git_directory: &PathBuf) -> Result<(), Error> {
+    let repo = git2::Repository::open(git_directory)?;
+    let installed_version = client::doctor::installed_binary_version(&client::client_executable_path(&repo))
+        .ok().and_then(|version| version)
+        .unwrap_or_else(|| "not installed".to_string());
+    let pinned_version = update::pinned_version(&repo)?;
+    let state = update::read_state(&repo);
+
+    println!("Installed version:       {}", installed_version);
+    println!("Pinned version:          {}", pinned_version.unwrap_or_else(|| "none, follows the server".to_string()));
+    match update::fetch_server_info(&repo) {
+        Ok(info) => println!("Server version:          {}", info.current_client_version.unwrap_or_else(|| "no client releases".to_string())),
+        Err(e) => println!("Server version:          unknown ({})", e),
+    }
+    println!("Signature checks:        {}", if update::UPDATE_PUBLIC_KEY.is_some() { "enabled" } else { "no update key, updates are off" });
+    println!("Last check:              {}", crate::sync_agent::format_time(state.last_check));
+    if let Some((version, time)) = state.last_update {
+        println!("Last update:             to {}, {}", version, crate::sync_agent::format_time(Some(time)));
+    }
+    if let Some(error) = state.last_error {
+        println!("Last error:              {}", error);
+    }
+
+    Ok(())
+}
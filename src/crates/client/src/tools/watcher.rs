@@ -0,0 +1,19 @@
+//! The Global Graph edit watcher (`globalgraph watch`). Runs in the background while you work, and
+//! warns as soon as you start editing a lockable file that would conflict with work in another
+//! repository.
+
+use failure::Error;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "globalgraph-watch")]
+pub struct Opt {
+    /// The path to the git repository whose working tree should be watched.
+    #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+    git_directory: PathBuf,
+}
+
+pub fn run(args: Opt) -> Result<(), Error> {
+    client::watcher::run_edit_watcher(&args.git_directory)
+}
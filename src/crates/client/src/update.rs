@@ -0,0 +1,333 @@
+//! Self-update of the client executable from the query server.
+//!
+//! The server hosts signed client executables for each platform, and advertises the newest one
+//! through `v1/info`. After each hook runs, the client checks (at most every `UPDATE_CHECK_INTERVAL`)
+//! whether the server has a different version than the one it runs, and if so starts `globalgraph
+//! update run` in the background, which downloads the new executable, verifies its signature against
+//! the public key compiled into the client, and replaces the installed executable and hook scripts.
+//!
+//! The client never downgrades itself to the server's newest version, eg. after a release was removed
+//! from the server. Set `globalgraph.clientversion` to pin a version instead of following the server's
+//! newest, including an older one, or `globalgraph.autoupdate` to false to only update with
+//! `globalgraph update run`.
+
+use git2::ErrorCode;
+use git2::Repository;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::{debug, info};
+use ring::signature;
+use serde_derive::{Deserialize, Serialize};
+use crate::manifest::InstalledFileKind;
+use crate::query_client::QueryClient;
+use shared::ClientRelease;
+use shared::InfoResponse;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The base64 encoded ed25519 public key client releases are signed with. Set at build time from the
+/// `GLOBALGRAPH_UPDATE_PUBLIC_KEY` environment variable (see `client-release generate-key`). Clients
+/// built without it never update themselves.
+pub const UPDATE_PUBLIC_KEY: Option<&str> = option_env!("GLOBALGRAPH_UPDATE_PUBLIC_KEY");
+
+/// The git config value pinning the client to a version. Unset, the client follows the newest version
+/// the server hosts.
+pub const PINNED_VERSION_CONFIG: &str = "globalgraph.clientversion";
+
+/// The git config value that turns the update checks after each hook off. Defaults to true.
+pub const AUTO_UPDATE_CONFIG: &str = "globalgraph.autoupdate";
+
+/// How long the hooks wait between two checks for an update.
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long the client waits for the server. Update checks must never hold up a hook for long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of the last update checks, shared by every worktree of the repository.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateState {
+    /// Unix time of the last check for an update.
+    pub last_check: Option<u64>,
+    /// The error returned by the last failed update, cleared on success.
+    pub last_error: Option<String>,
+    /// The version installed by the last update, and the Unix time it was installed at.
+    pub last_update: Option<(String, u64)>,
+}
+
+/// What `run_update` did.
+#[derive(Debug, PartialEq)]
+pub enum UpdateOutcome {
+    /// The server hosts no client releases.
+    NoRelease,
+    /// The installed executable already is the version the client should run.
+    UpToDate(String),
+    /// The executable was replaced by this version.
+    Updated(String),
+    /// The server's newest version is older than this client, and isn't pinned.
+    NotDowngraded(String),
+}
+
+/// The path of the update state file.
+fn state_path(repo: &Repository) -> PathBuf {
+    crate::common_git_directory(repo).join(shared::GLOBALGRAPH_REPO_NAME).join("update_state.json")
+}
+
+/// Reads the state of the last update checks. A missing or unreadable state counts as no check yet.
+pub fn read_state(repo: &Repository) -> UpdateState {
+    fs::read(state_path(repo)).ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn write_state(repo: &Repository, state: &UpdateState) -> Result<(), Error> {
+    let path = state_path(repo);
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(&path, serde_json::to_vec_pretty(state)?)
+        .context(format!("Could not write the update state to [{:?}].", path))?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+/// The version the repository is pinned to, if any.
+pub fn pinned_version(repo: &Repository) -> Result<Option<String>, Error> {
+    match repo.config()?.get_string(PINNED_VERSION_CONFIG) {
+        Ok(ref version) if version.trim().is_empty() => Ok(None),
+        Ok(version) => Ok(Some(version.trim().to_string())),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+fn auto_update_enabled(repo: &Repository) -> Result<bool, Error> {
+    match repo.config()?.get_bool(AUTO_UPDATE_CONFIG) {
+        Ok(enabled) => Ok(enabled),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(true),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Run after every hook. Starts a background update unless updates are turned off, or the last check
+/// was recent. Never fails, so a hook is never stopped by an update.
+pub fn check_for_update(repo: &Repository) {
+    if let Err(e) = try_check_for_update(repo) {
+        debug!("[Global Graph]: Could not check for a client update: {}", e);
+    }
+}
+
+fn try_check_for_update(repo: &Repository) -> Result<(), Error> {
+    if UPDATE_PUBLIC_KEY.is_none() || !auto_update_enabled(repo)? {
+        return Ok(());
+    }
+
+    let mut state = read_state(repo);
+    if let Some(last_check) = state.last_check {
+        if now().saturating_sub(last_check) < UPDATE_CHECK_INTERVAL.as_secs() {
+            return Ok(());
+        }
+    }
+
+    // Recorded before the update runs, so hooks running meanwhile don't start another one.
+    state.last_check = Some(now());
+    write_state(repo, &state)?;
+    spawn_update(repo)
+}
+
+/// Starts a detached `globalgraph update run`, which keeps running after the hook exits.
+fn spawn_update(repo: &Repository) -> Result<(), Error> {
+    let executable_path = crate::client_executable_path(repo);
+    let mut command = Command::new(&executable_path);
+    command
+        .arg("update")
+        .arg("run")
+        .arg("--git_path")
+        .arg(repo.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    crate::sync_queue::detach(&mut command);
+
+    debug!("Spawning client update: {:?}", command);
+    command.spawn()
+        .context(format!("Could not start the client update [{:?}].", executable_path))?;
+    Ok(())
+}
+
+/// Asks the query server which client releases it hosts.
+pub fn fetch_server_info(repo: &Repository) -> Result<InfoResponse, Error> {
+    QueryClient::for_repository(repo)?.with_timeout(REQUEST_TIMEOUT).info()
+}
+
+/// Updates the installed client executable to the pinned version, or to the newest version the
+/// server hosts unless it is older than this client, and records the result in the update state.
+pub fn run_update(repo: &Repository) -> Result<UpdateOutcome, Error> {
+    let result = update(repo);
+
+    let mut state = read_state(repo);
+    state.last_check = Some(now());
+    match result {
+        Ok(ref outcome) => {
+            state.last_error = None;
+            if let UpdateOutcome::Updated(ref version) = *outcome {
+                state.last_update = Some((version.clone(), now()));
+            }
+        }
+        Err(ref e) => state.last_error = Some(e.to_string()),
+    }
+    write_state(repo, &state)?;
+
+    result
+}
+
+fn update(repo: &Repository) -> Result<UpdateOutcome, Error> {
+    let public_key = UPDATE_PUBLIC_KEY
+        .ok_or(format_err!("This client was built without an update key (GLOBALGRAPH_UPDATE_PUBLIC_KEY), so it can't verify updates. Re-run the Global Graph installer to update it."))?;
+
+    let info = fetch_server_info(repo)?;
+    let version = match pinned_version(repo)? {
+        Some(version) => version,
+        None => match info.current_client_version {
+            Some(ref version) if shared::compare_versions(version, crate::VERSION) == Ordering::Less => {
+                debug!("The newest client the server hosts [{}] is older than this client [{}], not downgrading.", version, crate::VERSION);
+                return Ok(UpdateOutcome::NotDowngraded(version.clone()));
+            }
+            Some(version) => version,
+            None => return Ok(UpdateOutcome::NoRelease),
+        },
+    };
+
+    let installed_version = crate::doctor::installed_binary_version(&crate::client_executable_path(repo))?;
+    if installed_version.as_ref() == Some(&version) {
+        return Ok(UpdateOutcome::UpToDate(version));
+    }
+
+    let platform = shared::client_platform();
+    let release = info.client_releases.iter()
+        .find(|release| release.version == version && release.platform == platform)
+        .ok_or(format_err!("The Global Graph server has no [{}] client for [{}].", version, platform))?;
+
+    info!("[Global Graph]: Updating the client from version [{}] to [{}].", installed_version.unwrap_or_default(), version);
+    let executable = QueryClient::for_repository(repo)?.download_client(release)?;
+    verify_release(public_key, release, &executable)?;
+    install_client_executable(repo, &executable)?;
+    crate::manifest::record_files(repo, &[InstalledFileKind::ClientExecutable])?;
+
+    // The new version may write different hook scripts and dispatchers.
+    let status = Command::new(crate::client_executable_path(repo))
+        .arg("update")
+        .arg("refresh-hooks")
+        .arg("--git_path")
+        .arg(repo.path())
+        .status()
+        .context("Could not run the updated client.")?;
+    if !status.success() {
+        return Err(format_err!("The updated client could not refresh the hooks, it exited with [{}].", status));
+    }
+
+    Ok(UpdateOutcome::Updated(version))
+}
+
+/// Checks that the release was signed with the private key matching `public_key`.
+pub fn verify_release(public_key: &str, release: &ClientRelease, executable: &[u8]) -> Result<(), Error> {
+    let public_key = base64::decode(public_key.trim()).context("The update public key is not valid base64.")?;
+    let release_signature = base64::decode(release.signature.trim()).context("The release signature is not valid base64.")?;
+
+    let message = shared::client_release_message(&release.version, &release.platform, executable);
+    signature::verify(&signature::ED25519, untrusted::Input::from(&public_key), untrusted::Input::from(&message),
+                      untrusted::Input::from(&release_signature))
+        .map_err(|_| format_err!("The signature of the [{}] client for [{}] is invalid. The update was not installed.", release.version, release.platform))
+}
+
+/// Installs the client executable to the repository's hooks directory (see
+/// `crate::client_executable_path`). It is written next to the installed executable and then moved
+/// over it, since a hook or sync agent may be running it.
+pub fn install_client_executable(repo: &Repository, executable: &[u8]) -> Result<(), Error> {
+    let executable_path = crate::client_executable_path(repo);
+    let new_executable_path = executable_path.with_extension("new");
+    fs::create_dir_all(crate::hooks_directory(repo))?;
+    fs::write(&new_executable_path, executable)?;
+    make_executable(&new_executable_path)?;
+    replace_file(&new_executable_path, &executable_path)
+        .context(format!("Could not replace [{:?}]. Stop any running Global Graph sync agent and try again.", executable_path))?;
+    Ok(())
+}
+
+/// Windows can't replace a running executable, but it can rename it.
+#[cfg(windows)]
+fn replace_file(new_path: &Path, path: &Path) -> Result<(), Error> {
+    let old_path = path.with_extension("old");
+    let _ = fs::remove_file(&old_path);
+    if path.exists() {
+        fs::rename(path, &old_path)?;
+    }
+    fs::rename(new_path, path)?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn replace_file(new_path: &Path, path: &Path) -> Result<(), Error> {
+    fs::rename(new_path, path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Rewrites the installed hook scripts and the outdated dispatchers with this version's, and records
+/// them in the install manifest. Hooks that aren't installed stay that way.
+pub fn refresh_hooks(repo: &Repository) -> Result<(), Error> {
+    let hooks_directory = crate::hooks_directory(repo);
+    let mut refreshed = vec!();
+    for entry in fs::read_dir(&hooks_directory)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if path.is_dir() && name.ends_with(".d") {
+            let hook_name = &name[..name.len() - ".d".len()];
+            let hook_path = path.join(crate::dispatcher::HOOK_SCRIPT_NAME);
+            let hook_script = crate::dispatcher::hook_script(hook_name);
+            if hook_path.is_file() && fs::read(&hook_path)? != hook_script.as_bytes() {
+                info!("Updating the Global Graph hook [{:?}].", hook_path);
+                fs::write(&hook_path, hook_script)?;
+                make_executable(&hook_path)?;
+                refreshed.push(InstalledFileKind::HookScript(hook_name.to_string()));
+            }
+        } else if path.is_file() {
+            let contents = String::from_utf8_lossy(&fs::read(&path)?).into_owned();
+            match crate::dispatcher::dispatcher_version(&contents) {
+                Some(version) if version < crate::dispatcher::DISPATCHER_VERSION => {
+                    info!("Upgrading the [{}] dispatcher hook from version [{}] to [{}].", name, version, crate::dispatcher::DISPATCHER_VERSION);
+                    fs::write(&path, crate::dispatcher::dispatcher_script(&name))?;
+                    make_executable(&path)?;
+                    refreshed.push(InstalledFileKind::Dispatcher(name.clone()));
+                }
+                _ => (),
+            }
+        }
+    }
+    crate::manifest::record_files(repo, &refreshed)
+}
@@ -0,0 +1,155 @@
+//! Edit-time early warnings. Watches the working tree of a repository, and as soon as a lockable
+//! file is first modified, asks the Global Graph whether committing that edit would conflict.
+//!
+//! Warnings are printed to the terminal, appended to `<git dir>/globalgraph/warnings.log`, and passed
+//! to the command configured in `globalgraph.notifycommand` (for example `notify-send`), if any.
+
+use git2::Repository;
+use git2::Status;
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use log::{debug, info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use shared::GitPath;
+use shared::UnintegratedChange;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+/// How long file system events are collected before a modified file is checked.
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/// Watches the working tree of the repository until the watcher fails.
+pub fn run_edit_watcher<P: AsRef<Path>>(repository_path: P) -> Result<(), Error> {
+    let repo = Repository::open(repository_path.as_ref())?;
+    let workdir = repo.workdir()
+        .ok_or(format_err!("The repository [{:?}] has no working tree to watch.", repo.path()))?
+        .canonicalize()?;
+
+    let (sender, receiver) = channel();
+    let mut watcher = notify::watcher(sender, DEBOUNCE_DELAY)
+        .context("Could not start watching the file system.")?;
+    watcher.watch(&workdir, RecursiveMode::Recursive)
+        .context(format!("Could not watch the working tree [{:?}].", workdir))?;
+
+    println!("[Global Graph]: Watching [{}] for edits to lockable files. Press Ctrl+C to stop.", workdir.to_string_lossy());
+
+    let mut edits = EditTracker::new();
+
+    loop {
+        let event = receiver.recv()?;
+        let changed_path = match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) => path,
+            DebouncedEvent::Error(e, path) => {
+                warn!("[Global Graph]: File watcher error on [{:?}]: {}", path, e);
+                continue;
+            }
+            _ => continue,
+        };
+
+        let git_path = match crate::to_git_path(&workdir, &changed_path) {
+            Some(git_path) => git_path,
+            None => continue,
+        };
+
+        match edits.is_first_edit(&repo, &git_path) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                warn!("[Global Graph]: Could not tell whether [{}] is a lockable file being edited: {}", git_path, e);
+                continue;
+            }
+        }
+
+        if let Err(e) = check_edit(&repo, &git_path) {
+            warn!("[Global Graph]: Could not check [{}] for conflicts: {}", git_path, e);
+        }
+    }
+}
+
+/// Tracks the lockable files being edited, so each edit session is checked once: from the first
+/// modification of a file until it is clean again (committed or reverted).
+#[derive(Default)]
+pub struct EditTracker {
+    /// Files that were already checked since they were last clean.
+    checked_paths: HashSet<String>,
+}
+
+impl EditTracker {
+    pub fn new() -> EditTracker {
+        EditTracker::default()
+    }
+
+    /// Whether the file is a modified lockable file that wasn't checked since it was last clean. If
+    /// so, it counts as checked from now on.
+    pub fn is_first_edit(&mut self, repo: &Repository, git_path: &GitPath) -> Result<bool, Error> {
+        // Files that are clean again will be checked on their next edit.
+        self.checked_paths.retain(|path| is_modified(repo, path).unwrap_or(false));
+
+        if self.checked_paths.contains(&**git_path) || !crate::is_lockable(repo, git_path)? || !is_modified(repo, git_path)? {
+            return Ok(false);
+        }
+        self.checked_paths.insert(git_path.to_string());
+        Ok(true)
+    }
+}
+
+/// Asks the server whether the first edit to a lockable file conflicts, and warns if it does.
+fn check_edit(repo: &Repository, git_path: &GitPath) -> Result<(), Error> {
+    info!("[Global Graph]: Checking the first edit to [{}] for conflicts.", git_path);
+
+    // Let others know about the edit as well, if working tree synchronization is enabled.
+    if crate::worktree_sync_enabled(repo)? {
+        let workdir = repo.workdir().ok_or(format_err!("The repository has no working tree."))?;
+        crate::sync_queue::request_synchronization(workdir, "watcher")?;
+    }
+
+    let conflicts = crate::query::find_unintegrated_changes(repo, crate::query::NextCommit::in_progress(repo)?, vec!(git_path.clone()))?;
+    if conflicts.is_empty() {
+        debug!("No conflicts for [{}].", git_path);
+        return Ok(());
+    }
+
+    notify_conflicts(repo, git_path, &conflicts)
+}
+
+fn notify_conflicts(repo: &Repository, git_path: &GitPath, conflicts: &[UnintegratedChange]) -> Result<(), Error> {
+    let mut message = format!("[Global Graph]: WARNING: Your edit to [{}] conflicts with work in another repository. \
+        Stop editing and integrate the other changes first:\n", git_path);
+    for conflict in conflicts {
+        for line in crate::query::describe_conflict(conflict) {
+            message.push_str(&line);
+            message.push('\n');
+        }
+    }
+
+    eprintln!("{}", message);
+
+    let log_path = repo.path().join(shared::GLOBALGRAPH_REPO_NAME).join("warnings.log");
+    std::fs::create_dir_all(log_path.parent().unwrap())?;
+    let mut log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+    writeln!(log, "{}", message)?;
+
+    if let Ok(command) = repo.config()?.get_string("globalgraph.notifycommand") {
+        let summary = format!("Global Graph: [{}] conflicts with work in another repository.", git_path);
+        if let Err(e) = Command::new(&command).arg(&summary).spawn() {
+            warn!("[Global Graph]: Could not run the notification command [{}]: {}", command, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file differs from the index or HEAD, so it's part of an edit in progress.
+fn is_modified(repo: &Repository, path: &str) -> Result<bool, Error> {
+    match repo.status_file(Path::new(path)) {
+        Ok(status) => Ok(!status.is_empty() && !status.contains(Status::IGNORED)),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
@@ -1,38 +1,114 @@
 //! The installer for the Global Graph client. Standalone as an executable.
 //!
-//! The executable files for the installed git commit hooks are included in this installer via rust's `include_bytes`.
+//! The client executable, which runs every installed git hook, is included in this installer via rust's
+//! `include_bytes`. It is a release build for the platform the installer is built on (see `client_binary!`).
 
 use failure::Error;
 use structopt::StructOpt;
+use std::collections::HashSet;
+use std::path::Path;
 use std::path::PathBuf;
+use git2::Config;
+use git2::ConfigLevel;
+use git2::Direction;
+use git2::ErrorCode;
 use git2::Repository;
 use failure::ResultExt;
 use std::fs;
 use std::io;
 use std::io::BufRead;
-use regex::Regex;
-use log::{info};
+use client::manifest::{InstallManifest, InstallationProblem, InstalledFileKind};
+use client::dispatcher::{chained_hooks_directory, dispatcher_script, dispatcher_version, hook_script, CHAINED_HOOKS_PATH_CONFIG, DISPATCHER_VERSION, HOOK_SCRIPT_NAME};
+use log::{info, warn};
 use failure::format_err;
 
-/// The options provided on the command line.
+/// Embeds a release build of the client executable. Build the client for the installer's platform
+/// first: `cargo build --release -p client`.
+#[cfg(windows)]
+macro_rules! client_binary {
+    ($name:expr) => { include_bytes!(concat!("../../../target/release/", $name, ".exe")) }
+}
+
+#[cfg(not(windows))]
+macro_rules! client_binary {
+    ($name:expr) => { include_bytes!(concat!("../../../target/release/", $name)) }
+}
+
+/// The command line: a command, or none to install into the current directory's repository.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "globalgraph-configure")]
-struct Opt {
-    /// The URL of the Global Graph Git repo.
+struct Arguments {
+    #[structopt(subcommand)]
+    command: Option<Opt>,
+}
+
+/// The commands provided on the command line.
+#[derive(StructOpt, Debug)]
+enum Opt {
+    /// Installs the Global Graph client into a git repository.
+    #[structopt(name = "install")]
+    Install(InstallOpt),
+
+    /// Installs the Global Graph client into every clone of a repository found under a directory,
+    /// or listed in a file.
+    #[structopt(name = "bulk-install")]
+    BulkInstall(BulkInstallOpt),
+
+    /// Installs the Global Graph hooks into a git template directory, so every repository cloned
+    /// or initialized on this computer gets them. The first time a hook runs in a repository, it
+    /// configures the repository if one of the query servers serves the project it was cloned from.
+    #[structopt(name = "install-template")]
+    InstallTemplate(InstallTemplateOpt),
+
+    /// Removes the Global Graph client from a git repository.
+    #[structopt(name = "uninstall")]
+    Uninstall(UninstallOpt),
+
+    /// Checks that the files and config the installer set up in a git repository are unchanged.
+    #[structopt(name = "verify")]
+    Verify(RepositoryOpt),
+
+    /// Restores the files and config the installer set up in a git repository that were removed,
+    /// modified or replaced by older versions since.
+    #[structopt(name = "repair")]
+    Repair(RepositoryOpt),
+}
+
+/// How each repository is configured, shared by the install commands.
+#[derive(StructOpt, Debug, Default)]
+struct InstallSettings {
+    /// The URL of the Global Graph Git repo. Defaults to `remote` in the repository's
+    /// `.globalgraph.toml`, which the repository then keeps following.
     ///
     /// example: https://server.com/globalgraph.git
     ///
     /// example: git@server.com:repository/respository.git
     #[structopt(long = "global_graph_repo")]
-    global_graph_url: String,
+    global_graph_url: Option<String>,
 
-    /// The URL of the Global Graph query server.
+    /// The URL of the Global Graph query server. Defaults to `server` in the repository's
+    /// `.globalgraph.toml`, which the repository then keeps following.
     ///
     /// example: https://server.com:12345
     ///
     /// example: https://192.168.1.30:12345
     #[structopt(long = "query_server_url")]
-    server_url: String,
+    server_url: Option<String>,
+
+    /// Whether or not to enable conflicts detection in this repository.
+    /// Conflicts detection installs an additional set of hooks that will check all
+    /// commits made locally for conflicts in the global graph.
+    /// Without it, conflicts detection is removed if it was installed before.
+    /// For more information, see http://todo.com
+    #[structopt(long = "conflicts_detection")]
+    conflicts_detection: bool,
+}
+
+/// The options of the install command.
+#[derive(StructOpt, Debug)]
+struct InstallOpt {
+    #[structopt(flatten)]
+    settings: InstallSettings,
 
     /// The path to the git directory you would like to configure. This git repository will be
     /// configured to synchronize its history to the global_graph_repo specified alongside this command.
@@ -46,126 +122,1019 @@ struct Opt {
     #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
     git_directory: PathBuf,
 
-    /// Whether or not to enable conflicts detection in this repository.
-    /// Conflicts detection installs an additional set of hooks that will check all
-    /// commits made locally for conflicts in the global graph.
-    /// For more information, see http://todo.com
+    /// Reconfigure a repository Global Graph is already installed in without asking.
+    #[structopt(long = "yes", short = "y")]
+    yes: bool,
+}
+
+impl Default for InstallOpt {
+    /// Installs into the current directory's repository, with the urls of its `.globalgraph.toml`.
+    fn default() -> InstallOpt {
+        InstallOpt {
+            settings: InstallSettings::default(),
+            git_directory: PathBuf::from("./"),
+            yes: false,
+        }
+    }
+}
+
+/// The options of the bulk-install command.
+#[derive(StructOpt, Debug)]
+struct BulkInstallOpt {
+    #[structopt(flatten)]
+    settings: InstallSettings,
+
+    /// The `origin` URL of the project. Only clones whose origin remote points at it are installed.
+    ///
+    /// example: git@server.com:team/project.git
+    #[structopt(long = "origin")]
+    origin_url: String,
+
+    /// A directory to search for clones of the project. Every non-bare repository under it is
+    /// considered, including the directory itself.
+    ///
+    /// example: /home/me/projects
+    #[structopt(long = "search_path", parse(from_os_str), required_unless = "repository_list")]
+    search_directory: Option<PathBuf>,
+
+    /// A file listing the repositories to install into, one path per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    #[structopt(long = "repository_list", parse(from_os_str))]
+    repository_list: Option<PathBuf>,
+
+    /// Install without asking for confirmation first.
+    #[structopt(long = "yes", short = "y")]
+    yes: bool,
+}
+
+/// The options of the install-template command.
+#[derive(StructOpt, Debug)]
+struct InstallTemplateOpt {
+    /// The URL of a query server the projects of new clones are looked up on. Repeat it to look
+    /// them up on several servers. Added to the servers already known (see `globalgraph.knownservers`).
+    ///
+    /// example: https://server.com:12345
+    #[structopt(long = "query_server_url", raw(required = "true"))]
+    server_urls: Vec<String>,
+
+    /// The git template directory to install into. Defaults to the directory `init.templateDir`
+    /// already points at, or `~/.globalgraph/template` if it is unset. `init.templateDir` is set to
+    /// it in the global git config.
+    #[structopt(long = "template_dir", parse(from_os_str))]
+    template_directory: Option<PathBuf>,
+
+    /// Also install conflicts detection (the pre-commit and pre-push hooks) into the template.
+    /// Without it, conflicts detection is removed from the template if it was installed before.
     #[structopt(long = "conflicts_detection")]
     conflicts_detection: bool,
 }
 
-/// Entry point. Installs the GlobalGraph client to the computer.
+/// The options of the uninstall command.
+#[derive(StructOpt, Debug)]
+struct UninstallOpt {
+    /// The path to the git directory to remove the Global Graph client from.
+    ///
+    /// example: ./
+    #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+    git_directory: PathBuf,
+
+    /// Only remove conflicts detection (the pre-commit and pre-push hooks). The repository keeps
+    /// synchronizing with the global graph.
+    #[structopt(long = "conflicts_detection_only", raw(conflicts_with = r#""delete_from_server""#))]
+    conflicts_detection_only: bool,
+
+    /// Also ask the query server to delete the branches and snapshots this repository pushed to the
+    /// global graph.
+    #[structopt(long = "delete_from_server")]
+    delete_from_server: bool,
+}
+
+/// The options of the verify and repair commands.
+#[derive(StructOpt, Debug)]
+struct RepositoryOpt {
+    /// The path to the git directory Global Graph is installed in.
+    ///
+    /// example: ./
+    #[structopt(long = "git_path", parse(from_os_str), default_value = "./")]
+    git_directory: PathBuf,
+}
+
+/// The hooks that check for conflicts before they are committed or pushed.
+const CONFLICTS_DETECTION_HOOKS: &[&str] = &["pre-commit", "pre-push"];
+
+/// The hooks git runs in a client repository. When `core.hooksPath` is chained, each of these the
+/// chained directory has gets a dispatcher.
+const CLIENT_HOOKS: &[&str] = &["applypatch-msg", "pre-applypatch", "post-applypatch", "pre-commit", "pre-merge-commit",
+    "prepare-commit-msg", "commit-msg", "post-commit", "pre-rebase", "post-checkout", "post-merge", "pre-push", "pre-auto-gc",
+    "post-rewrite", "reference-transaction", "push-to-checkout", "post-index-change", "sendemail-validate", "fsmonitor-watchman"];
+
+/// Every hook the installer may have installed, in this or an earlier version.
+const GLOBAL_GRAPH_HOOKS: &[&str] = &["pre-commit", "pre-push", "post-commit", "post-merge", "post-rewrite", "reference-transaction"];
+
+/// Entry point. Installs the GlobalGraph client to the computer, or removes it.
 fn main() -> Result<(), Error> {
     let env = env_logger::Env::default()
         .filter_or(env_logger::DEFAULT_FILTER_ENV, "info");
 
     env_logger::Builder::from_env(env).init();
 
-    let args = Opt::from_args();
+    match Arguments::from_args().command.unwrap_or_else(|| Opt::Install(InstallOpt::default())) {
+        Opt::Install(args) => install(args),
+        Opt::BulkInstall(args) => bulk_install(args),
+        Opt::InstallTemplate(args) => install_template(args),
+        Opt::Uninstall(args) => uninstall(args),
+        Opt::Verify(args) => verify(args),
+        Opt::Repair(args) => repair(args),
+    }
+}
 
-    let repo = Repository::open(&args.git_directory)
-        .context(format!("The argument git_path [{}] was not a valid git repository.", args.git_directory.to_string_lossy()))?;
+/// Opens the repository at the given path, which must be a client repository.
+fn open_client_repository(git_directory: &PathBuf) -> Result<Repository, Error> {
+    let repo = Repository::open(git_directory)
+        .context(format!("The argument git_path [{}] was not a valid git repository.", git_directory.to_string_lossy()))?;
 
     if repo.is_bare() {
         return Err(format_err!("The git repository [{}] is a bare repository, and can't act as a client in the Global Graph.
-        If you are trying to start the server component of the Global Graph, this is the wrong executable.", args.git_directory.to_string_lossy()));
+        If you are trying to start the server component of the Global Graph, this is the wrong executable.", git_directory.to_string_lossy()));
     }
 
-    match repo.config()?.get_string("globalGraph.installed") {
-        Ok(_) => {
-            println!("WARNING: Global Graph is already installed in this git repository. This will reconfigure the settings for this repository. Continue? (y|n)");
-            if !get_yes_or_no()? {
-                println!("Exiting.");
-                return Ok(());
-            }
+    Ok(repo)
+}
+
+fn install(args: InstallOpt) -> Result<(), Error> {
+    let repo = open_client_repository(&args.git_directory)?;
+
+    if is_installed(&repo)? && !args.yes {
+        println!("WARNING: Global Graph is already installed in this git repository. This will reconfigure the settings for this repository. Continue? (y|n)");
+        if !get_yes_or_no()? {
+            println!("Exiting.");
+            return Ok(());
         }
-        Err(_) => {}
     }
 
-    install_hook(&repo, "post-commit", include_bytes!("../../../target/debug/post-commit.exe"))?;
+    install_repository(&repo, &args.settings)?;
 
-    if args.conflicts_detection {
+    println!("Installation finished successfully with the following configuration: {:#?}", args);
+    Ok(())
+}
+
+/// Whether the installer has successfully installed Global Graph in this repository before.
+fn is_installed(repo: &Repository) -> Result<bool, Error> {
+    Ok(repo.config()?.get_string("globalGraph.installed").is_ok())
+}
+
+/// Configures the repository, installs the client executable and the hooks, and verifies the
+/// installation.
+fn install_repository(repo: &Repository, settings: &InstallSettings) -> Result<(), Error> {
+    configure_repository(repo, settings)?;
+    let chained_hooks = chain_hooks_path(repo)?;
+
+    install_client_executable(repo, client_binary!("globalgraph"))?;
+    let mut hook_names = vec!("post-commit", "post-merge", "post-rewrite", "reference-transaction");
+
+    if settings.conflicts_detection {
         // Add conflicts detection.
-        install_hook(&repo, "pre-commit", include_bytes!("../../../target/debug/pre-commit.exe"))?;
+        hook_names.extend(CONFLICTS_DETECTION_HOOKS);
     } else {
-        // TODO(john): Remove conflicts detection.
+        for hook_name in CONFLICTS_DETECTION_HOOKS {
+            uninstall_hook(repo, hook_name)?;
+        }
+    }
+
+    for hook_name in &hook_names {
+        install_hook(&client::hooks_directory(repo), hook_name)?;
+    }
+
+    // The hooks only the chained directory has need a dispatcher too, or git stops running them.
+    for hook_name in &chained_hooks {
+        install_dispatcher(&client::hooks_directory(repo), hook_name)?;
     }
 
     info!("All hooks updated.");
 
-    info!("Verifying global graph repository URL: [{}]", args.global_graph_url);
-    // TODO(john): Implement
+    verify_installation(repo)?;
 
     repo.config()
         .context("Error when accessing the configuration store for this Git repo. Could not mark repository as 'installed'.")?
         .set_str("globalGraph.installed", "installed")
         .context("Could not mark repository as 'installed'.")?;
 
-    println!("Installation finished successfully with the following configuration: {:#?}", args);
+    let mut manifest = InstallManifest::new();
+    manifest.add_file(repo, InstalledFileKind::ClientExecutable)?;
+    for hook_name in &hook_names {
+        manifest.add_file(repo, InstalledFileKind::Dispatcher(hook_name.to_string()))?;
+        manifest.add_file(repo, InstalledFileKind::HookScript(hook_name.to_string()))?;
+    }
+    for hook_name in &chained_hooks {
+        manifest.add_file(repo, InstalledFileKind::Dispatcher(hook_name.clone()))?;
+    }
+    for name in installed_config_names(repo)? {
+        manifest.add_config(repo, &name)?;
+    }
+    client::manifest::write_manifest(repo, &manifest)?;
+    info!("Wrote the install manifest to [{:?}].", client::manifest::manifest_path(repo));
+    Ok(())
+}
+
+/// The git config values the installer sets, which the install manifest records.
+fn installed_config_names(repo: &Repository) -> Result<Vec<String>, Error> {
+    let mut names = vec!(
+        format!("remote.{}.url", shared::GLOBALGRAPH_REPO_NAME),
+        "globalgraph.server".to_string(),
+        "globalgraph.repouuid".to_string(),
+        "globalGraph.installed".to_string(),
+    );
+    // core.hooksPath is only the installer's when it chained the hooks it pointed at.
+    if repo.config()?.get_string(CHAINED_HOOKS_PATH_CONFIG).is_ok() {
+        names.push(CHAINED_HOOKS_PATH_CONFIG.to_string());
+        names.push("core.hooksPath".to_string());
+    }
+    Ok(names)
+}
+
+/// Installs the client executable and the hooks into the template directory, and points the global
+/// `init.templateDir` at it. Repositories configure themselves when a hook first runs in them (see
+/// `client::template`), so nothing repository specific is written to the template.
+fn install_template(args: InstallTemplateOpt) -> Result<(), Error> {
+    let mut global_config = open_global_config()?;
+    let current_template_directory = global_config.get_path("init.templateDir").ok();
+    let template_directory = match (&args.template_directory, &current_template_directory) {
+        (Some(template_directory), _) => template_directory.clone(),
+        (None, Some(current_template_directory)) => current_template_directory.clone(),
+        (None, None) => home_directory()?.join(".globalgraph").join("template"),
+    };
+    let hooks_directory = template_directory.join("hooks");
+    fs::create_dir_all(&hooks_directory)
+        .context(format!("Could not create the template directory [{:?}].", template_directory))?;
+
+    let executable_path = hooks_directory.join(format!("{}{}", client::CLIENT_EXECUTABLE_NAME, std::env::consts::EXE_SUFFIX));
+    info!("Writing the Global Graph client executable to [{:?}].", executable_path);
+    fs::write(&executable_path, &client_binary!("globalgraph")[..])
+        .context(format!("Could not write the client executable to [{:?}].", executable_path))?;
+    make_executable(&executable_path)?;
+
+    let mut hook_names = vec!("post-commit", "post-merge", "post-rewrite", "reference-transaction");
+    if args.conflicts_detection {
+        hook_names.extend(CONFLICTS_DETECTION_HOOKS);
+    } else {
+        for hook_name in CONFLICTS_DETECTION_HOOKS {
+            remove_hook(&hooks_directory, hook_name, false)?;
+        }
+    }
+    for hook_name in &hook_names {
+        install_hook(&hooks_directory, hook_name)?;
+    }
+
+    let template_directory_string = template_directory.to_string_lossy().into_owned();
+    if let Some(ref current_template_directory) = current_template_directory {
+        if *current_template_directory != template_directory {
+            warn!("init.templateDir pointed at [{:?}], new repositories no longer get the files in it.", current_template_directory);
+        }
+    }
+    global_config.set_str("init.templateDir", &template_directory_string)
+        .context("Could not set init.templateDir in the global git config.")?;
+
+    let mut known_servers = match global_config.get_string(client::template::KNOWN_SERVERS_CONFIG) {
+        Ok(servers) => servers.split_whitespace().map(|server| server.to_string()).collect(),
+        Err(_) => vec!(),
+    };
+    for server_url in &args.server_urls {
+        if !known_servers.contains(server_url) {
+            known_servers.push(server_url.clone());
+        }
+    }
+    global_config.set_str(client::template::KNOWN_SERVERS_CONFIG, &known_servers.join(" "))
+        .context(format!("Could not set {} in the global git config.", client::template::KNOWN_SERVERS_CONFIG))?;
+
+    println!("Installed the Global Graph hooks into the template directory [{}]. Repositories cloned from now on are \
+              configured when a hook first runs in them, if one of these query servers serves their origin: {:?}",
+             template_directory_string, known_servers);
+    println!("Existing clones are not affected, use `installer bulk-install` for them.");
+    Ok(())
+}
+
+/// Opens the global git config, `~/.gitconfig`, creating it if there is none.
+fn open_global_config() -> Result<Config, Error> {
+    let path = match Config::find_global() {
+        Ok(path) => path,
+        Err(_) => home_directory()?.join(".gitconfig"),
+    };
+    Ok(Config::open(&path).context(format!("Could not open the global git config [{:?}].", path))?)
+}
+
+/// The home directory of the current user.
+fn home_directory() -> Result<PathBuf, Error> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .ok_or(format_err!("Could not find the home directory: neither HOME nor USERPROFILE is set."))
+}
+
+fn verify(args: RepositoryOpt) -> Result<(), Error> {
+    let repo = open_client_repository(&args.git_directory)?;
+    let manifest = read_install_manifest(&repo)?;
+
+    let problems = client::manifest::find_problems(&repo, &manifest)?;
+    if problems.is_empty() {
+        println!("The Global Graph installation in [{}] is intact: [{}] files and [{}] config values are unchanged.",
+                 args.git_directory.to_string_lossy(), manifest.files.len(), manifest.config.len());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(format_err!("[{}] problems found with the Global Graph installation. Run `installer repair` to restore it.", problems.len()))
+}
+
+/// Reinstalls the files that changed since they were installed, and sets the changed config values
+/// back. Files are restored with this installer's version, which the client then updates (see
+/// `client::update`) if the query server hosts another.
+fn repair(args: RepositoryOpt) -> Result<(), Error> {
+    let repo = open_client_repository(&args.git_directory)?;
+    let mut manifest = read_install_manifest(&repo)?;
+
+    let problems = client::manifest::find_problems(&repo, &manifest)?;
+    if problems.is_empty() {
+        println!("The Global Graph installation in [{}] is intact, nothing to repair.", args.git_directory.to_string_lossy());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        info!("Repairing: {}", problem);
+        match *problem {
+            InstallationProblem::MissingFile(ref file)
+            | InstallationProblem::ModifiedFile(ref file)
+            | InstallationProblem::StaleClient { ref file, .. }
+            | InstallationProblem::StaleDispatcher { ref file, .. } => {
+                match file.kind {
+                    InstalledFileKind::ClientExecutable => install_client_executable(&repo, client_binary!("globalgraph"))?,
+                    InstalledFileKind::HookScript(ref hook_name) => install_hook(&client::hooks_directory(&repo), hook_name)?,
+                    InstalledFileKind::Dispatcher(ref hook_name) => install_dispatcher(&client::hooks_directory(&repo), hook_name)?,
+                }
+            }
+            InstallationProblem::ChangedConfig { ref name, ref expected, .. } => {
+                if *name == format!("remote.{}.url", shared::GLOBALGRAPH_REPO_NAME) && repo.find_remote(shared::GLOBALGRAPH_REPO_NAME).is_err() {
+                    repo.remote(shared::GLOBALGRAPH_REPO_NAME, expected)?;
+                } else {
+                    repo.config()?.set_str(name, expected)?;
+                }
+            }
+        }
+    }
+
+    // Reinstalling a hook rewrites its dispatcher too, and the client executable may be another version.
+    for kind in manifest.files.iter().map(|file| file.kind.clone()).collect::<Vec<_>>() {
+        manifest.add_file(&repo, kind)?;
+    }
+    manifest.dispatcher_version = DISPATCHER_VERSION;
+    client::manifest::write_manifest(&repo, &manifest)?;
+
+    println!("Repaired [{}] problems with the Global Graph installation in [{}].", problems.len(), args.git_directory.to_string_lossy());
+    Ok(())
+}
+
+/// Removes uninstalled hooks from the install manifest, so they aren't repaired. A dispatcher kept
+/// for a chained hook stays recorded.
+fn forget_hooks(repo: &Repository, hook_names: &[&str]) -> Result<(), Error> {
+    let mut manifest = match client::manifest::read_manifest(repo)? {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+
+    for hook_name in hook_names {
+        manifest.forget_hook(hook_name);
+        let dispatcher = InstalledFileKind::Dispatcher(hook_name.to_string());
+        let is_dispatcher = match fs::read_to_string(dispatcher.path(repo)) {
+            Ok(hook_contents) => is_hook_dispatcher(&hook_contents),
+            Err(_) => false,
+        };
+        if is_dispatcher {
+            manifest.add_file(repo, dispatcher)?;
+        }
+    }
+    client::manifest::write_manifest(repo, &manifest)
+}
+
+/// Reads the install manifest, which repositories installed by older installers don't have.
+fn read_install_manifest(repo: &Repository) -> Result<InstallManifest, Error> {
+    client::manifest::read_manifest(repo)?
+        .ok_or(format_err!("Global Graph was installed in this repository without an install manifest, or not at all. Re-run `installer install` to write one."))
+}
+
+/// What happened to one repository during a bulk install.
+enum BulkInstallResult {
+    Installed,
+    Skipped(String),
+    Failed(Error),
+}
+
+fn bulk_install(args: BulkInstallOpt) -> Result<(), Error> {
+    let mut candidates = vec!();
+    if let Some(ref search_directory) = args.search_directory {
+        info!("Searching [{:?}] for git repositories.", search_directory);
+        find_repositories(search_directory, &mut candidates);
+    }
+    if let Some(ref repository_list) = args.repository_list {
+        candidates.extend(read_repository_list(repository_list)?);
+    }
+
+    // The same clone may be both found and listed, under different paths.
+    let mut seen = HashSet::new();
+    candidates.retain(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone())));
+
+    // Decide which repositories to install into before changing any of them, so they can be confirmed at once.
+    let mut results = vec!();
+    let mut repositories = vec!();
+    for path in candidates {
+        match find_clone(&path, &args.origin_url) {
+            Ok(repo) => repositories.push((path, repo)),
+            Err(reason) => results.push((path, BulkInstallResult::Skipped(reason))),
+        }
+    }
+
+    if repositories.is_empty() {
+        print_bulk_install_summary(&results);
+        return Err(format_err!("No clone of [{}] was found.", args.origin_url));
+    }
+
+    if !args.yes {
+        println!("Global Graph will be installed in the following repositories:");
+        for &(ref path, ref repo) in &repositories {
+            let note = if is_installed(repo)? { " (already installed, will be reconfigured)" } else { "" };
+            println!("    {}{}", path.to_string_lossy(), note);
+        }
+        println!("Continue? (y|n)");
+        if !get_yes_or_no()? {
+            println!("Exiting.");
+            return Ok(());
+        }
+    }
+
+    for (path, repo) in repositories {
+        info!("Installing Global Graph in [{:?}].", path);
+        let result = match install_repository(&repo, &args.settings) {
+            Ok(()) => BulkInstallResult::Installed,
+            Err(e) => BulkInstallResult::Failed(e),
+        };
+        results.push((path, result));
+    }
+
+    print_bulk_install_summary(&results);
+
+    let failed = results.iter().filter(|&&(_, ref result)| match *result {
+        BulkInstallResult::Failed(_) => true,
+        _ => false,
+    }).count();
+    if failed > 0 {
+        return Err(format_err!("Global Graph could not be installed in [{}] of the repositories.", failed));
+    }
+    Ok(())
+}
+
+/// Collects every non-bare repository under a directory. The search doesn't descend into
+/// repositories, nor follow symbolic links. Directories that can't be read are skipped.
+fn find_repositories(directory: &Path, repositories: &mut Vec<PathBuf>) {
+    // `.git` is a directory in a regular clone, and a file in linked worktrees and submodules.
+    if directory.join(".git").exists() {
+        repositories.push(directory.to_path_buf());
+        return;
+    }
+
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Skipping [{:?}], which could not be read: {}", directory, e);
+            return;
+        }
+    };
+
+    let mut subdirectories = vec!();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => subdirectories.push(entry.path()),
+            _ => {}
+        }
+    }
+    subdirectories.sort();
+
+    for subdirectory in subdirectories {
+        find_repositories(&subdirectory, repositories);
+    }
+}
+
+/// Reads the repositories listed in a file, one path per line.
+fn read_repository_list(list_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let contents = fs::read_to_string(list_path)
+        .context(format!("Could not read the repository list [{:?}].", list_path))?;
+
+    Ok(contents.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Opens the repository at the given path if it is a client repository cloned from the given
+/// origin, or returns why it is skipped.
+fn find_clone(path: &Path, origin_url: &str) -> Result<Repository, String> {
+    let repo = Repository::open(path)
+        .map_err(|e| format!("Not a git repository: {}", e.message()))?;
+    if repo.is_bare() {
+        return Err("Bare repository".to_string());
+    }
+
+    let url = match repo.find_remote("origin") {
+        Ok(remote) => remote.url().map(|url| url.to_string()),
+        Err(_) => return Err("No origin remote".to_string()),
+    };
+    match url {
+        Some(ref url) if shared::same_remote_url(url, origin_url) => {}
+        Some(url) => return Err(format!("Origin is [{}]", url)),
+        None => return Err("The origin url is not valid UTF8".to_string()),
+    }
+
+    Ok(repo)
+}
+
+fn print_bulk_install_summary(results: &[(PathBuf, BulkInstallResult)]) {
+    let rows: Vec<(&str, String, String)> = results.iter()
+        .map(|&(ref path, ref result)| {
+            let (status, details) = match *result {
+                BulkInstallResult::Installed => ("installed", String::new()),
+                BulkInstallResult::Skipped(ref reason) => ("skipped", reason.clone()),
+                BulkInstallResult::Failed(ref e) => ("failed", client::error_chain(e)),
+            };
+            (status, path.to_string_lossy().into_owned(), details)
+        })
+        .collect();
+
+    let path_width = rows.iter().map(|&(_, ref path, _)| path.len()).chain(Some("REPOSITORY".len())).max().unwrap_or(0);
+
+    println!();
+    println!("{:<9}  {:<width$}  {}", "STATUS", "REPOSITORY", "DETAILS", width = path_width);
+    for &(status, ref path, ref details) in &rows {
+        println!("{}", format!("{:<9}  {:<width$}  {}", status, path, details, width = path_width).trim_end());
+    }
+
+    let count = |wanted: &str| rows.iter().filter(|&&(status, _, _)| status == wanted).count();
+    println!();
+    println!("[{}] installed, [{}] skipped, [{}] failed.", count("installed"), count("skipped"), count("failed"));
+}
+
+/// Points the repository at the global graph: the `globalgraph` remote, the query server, and the
+/// repository's uuid in the global graph. The urls that aren't given are left to the team
+/// configuration (see `client::team_config`), unless the local git config overrides them already.
+fn configure_repository(repo: &Repository, args: &InstallSettings) -> Result<(), Error> {
+    match args.global_graph_url {
+        Some(ref global_graph_url) if repo.find_remote(shared::GLOBALGRAPH_REPO_NAME).is_ok() => {
+            info!("Updating the [{}] remote to [{}].", shared::GLOBALGRAPH_REPO_NAME, global_graph_url);
+            repo.remote_set_url(shared::GLOBALGRAPH_REPO_NAME, global_graph_url)?;
+        }
+        Some(ref global_graph_url) => {
+            info!("Adding the [{}] remote at [{}].", shared::GLOBALGRAPH_REPO_NAME, global_graph_url);
+            repo.remote(shared::GLOBALGRAPH_REPO_NAME, global_graph_url)
+                .context(format!("[{}] is not a valid git remote url.", global_graph_url))?;
+        }
+        None => {
+            let global_graph_url = client::team_config::global_graph_url(repo)?
+                .ok_or(format_err!("No global graph repository is configured. Pass --global_graph_repo, or set `remote` in [{}].", client::team_config::TEAM_CONFIG_FILE))?;
+            info!("Using the global graph repository [{}].", global_graph_url);
+        }
+    }
+
+    match args.server_url {
+        Some(ref server_url) => repo.config()?.set_str("globalgraph.server", server_url)?,
+        None => {
+            let server_url = client::team_config::query_server(repo)?
+                .ok_or(format_err!("No query server is configured. Pass --query_server_url, or set `server` in [{}].", client::team_config::TEAM_CONFIG_FILE))?;
+            info!("Using the query server [{}].", server_url);
+        }
+    }
+    client::query::query_server_url(repo)?;
+
+    let uuid = client::get_or_create_client_uuid(repo)?;
+    info!("This repository is [{}] in the global graph.", uuid);
+    Ok(())
+}
+
+/// Checks the installation end to end: a test push synchronizes the repository with the global
+/// graph, and a test query asks the query server about its head.
+fn verify_installation(repo: &Repository) -> Result<(), Error> {
+    let workdir = repo.workdir().ok_or(format_err!("The repository has no working directory."))?;
+
+    // The push below does nothing when every branch is already synchronized, so connect explicitly.
+    let mut remote = client::team_config::global_graph_remote(repo)?;
+    let global_graph_url = remote.url().unwrap_or_default().to_string();
+    info!("Verifying global graph repository URL: [{}]", global_graph_url);
+    remote.connect(Direction::Push)
+        .context(format!("Could not connect to the global graph [{}] for pushing. Check --global_graph_repo and your credentials, then re-run the installer.", global_graph_url))?;
+    remote.disconnect();
+
+    client::synchronize_local_repository(workdir)
+        .context(format!("The test push to the global graph [{}] failed. Check --global_graph_repo and your credentials, then re-run the installer.", global_graph_url))?;
+
+    let server_url = client::query::query_server_url(repo)?;
+    info!("Verifying query server URL: [{}]", server_url);
+    let head = client::query::head_commit(repo)?;
+    client::query::find_unintegrated_changes(repo, client::query::NextCommit::on(head), vec!())
+        .context(format!("The test query to the query server [{}] failed. Check --query_server_url, then re-run the installer.", server_url))?;
+    // Before any other clone could claim the repository's namespace, so only this one can delete it.
+    client::query::claim_namespace(repo)
+        .context(format!("Could not claim this repository's namespace on the query server [{}].", server_url))?;
+
+    Ok(())
+}
+
+fn uninstall(args: UninstallOpt) -> Result<(), Error> {
+    let repo = open_client_repository(&args.git_directory)?;
+
+    if args.conflicts_detection_only {
+        for hook_name in CONFLICTS_DETECTION_HOOKS {
+            uninstall_hook(&repo, hook_name)?;
+        }
+        forget_hooks(&repo, CONFLICTS_DETECTION_HOOKS)?;
+        println!("Conflicts detection was removed. This repository still synchronizes with the global graph.");
+        return Ok(());
+    }
+
+    // A running agent keeps its executable open, and would recreate the sync queue.
+    let git_directories = worktree_git_directories(&repo)?;
+    for git_directory in &git_directories {
+        if client::sync_queue::SyncQueue::open_in(git_directory)?.status()?.agent_running {
+            return Err(format_err!("The Global Graph sync agent is still running in [{:?}]. \
+            Wait for it to finish (see `globalgraph sync status`) and try again.", git_directory));
+        }
+    }
+
+    if args.delete_from_server {
+        let deleted = client::query::delete_namespace(&repo)
+            .context("Could not delete this repository from the global graph. Uninstall without --delete_from_server to only remove it locally.")?;
+        info!("Deleted [{}] references from the global graph.", deleted);
+    }
+
+    unchain_hooks_path(&repo)?;
+
+    let mut hook_names: Vec<String> = GLOBAL_GRAPH_HOOKS.iter().map(|hook_name| hook_name.to_string()).collect();
+    hook_names.extend(find_dispatchers(&repo)?);
+    hook_names.sort();
+    hook_names.dedup();
+    for hook_name in &hook_names {
+        uninstall_hook(&repo, hook_name)?;
+    }
+
+    // On Windows, an update leaves the replaced executable behind as `globalgraph.old`.
+    let executable_path = client::client_executable_path(&repo);
+    for executable_path in &[executable_path.with_extension("old"), executable_path, legacy_sync_agent_path(&repo)] {
+        if executable_path.exists() {
+            info!("Removing the Global Graph client executable [{:?}].", executable_path);
+            fs::remove_file(executable_path)?;
+        }
+    }
+
+    for git_directory in &git_directories {
+        let state_directory = git_directory.join(shared::GLOBALGRAPH_REPO_NAME);
+        if state_directory.exists() {
+            info!("Removing the Global Graph state directory [{:?}].", state_directory);
+            fs::remove_dir_all(&state_directory)?;
+        }
+    }
+
+    remove_references(&repo)?;
+    if repo.find_remote(shared::GLOBALGRAPH_REPO_NAME).is_ok() {
+        info!("Removing the [{}] remote.", shared::GLOBALGRAPH_REPO_NAME);
+        repo.remote_delete(shared::GLOBALGRAPH_REPO_NAME)?;
+    }
+    remove_configuration(&repo)?;
+
+    println!("Global Graph was removed from [{}].", args.git_directory.to_string_lossy());
+    Ok(())
+}
+
+/// Removes the local references Global Graph keeps: the snapshots of uncommitted work, and the
+/// tracking references recording what was pushed to the global graph.
+fn remove_references(repo: &Repository) -> Result<(), Error> {
+    let tracking_namespace = format!("refs/remotes/{}/", shared::GLOBALGRAPH_REPO_NAME);
+
+    let mut names = vec!();
+    for reference in repo.references()? {
+        if let Some(name) = reference?.name() {
+            if name.starts_with(&tracking_namespace)
+                || name.starts_with(shared::INDEX_REFS_NAMESPACE)
+                || name.starts_with(shared::WORKTREE_REFS_NAMESPACE) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    for name in names {
+        info!("Removing reference [{}].", name);
+        repo.find_reference(&name)?.delete()?;
+    }
+    Ok(())
+}
+
+/// Removes every `globalgraph.*` value from the repository's local git config.
+fn remove_configuration(repo: &Repository) -> Result<(), Error> {
+    let mut config = repo.config()?.open_level(ConfigLevel::Local)
+        .context("Could not open the local git config of this repository.")?;
+
+    let mut names = vec!();
+    for entry in &config.entries(Some("^globalgraph\\."))? {
+        if let Some(name) = entry?.name() {
+            names.push(name.to_string());
+        }
+    }
+    names.dedup();
+
+    for name in names {
+        info!("Removing config value [{}].", name);
+        config.remove_multivar(&name, ".*")?;
+    }
     Ok(())
 }
 
-fn install_hook(repo: &Repository, hook_name: &str, hook_bytes: &[u8]) -> Result<(), Error> {
+/// Installs the Global Graph hook, a script that runs the client executable (see
+/// `client::dispatcher::hook_script`), and the dispatcher that runs it.
+fn install_hook(hooks_directory: &Path, hook_name: &str) -> Result<(), Error> {
     info!("Installing hook [{}]", hook_name);
 
     // Add hooks under hookname.d/
-    let hookd_directory_name = format!("{}.d/", hook_name);
-    let hookd_directory = repo.path().join("hooks").join(&hookd_directory_name);
+    let hookd_directory = hooks_directory.join(format!("{}.d", hook_name));
 
     info!("Making sure directory [{:?}] exists.", hookd_directory);
     fs::create_dir_all(&hookd_directory)?;
 
+    install_dispatcher(hooks_directory, hook_name)?;
+
+    // Write the global graph hook to the directory. It replaces the hook binary older installers wrote.
+    let globalgraph_hook_path = hookd_directory.join(HOOK_SCRIPT_NAME);
+
+    info!("Writing Global Graph hook to [{:?}].", globalgraph_hook_path);
+    fs::write(&globalgraph_hook_path, hook_script(hook_name))?;
+    make_executable(&globalgraph_hook_path)?;
+
+    Ok(())
+}
+
+/// Makes the hook a dispatcher (see `client::dispatcher`). A hook that isn't a dispatcher yet is
+/// moved to `hooks/<hook_name>.d/`, where the dispatcher runs it.
+fn install_dispatcher(hooks_directory: &Path, hook_name: &str) -> Result<(), Error> {
+    let hookd_directory_name = format!("{}.d/", hook_name);
+    let hookd_directory = hooks_directory.join(&hookd_directory_name);
+    fs::create_dir_all(hooks_directory)?;
+
     // TODO(john): Support moving git hooks named 'hook.exe'
-    let hook_path = repo.path().join("hooks").join(hook_name);
+    let hook_path = hooks_directory.join(hook_name);
 
     // Move old hook to hookname.d/
     if hook_path.exists() {
-        // If the existing main hook is the global graph dispatcher hook, then we don't want to copy it.
-        let hook_contents_result = fs::read_to_string(&hook_path);
-        let move_old_hook = if let Ok(hook_contents) = hook_contents_result {
-            // If the hook is already the dispatcher hook, so just update the global graph hook in hook.d/
-            !is_hook_dispatcher(&hook_contents)?
-        } else {
-            false
-        };
-
-        if move_old_hook {
-            let new_hook_path = hookd_directory.join(hook_name);
-            info!("Moving old hook from [{:?}] to [{:?}].", hook_path, new_hook_path);
-            if new_hook_path.exists() {
-                return Err(format_err!("Tried to move existing [{}] hook to path [{}], but a file already existed.", hook_name, new_hook_path.to_string_lossy()));
+        // Hooks can be binaries, which are never dispatchers.
+        let hook_contents = String::from_utf8_lossy(&fs::read(&hook_path)?).into_owned();
+        match dispatcher_version(&hook_contents) {
+            None => {
+                let new_hook_path = hookd_directory.join(hook_name);
+                info!("Moving old hook from [{:?}] to [{:?}].", hook_path, new_hook_path);
+                if new_hook_path.exists() {
+                    return Err(format_err!("Tried to move existing [{}] hook to path [{}], but a file already existed.", hook_name, new_hook_path.to_string_lossy()));
+                }
+                fs::create_dir_all(&hookd_directory)?;
+                fs::rename(&hook_path, &new_hook_path)?;
+            }
+            Some(version) if version < DISPATCHER_VERSION => {
+                info!("Upgrading the [{}] dispatcher hook from version [{}] to [{}].", hook_name, version, DISPATCHER_VERSION)
+            }
+            Some(_) => {
+                info!("Hook [{}] is already set up as a dispatching hook. No need to make it a dispatcher.", hook_name)
             }
-            fs::rename(&hook_path, &new_hook_path)?;
-        } else {
-            info!("Hook [{}] is already set up as a dispatching hook. No need to make it a dispatcher.", hook_name)
         }
     }
 
-    // Copy global graph hook to directory
-    let globalgraph_hook_path = hookd_directory.join("globalgraph");
-
-    info!("Writing Global Graph hook to [{:?}].", globalgraph_hook_path);
-    fs::write(globalgraph_hook_path, hook_bytes.as_ref())?;
-
     // Add sh files that executes all hooks in the hook.d directory.
     info!("Writing new hook to [{:?}] that executes all the hooks in [{}] directory.", hook_path, hookd_directory_name);
-    fs::write(hook_path, format!(
-    "#!/bin/sh
-     #HOOK_DISPATCH
-     for file in ./.git/hooks/{}.d/*; do $file; done" , hook_name))?;
+    fs::write(&hook_path, dispatcher_script(hook_name))?;
+    make_executable(&hook_path)?;
 
     Ok(())
 }
 
-/// Checks the contents of a hook file to see if this hook is the global graph hook dispatcher.
-fn is_hook_dispatcher(hook_contents: &str) -> Result<bool, Error> {
-    // Check the string contents of the file to see if has the global graph signifier.
-    let global_graph_signifier = Regex::new(r"^#HOOK_DISPATCH(\r\n|\r|\n)")?;
-    if global_graph_signifier.find(hook_contents).is_some() {
-        return Ok(true);
+/// Removes the Global Graph hook from `hooks/<hook_name>.d/`. When no other hook is left, the
+/// dispatcher is removed too, and the original hook the installer moved aside is restored. The
+/// dispatcher is kept while it chains to a hook of the same name (see `chain_hooks_path`).
+fn uninstall_hook(repo: &Repository, hook_name: &str) -> Result<(), Error> {
+    let is_chained = match chained_hooks_directory(repo)? {
+        Some(chained_directory) => chained_directory.join(hook_name).is_file(),
+        None => false,
+    };
+    remove_hook(&client::hooks_directory(repo), hook_name, is_chained)
+}
+
+/// Removes the Global Graph hook script, and the dispatcher unless it still runs other hooks.
+fn remove_hook(hooks_directory: &Path, hook_name: &str, is_chained: bool) -> Result<(), Error> {
+    let hookd_directory = hooks_directory.join(format!("{}.d", hook_name));
+    let globalgraph_hook_path = hookd_directory.join(HOOK_SCRIPT_NAME);
+    if globalgraph_hook_path.exists() {
+        info!("Removing hook [{}]", hook_name);
+        fs::remove_file(&globalgraph_hook_path)?;
+    }
+
+    // If the dispatcher was replaced since, whoever replaced it is responsible for the other hooks.
+    let hook_path = hooks_directory.join(hook_name);
+    let is_dispatcher = match fs::read_to_string(&hook_path) {
+        Ok(hook_contents) => is_hook_dispatcher(&hook_contents),
+        Err(_) => false,
+    };
+    if !is_dispatcher {
+        return Ok(());
+    }
+
+    let remaining_hooks = if hookd_directory.exists() {
+        fs::read_dir(&hookd_directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, io::Error>>()?
+    } else {
+        vec!()
+    };
+    let original_hook_path = hookd_directory.join(hook_name);
+
+    if is_chained {
+        info!("The [{}] dispatcher also runs the chained hook, keeping it.", hook_name);
+        if remaining_hooks.is_empty() && hookd_directory.exists() {
+            fs::remove_dir(&hookd_directory)?;
+        }
+    } else if remaining_hooks.is_empty() {
+        info!("Removing the dispatcher hook [{:?}].", hook_path);
+        fs::remove_file(&hook_path)?;
+        if hookd_directory.exists() {
+            fs::remove_dir(&hookd_directory)?;
+        }
+    } else if remaining_hooks == [original_hook_path.clone()] {
+        info!("Restoring the original hook from [{:?}] to [{:?}].", original_hook_path, hook_path);
+        fs::remove_file(&hook_path)?;
+        fs::rename(&original_hook_path, &hook_path)?;
+        fs::remove_dir(&hookd_directory)?;
     } else {
-        return Ok(false);
+        info!("Other hooks remain in [{:?}], keeping the dispatcher hook.", hookd_directory);
+    }
+
+    Ok(())
+}
+
+/// Returns the hooks that are dispatchers.
+fn find_dispatchers(repo: &Repository) -> Result<Vec<String>, Error> {
+    let hooks_directory = client::hooks_directory(repo);
+    let mut dispatchers = vec!();
+    if !hooks_directory.exists() {
+        return Ok(dispatchers);
+    }
+
+    for entry in fs::read_dir(&hooks_directory)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let hook_contents = String::from_utf8_lossy(&fs::read(&path)?).into_owned();
+        if let (true, Some(hook_name)) = (is_hook_dispatcher(&hook_contents), path.file_name()) {
+            dispatchers.push(hook_name.to_string_lossy().into_owned());
+        }
+    }
+    Ok(dispatchers)
+}
+
+/// Git runs the hooks in `core.hooksPath` when it is set, eg. by hook managers such as Husky, and
+/// ignores the repository's hooks directory. Points `core.hooksPath` back at the hooks directory
+/// (in the local git config), and records the previous value in `globalgraph.chainedhookspath`, so
+/// the dispatchers run the hooks there after their own. Returns the hooks in the chained directory.
+fn chain_hooks_path(repo: &Repository) -> Result<Vec<String>, Error> {
+    let hooks_directory = client::hooks_directory(repo);
+    let mut config = repo.config()?;
+
+    match config.get_string("core.hooksPath") {
+        Ok(hooks_path) => {
+            let resolved = client::dispatcher::resolve_hooks_path(repo, &config.get_path("core.hooksPath")?);
+            if !client::dispatcher::is_hooks_directory(repo, &resolved) {
+                info!("core.hooksPath is [{}]. Pointing it at [{:?}], and running its hooks from there.", hooks_path, hooks_directory);
+                config.set_str(CHAINED_HOOKS_PATH_CONFIG, &hooks_path)?;
+                let hooks_directory_path = hooks_directory.to_str()
+                    .ok_or(format_err!("The hooks directory [{:?}] is not valid UTF8.", hooks_directory))?;
+                config.set_str("core.hooksPath", hooks_directory_path)?;
+                warn_about_inactive_hooks(&hooks_directory)?;
+            }
+        }
+        Err(ref e) if e.code() == ErrorCode::NotFound => {}
+        Err(e) => return Err(Error::from(e)),
+    }
+
+    let chained_directory = match chained_hooks_directory(repo)? {
+        Some(chained_directory) => chained_directory,
+        None => return Ok(vec!()),
+    };
+    Ok(CLIENT_HOOKS.iter()
+        .filter(|hook_name| chained_directory.join(hook_name).is_file())
+        .map(|hook_name| hook_name.to_string())
+        .collect())
+}
+
+/// Git didn't run the hooks in the hooks directory while `core.hooksPath` pointed elsewhere. Once it
+/// points back, they run again.
+fn warn_about_inactive_hooks(hooks_directory: &Path) -> Result<(), Error> {
+    for hook_name in CLIENT_HOOKS {
+        let hook_path = hooks_directory.join(hook_name);
+        if !hook_path.is_file() {
+            continue;
+        }
+        let hook_contents = String::from_utf8_lossy(&fs::read(&hook_path)?).into_owned();
+        if !is_hook_dispatcher(&hook_contents) {
+            warn!("Git didn't run the hook [{:?}] while core.hooksPath was set, and will from now on. Delete it if it is no longer needed.", hook_path);
+        }
     }
+    Ok(())
+}
+
+/// Undoes `chain_hooks_path`: if `core.hooksPath` still points at the hooks directory, it is set
+/// back to the chained directory.
+fn unchain_hooks_path(repo: &Repository) -> Result<(), Error> {
+    let chained_hooks_path = match repo.config()?.get_string(CHAINED_HOOKS_PATH_CONFIG) {
+        Ok(chained_hooks_path) => chained_hooks_path,
+        Err(_) => return Ok(()),
+    };
+
+    let mut local_config = repo.config()?.open_level(ConfigLevel::Local)
+        .context("Could not open the local git config of this repository.")?;
+    let points_at_hooks_directory = match local_config.get_path("core.hooksPath") {
+        Ok(path) => client::dispatcher::is_hooks_directory(repo, &client::dispatcher::resolve_hooks_path(repo, &path)),
+        Err(_) => false,
+    };
+
+    if points_at_hooks_directory {
+        info!("Setting core.hooksPath back to [{}].", chained_hooks_path);
+        local_config.remove("core.hooksPath")?;
+        // The chained value may come from the global git config, which applies again.
+        if repo.config()?.get_string("core.hooksPath").ok().as_ref() != Some(&chained_hooks_path) {
+            local_config.set_str("core.hooksPath", &chained_hooks_path)?;
+        }
+    }
+
+    local_config.remove(CHAINED_HOOKS_PATH_CONFIG)?;
+    Ok(())
+}
+
+/// The git directories of the main working tree and of every linked worktree.
+fn worktree_git_directories(repo: &Repository) -> Result<Vec<PathBuf>, Error> {
+    let common_directory = client::common_git_directory(repo);
+    let mut git_directories = vec!(common_directory.clone());
+
+    let worktrees_directory = common_directory.join("worktrees");
+    if worktrees_directory.exists() {
+        for entry in fs::read_dir(&worktrees_directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                git_directories.push(path);
+            }
+        }
+    }
+    Ok(git_directories)
+}
+
+/// Installs the client executable the hooks run, which is also the background sync agent the hooks
+/// hand synchronization requests to. Older installers installed a separate sync agent, which is
+/// removed.
+fn install_client_executable(repo: &Repository, executable_bytes: &[u8]) -> Result<(), Error> {
+    info!("Writing the Global Graph client executable to [{:?}].", client::client_executable_path(repo));
+    client::update::install_client_executable(repo, executable_bytes)?;
+
+    let legacy_agent_path = legacy_sync_agent_path(repo);
+    if legacy_agent_path.exists() {
+        info!("Removing the sync agent [{:?}], which the client executable replaces.", legacy_agent_path);
+        fs::remove_file(&legacy_agent_path)?;
+    }
+    Ok(())
+}
+
+/// The sync agent executable older installers installed next to the hooks.
+fn legacy_sync_agent_path(repo: &Repository) -> PathBuf {
+    client::hooks_directory(repo).join(format!("globalgraph-sync{}", std::env::consts::EXE_SUFFIX))
+}
+
+/// Marks an installed file as executable. Git skips hooks that aren't executable on POSIX systems.
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(path, permissions)
+        .context(format!("Could not make [{:?}] executable.", path))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Checks the contents of a hook file to see if this hook is the global graph hook dispatcher, of any version.
+fn is_hook_dispatcher(hook_contents: &str) -> bool {
+    dispatcher_version(hook_contents).is_some()
 }
 
 fn get_yes_or_no() -> Result<bool, Error> {
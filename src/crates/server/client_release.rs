@@ -0,0 +1,94 @@
+//! Publishes signed client releases for the server to host, so clients update themselves.
+//!
+//! Generate a key pair once, and build the client with the public key in the
+//! `GLOBALGRAPH_UPDATE_PUBLIC_KEY` environment variable. Clients only install releases signed with
+//! the matching private key, which never needs to be on the server.
+
+use failure::Error;
+use failure::ResultExt;
+use failure::format_err;
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use std::fs;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "client-release")]
+enum Opt {
+    /// Generates the key pair client releases are signed with, and prints the public key to build the
+    /// client with.
+    #[structopt(name = "generate-key")]
+    GenerateKey {
+        /// Where to write the private key. Keep it out of the server's working directory.
+        #[structopt(long = "private_key", parse(from_os_str))]
+        private_key: PathBuf,
+    },
+
+    /// Signs a client executable and adds it to the releases the server hosts. The version is read
+    /// from the executable.
+    #[structopt(name = "publish")]
+    Publish {
+        /// The private key written by `generate-key`.
+        #[structopt(long = "private_key", parse(from_os_str))]
+        private_key: PathBuf,
+
+        /// The working directory of the server.
+        #[structopt(long = "work_dir", parse(from_os_str))]
+        work_directory: PathBuf,
+
+        /// The platform the executable runs on, eg. windows-x86_64. Defaults to this machine's.
+        #[structopt(long = "platform")]
+        platform: Option<String>,
+
+        /// The client executable, ie. a release build of `globalgraph`.
+        #[structopt(parse(from_os_str))]
+        executable: PathBuf,
+    },
+}
+
+fn main() -> Result<(), Error> {
+    match Opt::from_args() {
+        Opt::GenerateKey { private_key } => generate_key(&private_key),
+        Opt::Publish { private_key, work_directory, platform, executable } =>
+            publish(&private_key, &work_directory, &platform.unwrap_or_else(shared::client_platform), &executable),
+    }
+}
+
+fn generate_key(private_key_path: &PathBuf) -> Result<(), Error> {
+    if private_key_path.exists() {
+        return Err(format_err!("[{:?}] already exists. Clients built with its public key can't verify releases signed with a new key.", private_key_path));
+    }
+
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| format_err!("Could not generate a key pair."))?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8))
+        .map_err(|_| format_err!("Could not read the generated key pair."))?;
+    fs::write(private_key_path, pkcs8.as_ref())
+        .context(format!("Could not write the private key to [{:?}].", private_key_path))?;
+
+    println!("Wrote the private key to [{}].", private_key_path.to_string_lossy());
+    println!("Build the client with the public key:");
+    println!("    GLOBALGRAPH_UPDATE_PUBLIC_KEY={} cargo build --release -p client", base64::encode(key_pair.public_key_bytes()));
+    Ok(())
+}
+
+fn publish(private_key_path: &PathBuf, work_directory: &PathBuf, platform: &str, executable_path: &PathBuf) -> Result<(), Error> {
+    let private_key = fs::read(private_key_path).context("Could not read the private key.")?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&private_key))
+        .map_err(|_| format_err!("[{:?}] is not a private key written by `client-release generate-key`.", private_key_path))?;
+
+    let executable = fs::read(executable_path).context(format!("Could not read [{:?}].", executable_path))?;
+    let version = shared::find_client_version(&executable)
+        .ok_or(format_err!("[{:?}] is not a Global Graph client executable, it has no version marker.", executable_path))?;
+
+    let signature = key_pair.sign(&shared::client_release_message(&version, platform, &executable));
+
+    let release_directory = server::client_release_directory(work_directory, &version, platform);
+    fs::create_dir_all(&release_directory)?;
+    fs::write(release_directory.join(server::CLIENT_EXECUTABLE_NAME), &executable)?;
+    fs::write(release_directory.join(format!("{}.sig", server::CLIENT_EXECUTABLE_NAME)), base64::encode(signature.as_ref()))?;
+
+    println!("Published the [{}] client for [{}] to [{}].", version, platform, release_directory.to_string_lossy());
+    Ok(())
+}
@@ -2,11 +2,13 @@ use actix_web::{
     http, middleware, server, App, AsyncResponder, HttpMessage,
     HttpRequest, HttpResponse
 };
+use actix_web::middleware::{Middleware, Started};
 
 use log::{debug, info};
 use futures::{Future};
 use git2::Branch;
 use git2::Repository;
+use std::path::Path;
 use std::path::PathBuf;
 use git2::BranchType;
 use failure::Error;
@@ -14,18 +16,52 @@ use failure::ResultExt;
 use failure::err_msg;
 use shared::*;
 use git2::Commit;
+use git2::Tree;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use git2::Oid;
 use structopt::StructOpt;
 use failure::format_err;
+use serde_derive::Deserialize;
+use std::fs;
+use std::io;
+use std::io::Write;
 
 
 pub struct AppState {
     work_directory: PathBuf,
 }
 
-// Returns information about the Global Graph server that is running.
-pub struct InfoResponse {
-    pub global_graph_git_remote_url: String,
+/// The directory of the working directory holding the client releases, as
+/// `clients/<version>/<platform>/globalgraph`. Each executable is signed by the base64 encoded
+/// signature in `globalgraph.sig` next to it (see the `client-release` tool).
+pub const CLIENT_RELEASES_DIRECTORY: &str = "clients";
+
+/// The file name of the client executables, and of their signatures with `.sig` appended.
+pub const CLIENT_EXECUTABLE_NAME: &str = "globalgraph";
+
+/// The file of the working directory listing the projects the global graph serves, so clones with
+/// hooks installed from a git template can configure themselves:
+///
+///     { "global_graph_url": "git@server.com:globalgraph.git", "origins": ["git@server.com:team/project.git"] }
+pub const PROJECTS_FILE: &str = "projects.json";
+
+/// The file of the working directory listing the tokens clients authenticate with, one per line.
+/// Lines starting with `#` are ignored. Without it, the query server accepts every request.
+pub const AUTH_TOKENS_FILE: &str = "auth_tokens";
+
+/// The directory of the working directory recording which client owns each namespace, as one file
+/// per repository uuid holding the SHA-256 of the client's namespace secret. The first secret
+/// presented for a namespace claims it.
+pub const NAMESPACE_OWNERS_DIRECTORY: &str = "namespace_owners";
+
+/// The contents of `PROJECTS_FILE`.
+#[derive(Debug, Deserialize)]
+struct RegisteredProjects {
+    /// The url clients push to the global graph repository with.
+    global_graph_url: String,
+    /// The `origin` urls of the projects' repositories.
+    origins: Vec<String>,
 }
 
 /// Returns all branches in the global graph that can conflict with the given branch.
@@ -44,32 +80,515 @@ fn get_conflicting_branches<'repo>(global_graph: &'repo Repository, _target_head
     return Ok(branch_vec);
 }
 
-/// Given a target_branch and some changes, determines whether these changes can be committed on
-/// this branch. If not, it returns a reasoning. 
-fn check_integration(repo: &Repository, commit_head: &HeadCommit, files: &[GitPath]) -> Result<Vec<UnintegratedChange>, Error> {
-    let branches = get_conflicting_branches(&repo, &commit_head)?;
+/// Returns the paths that differ between two trees. A missing old tree is treated as empty.
+fn changed_paths(repo: &Repository, old_tree: Option<&Tree>, new_tree: &Tree) -> Result<Vec<GitPath>, Error> {
+    let diff = repo.diff_tree_to_tree(old_tree, Some(new_tree), None)?;
+    Ok(diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .filter_map(|path| path.to_str())
+        .map(GitPath::new)
+        .collect())
+}
+
+/// Finds uncommitted work in the snapshots other clients pushed under `namespace`. Uncommitted work
+/// can never be integrated by another head, so every change found is a conflict. The requesting
+/// client's own snapshot is ignored.
+///
+/// Only the given files are checked, or every file in the snapshots if `files` is None.
+fn check_in_flight_work(repo: &Repository, namespace: &str, kind: ChangeKind, requesting_repo_uuid: &str, files: Option<&[GitPath]>) -> Result<Vec<UnintegratedChange>, Error> {
+    let mut in_flight_changes = vec!();
+
+    for reference in repo.references()? {
+        let reference = reference?;
+        let snapshot_reference = match reference.name() {
+            Some(name) => ReferencePath::new(name),
+            None => continue,
+        };
+        let repo_uuid = match map_snapshot_to_client(namespace, &snapshot_reference) {
+            Some(repo_uuid) => repo_uuid,
+            None => continue,
+        };
+        if repo_uuid == requesting_repo_uuid {
+            continue;
+        }
+
+        let snapshot = reference.peel_to_commit()?;
+        let base_tree = match snapshot.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let snapshot_tree = snapshot.tree()?;
+        let branch = snapshot.message().and_then(parse_snapshot_branch)
+            .unwrap_or_else(|| ReferencePath::new("HEAD"));
+
+        let candidate_files = match files {
+            Some(files) => files.to_vec(),
+            None => changed_paths(repo, base_tree.as_ref(), &snapshot_tree)?,
+        };
+
+        for file in candidate_files {
+            let path = Path::new(file.as_str());
+            let snapshot_entry = snapshot_tree.get_path(path).ok().map(|entry| entry.id());
+            let base_entry = match base_tree {
+                Some(ref tree) => tree.get_path(path).ok().map(|entry| entry.id()),
+                None => None,
+            };
+
+            if snapshot_entry != base_entry {
+                debug!("Found in-flight work on [{}] in [{}].", file, snapshot_reference);
+                in_flight_changes.push(UnintegratedChange {
+                    file,
+                    commit: CommitSha::new(&snapshot.id().to_string()),
+                    branch: branch.clone(),
+                    repo_uuid: repo_uuid.clone(),
+                    kind,
+                    rewritten_as: None,
+                });
+            }
+        }
+    }
+
+    Ok(in_flight_changes)
+}
+
+/// Finds the staged and working tree changes of other clients. Files that are both staged and
+/// modified in the working tree are only reported as staged.
+fn find_in_flight_work(repo: &Repository, requesting_repo_uuid: &str, files: Option<&[GitPath]>) -> Result<Vec<UnintegratedChange>, Error> {
+    let mut in_flight_changes = check_in_flight_work(repo, INDEX_REFS_NAMESPACE, ChangeKind::Staged, requesting_repo_uuid, files)?;
+
+    for change in check_in_flight_work(repo, WORKTREE_REFS_NAMESPACE, ChangeKind::WorkingTree, requesting_repo_uuid, files)? {
+        let already_reported = in_flight_changes.iter()
+            .any(|reported| reported.repo_uuid == change.repo_uuid && *reported.file == *change.file);
+        if !already_reported {
+            in_flight_changes.push(change);
+        }
+    }
+
+    Ok(in_flight_changes)
+}
+
+/// Lists every change to any file, on every branch of other clients that can conflict with the given
+/// head, that the head doesn't integrate. For each file and branch, only the latest change is reported.
+fn find_all_unintegrated_changes(repo: &Repository, requesting_repo_uuid: &str, commit_head: &HeadCommit) -> Result<Vec<UnintegratedChange>, Error> {
     let mut unintegrated_changes = vec!();
-    let commit_head_object = match commit_head {
-        Some(commit) => {
-            println!("repo: {:?}", &repo.path());
-            println!("commit: {:?}", &commit.0);
-            Some(repo.find_commit(Oid::from_str(&commit.0)?)?)
+    let mut equivalence_cache = EquivalenceCache::default();
+
+    // A head that isn't synchronized yet (eg. a detached HEAD, or a sync still queued) can't integrate
+    // anything, as in `check_integration`.
+    let head = match commit_head {
+        Some(ref head) => match repo.find_commit(Oid::from_str(head)?) {
+            Ok(head_object) => Some(head_object.id()),
+            Err(_) => {
+                debug!("Head [{}] is not in the global graph, it can't integrate anything.", head);
+                None
+            }
         },
-        None => None
+        None => None,
+    };
+
+    for conflict_branch in get_conflicting_branches(&repo, &commit_head)? {
+        let conflicting_branch_name = ReferencePath::new(conflict_branch.get().name()
+            .ok_or(err_msg("A branch name in the Global Graph is invalid UTF-8"))?);
+        // As in `check_integration`, branches that no client pushed, and the requester's own, are skipped.
+        let (client_info, local_branch_reference) = match map_branch_to_local(&conflicting_branch_name) {
+            Ok(mapping) => mapping,
+            Err(_) => continue,
+        };
+        if client_info.repo_uuid == requesting_repo_uuid {
+            continue;
+        }
+
+        // Walk the commits on the branch that the head doesn't contain, newest first.
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push_ref(&conflicting_branch_name)?;
+        if let Some(head) = head {
+            revwalk.hide(head)?;
+        }
+
+        let mut reported_files: HashSet<String> = HashSet::new();
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+            let parent_tree = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+
+            // The head may contain a rewritten version of this commit instead.
+            let integrated = match head {
+                Some(head) => integrates(repo, head, commit.id())?,
+                None => false,
+            };
+
+            for file in changed_paths(repo, parent_tree.as_ref(), &commit.tree()?)? {
+                if !reported_files.insert(file.to_string()) || integrated {
+                    continue;
+                }
+
+                // The head may contain an equivalent change instead, eg. if it cherry-picked it.
+                let integrated_equivalent = match head {
+                    Some(head) => integrates_equivalent_change(repo, &mut equivalence_cache, head, &commit, &file)?,
+                    None => false,
+                };
+                if !integrated_equivalent {
+                    unintegrated_changes.push(UnintegratedChange {
+                        file,
+                        commit: CommitSha::new(&commit.id().to_string()),
+                        branch: local_branch_reference.clone(),
+                        repo_uuid: client_info.repo_uuid.clone(),
+                        kind: ChangeKind::Committed,
+                        rewritten_as: newest_rewrite(repo, commit.id())?.map(|oid| CommitSha::new(&oid.to_string())),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(unintegrated_changes)
+}
+
+/// Returns the commits that replaced `commit`, oldest first, by following the recorded rewrites.
+fn find_rewrites(repo: &Repository, commit: Oid) -> Result<Vec<Oid>, Error> {
+    let mut rewrites: Vec<Oid> = vec!();
+    let mut current = commit;
+
+    loop {
+        let rewrite_reference = format!("{}{}", REWRITE_REFS_NAMESPACE, current);
+        let rewritten = match repo.refname_to_id(&rewrite_reference) {
+            Ok(rewritten) => rewritten,
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => break,
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        // A commit rewritten back to an earlier version would otherwise loop forever.
+        if rewritten == commit || rewrites.contains(&rewritten) {
+            break;
+        }
+        rewrites.push(rewritten);
+        current = rewritten;
+    }
+
+    Ok(rewrites)
+}
+
+/// Returns the newest commit that replaced `commit`, if it was rewritten.
+fn newest_rewrite(repo: &Repository, commit: Oid) -> Result<Option<Oid>, Error> {
+    Ok(find_rewrites(repo, commit)?.pop())
+}
+
+/// Whether `head` integrates `commit`, either by containing it or by containing any of the commits
+/// that replaced it.
+fn integrates(repo: &Repository, head: Oid, commit: Oid) -> Result<bool, Error> {
+    for candidate in Some(commit).into_iter().chain(find_rewrites(repo, commit)?) {
+        if candidate == head || repo.graph_descendant_of(head, candidate)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the version of `file` in a commit, or None if the commit doesn't have the file.
+fn file_version(commit: &Commit, file: &GitPath) -> Result<Option<Oid>, Error> {
+    Ok(commit.tree()?.get_path(Path::new(file.as_str())).ok().map(|entry| entry.id()))
+}
+
+/// Whether a commit changed `file` compared to its first parent.
+fn changes_file(commit: &Commit, file: &GitPath) -> Result<bool, Error> {
+    let parent_version = match commit.parents().next() {
+        Some(parent) => file_version(&parent, file)?,
+        None => None,
     };
+    Ok(file_version(commit, file)? != parent_version)
+}
+
+/// Returns the lines a commit removed from and added to `file`, without their positions, like
+/// `git patch-id` restricted to that file. The same change made on top of different versions of
+/// the file gives the same lines. Binary changes have no lines.
+fn file_patch(repo: &Repository, commit: &Commit, file: &GitPath) -> Result<Vec<(char, Vec<u8>)>, Error> {
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+
+    let mut options = git2::DiffOptions::new();
+    options.pathspec(file.as_str()).disable_pathspec_match(true);
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), Some(&mut options))?;
+
+    let mut lines = vec!();
+    diff.print(git2::DiffFormat::Patch, |delta, _hunk, line| {
+        let path = delta.new_file().path().or(delta.old_file().path());
+        let is_file = path.and_then(|path| path.to_str()) == Some(file.as_str());
+        if is_file && (line.origin() == '+' || line.origin() == '-') {
+            lines.push((line.origin(), line.content().to_vec()));
+        }
+        true
+    })?;
+    Ok(lines)
+}
+
+/// What the commits of a head since a merge base did to a file.
+struct RangeChanges {
+    /// Every version of the file in those commits.
+    versions: HashSet<Oid>,
+    /// The patches of those commits that changed the file (see `file_patch`).
+    patches: Vec<Vec<(char, Vec<u8>)>>,
+}
+
+/// Caches what each head's history did to each file, so a request walks it once per file, not per commit.
+#[derive(Default)]
+struct EquivalenceCache {
+    /// The commits of a head since a merge base (all of them without one), keyed by both.
+    ranges: HashMap<(Oid, Option<Oid>), Vec<Oid>>,
+    changes: HashMap<(Oid, Option<Oid>, GitPath), RangeChanges>,
+}
+
+impl EquivalenceCache {
+    fn range_changes(&mut self, repo: &Repository, head: Oid, merge_base: Option<Oid>, file: &GitPath) -> Result<&RangeChanges, Error> {
+        let key = (head, merge_base, file.clone());
+        if !self.changes.contains_key(&key) {
+            if !self.ranges.contains_key(&(head, merge_base)) {
+                let mut revwalk = repo.revwalk()?;
+                revwalk.push(head)?;
+                if let Some(merge_base) = merge_base {
+                    revwalk.hide(merge_base)?;
+                }
+                let range = revwalk.collect::<Result<Vec<Oid>, git2::Error>>()?;
+                self.ranges.insert((head, merge_base), range);
+            }
+
+            let mut changes = RangeChanges { versions: HashSet::new(), patches: vec!() };
+            for &oid in &self.ranges[&(head, merge_base)] {
+                let candidate = repo.find_commit(oid)?;
+                if let Some(version) = file_version(&candidate, file)? {
+                    changes.versions.insert(version);
+                }
+                if changes_file(&candidate, file)? {
+                    changes.patches.push(file_patch(repo, &candidate, file)?);
+                }
+            }
+            self.changes.insert(key.clone(), changes);
+        }
+        Ok(&self.changes[&key])
+    }
+}
+
+/// Whether `head` contains a change to `file` equivalent to the latest change made to it in the
+/// history of `commit`, without containing `commit` itself, eg. because the change was cherry-picked.
+///
+/// The head integrates the change if it contains the commit that made it, or if one of its commits
+/// since it diverged from `commit` either has the same version of the file (blob equivalence), or
+/// changes the file the same way (patch equivalence).
+fn integrates_equivalent_change(repo: &Repository, cache: &mut EquivalenceCache, head: Oid, commit: &Commit, file: &GitPath) -> Result<bool, Error> {
+    let version = match file_version(commit, file)? {
+        Some(version) => version,
+        None => return Ok(false),
+    };
+
+    let mut history = repo.revwalk()?;
+    history.push(commit.id())?;
+    let mut latest_change = None;
+    for oid in history {
+        let candidate = repo.find_commit(oid?)?;
+        if changes_file(&candidate, file)? {
+            latest_change = Some(candidate);
+            break;
+        }
+    }
+    let patch = match latest_change {
+        // The head may contain the change itself, just not the later commits of the branch.
+        Some(ref change) if integrates(repo, head, change.id())? => return Ok(true),
+        Some(ref change) => file_patch(repo, change, file)?,
+        None => vec!(),
+    };
+
+    let merge_base = repo.merge_base(head, commit.id()).ok();
+    let changes = cache.range_changes(repo, head, merge_base, file)?;
+    if changes.versions.contains(&version) {
+        debug!("[{}] has the same version of [{}] as [{}].", head, file, commit.id());
+        return Ok(true);
+    }
+    if !patch.is_empty() && changes.patches.contains(&patch) {
+        debug!("[{}] changes [{}] the same way as [{}].", head, file, commit.id());
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Records commit rewrites as references in the global graph. A client can only rewrite commits it
+/// published (see `published_commits`), into commits the global graph has. Other rewrites are
+/// skipped: they could hide another client's work, or can't be integrated by anyone.
+fn store_rewrites(repo: &Repository, repo_uuid: &str, rewrites: &[CommitRewrite]) -> Result<usize, Error> {
+    let published = published_commits(repo, repo_uuid)?;
+
+    let mut recorded = 0;
+    for rewrite in rewrites {
+        let original = Oid::from_str(&rewrite.original)?;
+        let rewritten = Oid::from_str(&rewrite.rewritten)?;
+        if original == rewritten {
+            continue;
+        }
+
+        if repo.find_commit(rewritten).is_err() {
+            debug!("Skipping rewrite [{}] -> [{}]: the rewritten commit is not in the global graph.", original, rewritten);
+            continue;
+        }
+
+        let mut is_published = false;
+        for &tip in &published {
+            if tip == original || repo.graph_descendant_of(tip, original)? {
+                is_published = true;
+                break;
+            }
+        }
+        if !is_published {
+            debug!("Skipping rewrite [{}] -> [{}]: the original commit was not published by [{}].", original, rewritten, repo_uuid);
+            continue;
+        }
+
+        repo.reference(&format!("{}{}", REWRITE_REFS_NAMESPACE, original), rewritten, true,
+                       &format!("Rewritten as {}", rewritten))?;
+        recorded += 1;
+    }
+
+    Ok(recorded)
+}
+
+/// The commits a client's branches point to in the global graph, or pointed to at some point. The
+/// global graph keeps the reflog of every branch (see `prepare_work_directory`), so commits a client
+/// rewrote and force pushed away still count as published by it.
+fn published_commits(repo: &Repository, repo_uuid: &str) -> Result<Vec<Oid>, Error> {
+    if repo_uuid.is_empty() || repo_uuid.contains('/') {
+        return Err(format_err!("The repository uuid [{}] is not valid.", repo_uuid));
+    }
+
+    let mut commits = vec!();
+    for reference in repo.references_glob(&format!("refs/heads/{}/*", repo_uuid))? {
+        let reference = reference?;
+        if let Some(target) = reference.target() {
+            commits.push(target);
+        }
+        if let Some(name) = reference.name() {
+            if let Ok(reflog) = repo.reflog(name) {
+                commits.extend(reflog.iter().map(|entry| entry.id_new()).filter(|id| !id.is_zero()));
+            }
+        }
+    }
+
+    commits.sort();
+    commits.dedup();
+    Ok(commits)
+}
+
+/// Deletes the branches and snapshots a client pushed to the global graph. Returns the number of
+/// references deleted. Recorded rewrites are kept, other clients may still have the original commits.
+fn delete_client_references(repo: &Repository, repo_uuid: &str) -> Result<usize, Error> {
+    if repo_uuid.is_empty() || repo_uuid.contains('/') {
+        return Err(format_err!("The repository uuid [{}] is not valid.", repo_uuid));
+    }
+
+    let branch_namespace = format!("refs/heads/{}/", repo_uuid);
+    // The snapshots of every worktree of the client.
+    let is_snapshot = |name: &str| [INDEX_REFS_NAMESPACE, WORKTREE_REFS_NAMESPACE].iter()
+        .any(|namespace| map_snapshot_to_client(namespace, &ReferencePath::new(name)).map_or(false, |uuid| uuid == repo_uuid));
+
+    let mut client_references = vec!();
+    for reference in repo.references()? {
+        let reference = reference?;
+        if let Some(name) = reference.name() {
+            if name.starts_with(&branch_namespace) || is_snapshot(name) {
+                client_references.push(name.to_string());
+            }
+        }
+    }
+
+    for name in &client_references {
+        debug!("Deleting [{}].", name);
+        repo.find_reference(name)?.delete()?;
+    }
+
+    Ok(client_references.len())
+}
+
+/// Whether the secret proves the client owns the repository uuid's namespace. If no client claimed
+/// the namespace yet, the secret claims it.
+fn authenticate_namespace(work_directory: &Path, repo_uuid: &str, namespace_secret: &str) -> Result<bool, Error> {
+    if repo_uuid.is_empty() || repo_uuid.contains('/') || repo_uuid.starts_with('.') {
+        return Err(format_err!("The repository uuid [{}] is not valid.", repo_uuid));
+    }
+    if namespace_secret.is_empty() {
+        return Ok(false);
+    }
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, namespace_secret.as_bytes());
+    let digest: String = digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let owners_directory = work_directory.join(NAMESPACE_OWNERS_DIRECTORY);
+    fs::create_dir_all(&owners_directory).context(format!("Could not create [{:?}].", owners_directory))?;
+    let owner_path = owners_directory.join(repo_uuid);
+    // Created atomically, so of two clients claiming the same namespace at once only one succeeds.
+    match fs::OpenOptions::new().write(true).create_new(true).open(&owner_path) {
+        Ok(mut owner_file) => {
+            info!("Namespace [{}] claimed by its first client.", repo_uuid);
+            owner_file.write_all(digest.as_bytes()).context(format!("Could not write [{:?}].", owner_path))?;
+            Ok(true)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let owner_digest = fs::read_to_string(&owner_path).context(format!("Could not read [{:?}].", owner_path))?;
+            Ok(owner_digest.trim() == digest)
+        }
+        Err(e) => Err(format_err!("Could not create [{:?}]: {}", owner_path, e)),
+    }
+}
+
+/// Given a target_branch and some changes, determines whether these changes can be committed on
+/// this branch. If not, it returns a reasoning. 
+///
+/// `heads` are the parents the new commit will have: usually just the client's HEAD, but also the
+/// merged heads when committing a merge, or none if the client's repository has no commits yet.
+/// `applied_commits` are commits whose changes (but not history) the new commit copies, eg. when
+/// committing a cherry-pick. The requesting client's own branches are ignored: their commits are
+/// either in its history already, or being replayed onto it by a rebase.
+fn check_integration(repo: &Repository, requesting_repo_uuid: &str, heads: &[CommitSha], applied_commits: &[CommitSha], files: &[GitPath]) -> Result<Vec<UnintegratedChange>, Error> {
+    let branches = get_conflicting_branches(&repo, &heads.first().cloned())?;
+    let mut unintegrated_changes = vec!();
+    let mut equivalence_cache = EquivalenceCache::default();
+    let mut head_objects = vec!();
+    for head in heads {
+        // A merged head fetched from elsewhere may not be synchronized yet. Skipping it can only
+        // report more conflicts, never fewer.
+        match repo.find_commit(Oid::from_str(&head.0)?) {
+            Ok(head_object) => head_objects.push(head_object),
+            Err(_) => debug!("Head [{}] is not in the global graph, it can't integrate anything.", head),
+        }
+    }
+    let applied_commits = applied_commits.iter()
+        .map(|commit| Oid::from_str(commit))
+        .collect::<Result<Vec<Oid>, git2::Error>>()?;
 
     // For every branch that can conflict with the client's branch, check to make sure
     // the client has integrated its changes, for the files specified.
     for conflict_branch in branches {
-        println!("Checking branch [{}]", conflict_branch.name()?.ok_or(format_err!("Conflict candidate branch has a name that is not valid UTF8."))?);
+        let conflicting_branch_name = ReferencePath::new(conflict_branch.get().name()
+            .ok_or(format_err!("Conflict candidate branch has a name that is not valid UTF8."))?);
+        // Branches that no client pushed (eg. created on the server directly) belong to nobody.
+        let (client_info, local_branch_reference) = match map_branch_to_local(&conflicting_branch_name) {
+            Ok(mapping) => mapping,
+            Err(_) => {
+                debug!("Skipping branch [{}], it isn't in a client's namespace.", conflicting_branch_name);
+                continue;
+            }
+        };
+        if client_info.repo_uuid == requesting_repo_uuid {
+            continue;
+        }
+        debug!("Checking branch [{}]", conflicting_branch_name);
 
         for file in files {
-            println!("   - checking file [{:?}]", file);
+            debug!("   - checking file [{:?}]", file);
 
             // Find the most recent commit that touches this file on the conflict branch.
             let mut revwalk = repo.revwalk()?;
-            revwalk.push_ref(&conflict_branch.get().name()
-                .ok_or(err_msg("A branch name in the Global Graph is invalid UTF-8"))?)?;
+            revwalk.push_ref(&conflicting_branch_name)?;
 
             let mut latest_commit: Option<Commit> = None;
             'find_latest_commit: for oid in revwalk {
@@ -91,28 +610,33 @@ fn check_integration(repo: &Repository, commit_head: &HeadCommit, files: &[GitPa
 
                 // Verify that the current head integrates this change.
                 Some(latest_commit) => {
-                    let does_integrate = match commit_head_object {
-                        Some(ref head_object) => repo.graph_descendant_of(head_object.id().clone(), latest_commit.id())?,
-                        // If the client's repository has no valid head, then it definitely does not integrate
-                        // the changed file.
-                        None => false
-                    };
-                    println!("Found latest commit [{:?}]. Does the target branch integrate it: [{}]", latest_commit, does_integrate);
+                    // The change is integrated if the new commit copies it, or if any of its parents
+                    // contains it. Without parents (the client's repository has no valid head), it
+                    // definitely isn't.
+                    let mut does_integrate = applied_commits.contains(&latest_commit.id());
+                    for head_object in &head_objects {
+                        does_integrate = does_integrate || integrates(repo, head_object.id(), latest_commit.id())?;
+                    }
+
+                    // The head may also contain an equivalent change, eg. if it cherry-picked it.
+                    for head_object in &head_objects {
+                        does_integrate = does_integrate || integrates_equivalent_change(repo, &mut equivalence_cache, head_object.id(), &latest_commit, file)?;
+                    }
+                    debug!("Found latest commit [{:?}]. Does the target branch integrate it: [{}]", latest_commit, does_integrate);
 
                     if does_integrate {
                         // The target head already integrated this change, so we don't have a conflict.
                         continue;
                     } else {
-                        let conflicting_branch_name = ReferencePath::new(conflict_branch.get().name()
-                            .ok_or(format_err!("Conflict found, but conflicting branch name was not UTF8."))?);
-                        let (client_info, local_branch_reference) = map_branch_to_local(&conflicting_branch_name)?;
 //                        let repo_info = break_repo_uuid(&client_info.repo_uuid)?;
 
                         unintegrated_changes.push(UnintegratedChange {
                             file: (*file).clone(),
                             commit: CommitSha::new(&format!("{}", latest_commit.id())),
-                            branch: local_branch_reference,
-                            repo_uuid: client_info.repo_uuid,
+                            branch: local_branch_reference.clone(),
+                            repo_uuid: client_info.repo_uuid.clone(),
+                            kind: ChangeKind::Committed,
+                            rewritten_as: newest_rewrite(repo, latest_commit.id())?.map(|oid| CommitSha::new(&oid.to_string())),
                         });
                     }
                 }
@@ -142,7 +666,11 @@ fn conflicts_after_commit(request: &HttpRequest<AppState>) -> Box<Future<Item=Ht
 //            let target_branch = repo.find_branch(&to_friendly_name(&gg_branch)?, BranchType::Local)
 //                .context(format!("The client's current branch [{:?}] was not found in the Global Graph.", &gg_branch)).compat()?;
 
-            let unintegrated_changes = check_integration(&repo, &payload.repo_head_commit, &payload.files)?;
+            let heads: Vec<CommitSha> = payload.repo_head_commit.iter().cloned()
+                .chain(payload.other_parents.iter().cloned())
+                .collect();
+            let mut unintegrated_changes = check_integration(&repo, &payload.repo_uuid, &heads, &payload.applied_commits, &payload.files)?;
+            unintegrated_changes.extend(find_in_flight_work(&repo, &payload.repo_uuid, Some(&payload.files))?);
             let response = ConflictsAfterCommitResponse {
                 conflicts: unintegrated_changes
             };
@@ -151,6 +679,217 @@ fn conflicts_after_commit(request: &HttpRequest<AppState>) -> Box<Future<Item=Ht
         }).responder()
 }
 
+/// Handles requests for every change in the global graph that a client's head doesn't integrate.
+fn unintegrated_changes(request: &HttpRequest<AppState>) -> Box<Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let work_dir = request.state().work_directory.clone();
+    request.json().from_err()
+        .and_then(move |payload: UnintegratedChangesRequest| {
+            debug!("Received request: {:?}", payload);
+
+            let repo_path = work_dir.join("repo");
+            let repo = Repository::open_bare(repo_path).context("Could not open global graph repository path.").compat()?;
+
+            let mut changes = find_all_unintegrated_changes(&repo, &payload.repo_uuid, &payload.repo_head_commit)?;
+            changes.extend(find_in_flight_work(&repo, &payload.repo_uuid, None)?);
+
+            Ok(HttpResponse::Ok().json(UnintegratedChangesResponse { changes }))
+        }).responder()
+}
+
+/// Handles commit rewrites uploaded by a client's post-rewrite hook.
+fn rewrites(request: &HttpRequest<AppState>) -> Box<Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let work_dir = request.state().work_directory.clone();
+    request.json().from_err()
+        .and_then(move |payload: RecordRewritesRequest| {
+            debug!("Received rewrites from [{}]: {:?}", payload.repo_uuid, payload.rewrites);
+
+            if !authenticate_namespace(&work_dir, &payload.repo_uuid, &payload.namespace_secret)? {
+                debug!("Refusing to record rewrites for the namespace [{}] without its owner's secret.", payload.repo_uuid);
+                return Ok(HttpResponse::Forbidden().finish());
+            }
+
+            let repo_path = work_dir.join("repo");
+            let repo = Repository::open_bare(repo_path).context("Could not open global graph repository path.").compat()?;
+
+            let recorded = store_rewrites(&repo, &payload.repo_uuid, &payload.rewrites)?;
+            Ok(HttpResponse::Ok().json(RecordRewritesResponse { recorded }))
+        }).responder()
+}
+
+/// Handles a client leaving the global graph, ie. when Global Graph is uninstalled from it.
+fn delete_namespace(request: &HttpRequest<AppState>) -> Box<Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let work_dir = request.state().work_directory.clone();
+    request.json().from_err()
+        .and_then(move |payload: DeleteNamespaceRequest| {
+            debug!("Received a request to delete the namespace [{}].", payload.repo_uuid);
+
+            if !authenticate_namespace(&work_dir, &payload.repo_uuid, &payload.namespace_secret)? {
+                debug!("Refusing to delete the namespace [{}] without its owner's secret.", payload.repo_uuid);
+                return Ok(HttpResponse::Forbidden().finish());
+            }
+
+            let repo_path = work_dir.join("repo");
+            let repo = Repository::open_bare(repo_path).context("Could not open global graph repository path.").compat()?;
+
+            let deleted = delete_client_references(&repo, &payload.repo_uuid)?;
+            Ok(HttpResponse::Ok().json(DeleteNamespaceResponse { deleted }))
+        }).responder()
+}
+
+/// Handles a client claiming its namespace with its secret, so no other client can change it.
+fn claim_namespace(request: &HttpRequest<AppState>) -> Box<Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let work_dir = request.state().work_directory.clone();
+    request.json().from_err()
+        .and_then(move |payload: ClaimNamespaceRequest| {
+            if !authenticate_namespace(&work_dir, &payload.repo_uuid, &payload.namespace_secret)? {
+                debug!("The namespace [{}] was already claimed by another client.", payload.repo_uuid);
+                return Ok(HttpResponse::Forbidden().finish());
+            }
+            Ok(HttpResponse::Ok().json(ClaimNamespaceResponse {}))
+        }).responder()
+}
+
+/// Reads the projects the global graph serves. Without a projects file, it serves none.
+fn read_registered_projects(work_directory: &Path) -> Result<Option<RegisteredProjects>, Error> {
+    let path = work_directory.join(PROJECTS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read(&path).context(format!("Could not read [{:?}].", path))?;
+    Ok(Some(serde_json::from_slice(&contents).context(format!("[{:?}] is not a valid projects file.", path))?))
+}
+
+/// Handles a new clone asking whether the global graph serves the project it was cloned from.
+fn project_lookup(request: &HttpRequest<AppState>) -> Box<Future<Item=HttpResponse, Error=actix_web::Error>> {
+    let work_dir = request.state().work_directory.clone();
+    request.json().from_err()
+        .and_then(move |payload: ProjectLookupRequest| {
+            debug!("Received request: {:?}", payload);
+
+            let global_graph_url = read_registered_projects(&work_dir).map_err(|e| e.compat())?
+                .filter(|projects| projects.origins.iter().any(|origin| same_remote_url(origin, &payload.origin_url)))
+                .map(|projects| projects.global_graph_url);
+            Ok(HttpResponse::Ok().json(ProjectLookupResponse { global_graph_url }))
+        }).responder()
+}
+
+/// Reads the tokens clients authenticate with, or None if the server doesn't require authentication.
+fn read_auth_tokens(work_directory: &Path) -> Result<Option<Vec<String>>, Error> {
+    let path = work_directory.join(AUTH_TOKENS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).context(format!("Could not read [{:?}].", path))?;
+    Ok(Some(contents.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()))
+}
+
+/// Rejects requests without a valid `Authorization: Bearer <token>` header, when the working
+/// directory has an `AUTH_TOKENS_FILE`.
+struct RequireAuthToken;
+
+impl Middleware<AppState> for RequireAuthToken {
+    fn start(&self, request: &HttpRequest<AppState>) -> actix_web::Result<Started> {
+        let tokens = match read_auth_tokens(&request.state().work_directory)? {
+            Some(tokens) => tokens,
+            None => return Ok(Started::Done),
+        };
+
+        let token = request.headers().get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| if value.starts_with("Bearer ") { Some(value["Bearer ".len()..].trim()) } else { None });
+        match token {
+            Some(token) if tokens.iter().any(|valid_token| valid_token == token) => Ok(Started::Done),
+            _ => {
+                debug!("Rejecting an unauthenticated request to [{}].", request.path());
+                Ok(Started::Response(HttpResponse::Unauthorized().finish()))
+            }
+        }
+    }
+}
+
+/// The directory a client release is stored in.
+pub fn client_release_directory(work_directory: &Path, version: &str, platform: &str) -> PathBuf {
+    work_directory.join(CLIENT_RELEASES_DIRECTORY).join(version).join(platform)
+}
+
+/// Whether a version or platform from a request can be used as a directory name.
+fn is_release_component(component: &str) -> bool {
+    !component.is_empty() && !component.starts_with('.')
+        && component.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Lists the signed client releases in the working directory. Releases without a signature are skipped.
+fn find_client_releases(work_directory: &Path) -> Result<Vec<ClientRelease>, Error> {
+    let releases_directory = work_directory.join(CLIENT_RELEASES_DIRECTORY);
+    let mut releases = vec!();
+    if !releases_directory.exists() {
+        return Ok(releases);
+    }
+
+    for version_entry in fs::read_dir(&releases_directory)? {
+        let version_path = version_entry?.path();
+        let version = match version_path.file_name().and_then(|name| name.to_str()) {
+            Some(version) if version_path.is_dir() && is_release_component(version) => version.to_string(),
+            _ => continue,
+        };
+
+        for platform_entry in fs::read_dir(&version_path)? {
+            let platform_path = platform_entry?.path();
+            let platform = match platform_path.file_name().and_then(|name| name.to_str()) {
+                Some(platform) if platform_path.is_dir() && is_release_component(platform) => platform.to_string(),
+                _ => continue,
+            };
+
+            let signature_path = platform_path.join(format!("{}.sig", CLIENT_EXECUTABLE_NAME));
+            if !platform_path.join(CLIENT_EXECUTABLE_NAME).is_file() || !signature_path.is_file() {
+                debug!("Skipping the client release [{:?}], which has no executable or no signature.", platform_path);
+                continue;
+            }
+
+            releases.push(ClientRelease {
+                version: version.clone(),
+                platform,
+                signature: fs::read_to_string(&signature_path)?.trim().to_string(),
+            });
+        }
+    }
+
+    releases.sort_by(|a, b| compare_versions(&a.version, &b.version).then_with(|| a.platform.cmp(&b.platform)));
+    Ok(releases)
+}
+
+/// Handles requests for information about the server, including the client releases it hosts.
+fn info(request: &HttpRequest<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let client_releases = find_client_releases(&request.state().work_directory)?;
+    let current_client_version = client_releases.last().map(|release| release.version.clone());
+
+    Ok(HttpResponse::Ok().json(InfoResponse {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        current_client_version,
+        client_releases,
+        api_versions: API_VERSIONS.to_vec(),
+    }))
+}
+
+/// Handles client downloads of a release executable.
+fn client_executable(request: &HttpRequest<AppState>) -> Result<HttpResponse, actix_web::Error> {
+    let version = request.match_info().get("version").unwrap_or_default();
+    let platform = request.match_info().get("platform").unwrap_or_default();
+    if !is_release_component(version) || !is_release_component(platform) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let executable_path = client_release_directory(&request.state().work_directory, version, platform).join(CLIENT_EXECUTABLE_NAME);
+    match fs::read(&executable_path) {
+        Ok(executable) => Ok(HttpResponse::Ok().content_type("application/octet-stream").body(executable)),
+        Err(_) => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
 fn prepare_work_directory(work_directory: &PathBuf) -> Result<(), Error> {
     if !work_directory.exists() {
         return Err(format_err!("Working directory path does not exist: {:?}", work_directory));
@@ -170,6 +909,10 @@ fn prepare_work_directory(work_directory: &PathBuf) -> Result<(), Error> {
         }
     }
 
+    // Keep the history of every branch, so commits rewritten by their client still count as
+    // published by it (see `published_commits`).
+    Repository::open(&repo_path)?.config()?.set_bool("core.logAllRefUpdates", true)?;
+
     // TODO(john): Assert that the directory is either empty or contains only the required files.
 
     Ok(())
@@ -187,8 +930,30 @@ Result<impl Fn() -> App<AppState>, Error>
         })
             // enable logger
             .middleware(middleware::Logger::default())
+            .middleware(RequireAuthToken)
             .resource("/v1/conflicts_after_commit", |r| {
                 r.method(http::Method::POST).f(conflicts_after_commit)
+            })
+            .resource("/v1/unintegrated_changes", |r| {
+                r.method(http::Method::POST).f(unintegrated_changes)
+            })
+            .resource("/v1/rewrites", |r| {
+                r.method(http::Method::POST).f(rewrites)
+            })
+            .resource("/v1/delete_namespace", |r| {
+                r.method(http::Method::POST).f(delete_namespace)
+            })
+            .resource("/v1/claim_namespace", |r| {
+                r.method(http::Method::POST).f(claim_namespace)
+            })
+            .resource("/v1/project_lookup", |r| {
+                r.method(http::Method::POST).f(project_lookup)
+            })
+            .resource("/v1/info", |r| {
+                r.method(http::Method::GET).f(info)
+            })
+            .resource("/v1/clients/{version}/{platform}", |r| {
+                r.method(http::Method::GET).f(client_executable)
             });
     };
 
@@ -6,6 +6,7 @@ use uuid::Uuid;
 use hostname::get_hostname;
 use std::ops::Deref;
 use std::fmt;
+use std::cmp::Ordering;
 use failure::Error;
 use failure::ResultExt;
 use failure::format_err;
@@ -14,6 +15,20 @@ use serde_derive::{Deserialize, Serialize};
 
 pub const GLOBALGRAPH_REPO_NAME: &str = "globalgraph";
 
+/// The namespace of the references holding a snapshot of each client's staged changes.
+/// ie. refs/gg-index/<repo_uuid>, or refs/gg-index/<repo_uuid>+<worktree> for a linked worktree.
+pub const INDEX_REFS_NAMESPACE: &str = "refs/gg-index/";
+
+/// The namespace of the references holding a snapshot of each client's modified, unstaged lockable
+/// files. ie. refs/gg-worktree/<repo_uuid>, or refs/gg-worktree/<repo_uuid>+<worktree> for a linked
+/// worktree.
+pub const WORKTREE_REFS_NAMESPACE: &str = "refs/gg-worktree/";
+
+/// The namespace of the references recording commit rewrites (amends, rebases). Each reference is
+/// named after the original commit, and points to the commit that replaced it.
+/// ie. refs/gg-rewrites/<original sha>
+pub const REWRITE_REFS_NAMESPACE: &str = "refs/gg-rewrites/";
+
 // The full commit sha, as a string.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CommitSha(pub String);
@@ -86,7 +101,7 @@ impl fmt::Display for ReferencePath {
 }
 
 // The path format that git uses to represent a file in the working directory.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct GitPath(String);
 
 impl GitPath {
@@ -125,6 +140,9 @@ pub type HeadCommit = Option<CommitSha>;
 #[derive(Debug, PartialEq)]
 pub struct ClientSyncConfig {
     pub repo_uuid: String,
+    /// The name of the linked worktree (see `git worktree`) the client runs in, or None in the main
+    /// working tree. Worktrees share the branches of the repository, but each has its own snapshots.
+    pub worktree: Option<String>,
 }
 
 impl ClientSyncConfig {
@@ -132,6 +150,57 @@ impl ClientSyncConfig {
         let friendly_name = to_friendly_name(client_branch)?;
         Ok(ReferencePath(format!("refs/heads/{}/{}", &self.repo_uuid, friendly_name)))
     }
+
+    /// The prefix of the local references that record the last tip of each branch pushed to the
+    /// global graph. ie. refs/remotes/globalgraph/<repo_uuid>/
+    pub fn tracking_namespace(&self) -> String {
+        format!("refs/remotes/{}/{}/", GLOBALGRAPH_REPO_NAME, &self.repo_uuid)
+    }
+
+    /// Maps a local branch to the local reference that records its last synchronized tip.
+    pub fn map_branch_to_tracking(&self, client_branch: &ReferencePath) -> Result<ReferencePath, Error> {
+        let friendly_name = to_friendly_name(client_branch)?;
+        Ok(ReferencePath(format!("{}{}", self.tracking_namespace(), friendly_name)))
+    }
+
+    /// The name of this client's snapshot references in each snapshot namespace. Worktree names are
+    /// reduced to characters that are valid in any reference name.
+    fn snapshot_name(&self) -> String {
+        match self.worktree {
+            Some(ref worktree) => format!("{}{}{}", &self.repo_uuid, SNAPSHOT_WORKTREE_SEPARATOR, sanitize_worktree_name(worktree)),
+            None => self.repo_uuid.clone(),
+        }
+    }
+
+    /// The reference holding a snapshot of this client's staged changes, both locally and in the
+    /// global graph.
+    pub fn index_reference(&self) -> ReferencePath {
+        ReferencePath(format!("{}{}", INDEX_REFS_NAMESPACE, self.snapshot_name()))
+    }
+
+    /// The local reference recording the last index snapshot pushed to the global graph.
+    pub fn index_tracking_reference(&self) -> ReferencePath {
+        ReferencePath(format!("refs/remotes/{}/gg-index/{}", GLOBALGRAPH_REPO_NAME, self.snapshot_name()))
+    }
+
+    /// The reference holding a snapshot of this client's modified working tree files.
+    pub fn worktree_reference(&self) -> ReferencePath {
+        ReferencePath(format!("{}{}", WORKTREE_REFS_NAMESPACE, self.snapshot_name()))
+    }
+
+    /// The local reference recording the last working tree snapshot pushed to the global graph.
+    pub fn worktree_tracking_reference(&self) -> ReferencePath {
+        ReferencePath(format!("refs/remotes/{}/gg-worktree/{}", GLOBALGRAPH_REPO_NAME, self.snapshot_name()))
+    }
+
+    /// Maps a tracking reference (see `map_branch_to_tracking`) back to the local branch it tracks.
+    pub fn map_tracking_to_branch(&self, tracking_reference: &ReferencePath) -> Result<ReferencePath, Error> {
+        let namespace = self.tracking_namespace();
+        if !tracking_reference.starts_with(&namespace) || tracking_reference.len() == namespace.len() {
+            return Err(format_err!("Reference [{}] is not a Global Graph tracking reference for repo [{}].", tracking_reference, &self.repo_uuid));
+        }
+        Ok(ReferencePath(format!("refs/heads/{}", &tracking_reference[namespace.len()..])))
+    }
 }
 
 // Takes a global graph branch name and returns the information about where it came from.
@@ -140,7 +209,49 @@ pub fn map_branch_to_local(global_branch: &ReferencePath) -> Result<(ClientSyncC
     let captures = reg.captures(global_branch)
         .ok_or(format_err!("The branch [{}] is not a valid Global Graph branch name.", global_branch))?;
 
-    Ok((ClientSyncConfig { repo_uuid: captures["repo_uuid"].to_string() }, ReferencePath(format!("refs/heads/{}", &captures["branch_name"]))))
+    Ok((ClientSyncConfig { repo_uuid: captures["repo_uuid"].to_string(), worktree: None }, ReferencePath(format!("refs/heads/{}", &captures["branch_name"]))))
+}
+
+/// Separates the repository uuid from the worktree name in the snapshot references of linked
+/// worktrees. Repository uuids never contain it.
+const SNAPSHOT_WORKTREE_SEPARATOR: char = '+';
+
+/// Replaces the characters of a worktree name that aren't letters, digits, `-` or `_`.
+fn sanitize_worktree_name(worktree: &str) -> String {
+    worktree.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Takes a snapshot reference (ie. refs/gg-index/<repo_uuid>) and returns the uuid of the client
+/// that pushed it, or None if the reference is not in the given namespace. The snapshots of every
+/// worktree of a client map to the client.
+pub fn map_snapshot_to_client(namespace: &str, snapshot_reference: &ReferencePath) -> Option<String> {
+    if snapshot_reference.starts_with(namespace) && snapshot_reference.len() > namespace.len() {
+        let name = &snapshot_reference[namespace.len()..];
+        Some(name.split(SNAPSHOT_WORKTREE_SEPARATOR).next().unwrap_or(name).to_string())
+    } else {
+        None
+    }
+}
+
+const SNAPSHOT_BRANCH_TRAILER: &str = "Branch: ";
+
+/// The commit message of a snapshot of uncommitted work. Records the branch the work is based on,
+/// so the server can report it.
+pub fn snapshot_message(description: &str, branch: Option<&ReferencePath>) -> String {
+    match branch {
+        Some(branch) => format!("{}\n\n{}{}\n", description, SNAPSHOT_BRANCH_TRAILER, branch),
+        None => format!("{}\n", description),
+    }
+}
+
+/// Reads the branch recorded by `snapshot_message`.
+pub fn parse_snapshot_branch(message: &str) -> Option<ReferencePath> {
+    message.lines()
+        .filter(|line| line.starts_with(SNAPSHOT_BRANCH_TRAILER))
+        .map(|line| ReferencePath::new(&line[SNAPSHOT_BRANCH_TRAILER.len()..]))
+        .next()
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -195,6 +306,13 @@ pub struct ConflictsAfterCommitRequest {
     /// Either the head commit of the client repository, or None if the client repository has no
     /// valid HEAD. (ie. if it has just been initialized and has no content)
     pub repo_head_commit: HeadCommit,
+    /// The other parents the new commit will have, eg. the MERGE_HEAD when committing a merge.
+    #[serde(default)]
+    pub other_parents: Vec<CommitSha>,
+    /// Commits whose changes the new commit copies without becoming their descendant, eg. the
+    /// CHERRY_PICK_HEAD when committing a cherry-pick. Only these exact commits count as integrated.
+    #[serde(default)]
+    pub applied_commits: Vec<CommitSha>,
     pub repo_uuid: String,
 }
 
@@ -205,14 +323,225 @@ pub struct ConflictsAfterCommitResponse {
     pub conflicts: Vec<UnintegratedChange>,
 }
 
+/// A request for every change in the global graph that a client's head doesn't integrate, including
+/// the uncommitted work of other clients.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnintegratedChangesRequest {
+    /// Either the head commit of the client repository, or None if it has no valid HEAD.
+    pub repo_head_commit: HeadCommit,
+    pub repo_uuid: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnintegratedChangesResponse {
+    pub changes: Vec<UnintegratedChange>,
+}
+
+/// A commit that was replaced by another one, as reported by git's post-rewrite hook.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CommitRewrite {
+    pub original: CommitSha,
+    pub rewritten: CommitSha,
+}
+
+/// Records commit rewrites made in a client repository, so the original commits count as integrated
+/// by heads that contain their rewritten versions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordRewritesRequest {
+    pub repo_uuid: String,
+    /// The secret the client claimed its namespace with (see `ClaimNamespaceRequest`).
+    #[serde(default)]
+    pub namespace_secret: String,
+    pub rewrites: Vec<CommitRewrite>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordRewritesResponse {
+    /// The number of rewrites recorded. Rewrites to commits missing from the global graph are skipped.
+    pub recorded: usize,
+}
+
+/// Asks the server to forget a client repository, eg. when Global Graph is uninstalled from it. The
+/// branches and snapshots the client pushed are deleted from the global graph.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteNamespaceRequest {
+    pub repo_uuid: String,
+    /// The secret the client claimed its namespace with (see `ClaimNamespaceRequest`).
+    #[serde(default)]
+    pub namespace_secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteNamespaceResponse {
+    /// The number of references deleted from the global graph.
+    pub deleted: usize,
+}
+
+/// Claims a client repository's namespace with a secret only the client knows. Requests that change
+/// the namespace (eg. deleting it, or recording its rewrites) must then carry the same secret. A namespace is claimed by the
+/// first secret presented for it, either by this request or by one of those.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClaimNamespaceRequest {
+    pub repo_uuid: String,
+    pub namespace_secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClaimNamespaceResponse {}
+
+/// Asks the server whether it serves the project a repository was cloned from, eg. when hooks
+/// installed from a git template run in a new clone for the first time.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectLookupRequest {
+    /// The url of the repository's `origin` remote.
+    pub origin_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProjectLookupResponse {
+    /// The url of the global graph repository clones of the project push to, or None if the server
+    /// doesn't serve the project.
+    pub global_graph_url: Option<String>,
+}
+
+/// Whether two remote urls point at the same repository, ignoring a trailing slash or `.git`.
+pub fn same_remote_url(a: &str, b: &str) -> bool {
+    fn normalize(url: &str) -> &str {
+        url.trim().trim_end_matches('/').trim_end_matches(".git")
+    }
+    normalize(a) == normalize(b)
+}
+
+/// Where an unintegrated change lives in the other client's repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    /// The change is committed on a branch.
+    Committed,
+    /// The change is staged in the index, but not committed yet.
+    Staged,
+    /// The file is modified in the working tree, but not staged yet.
+    WorkingTree,
+    /// A kind of change added after this client was built. Clients still report the change, so
+    /// new kinds don't break older clients.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Default for ChangeKind {
+    fn default() -> ChangeKind {
+        ChangeKind::Committed
+    }
+}
+
 /// Represents a change on a different branch that is not integrated with the
 /// target branch. Also known as a 'conflict'.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UnintegratedChange {
     pub file: GitPath,
+    /// The commit containing the change. For uncommitted work, the snapshot commit.
     pub commit: CommitSha,
     pub branch: ReferencePath,
     pub repo_uuid: String,
+    #[serde(default)]
+    pub kind: ChangeKind,
+    /// The newest version of `commit`, if it was rewritten (eg. amended or rebased) since.
+    #[serde(default)]
+    pub rewritten_as: Option<CommitSha>,
+}
+
+/// Information about a running Global Graph server.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InfoResponse {
+    /// The version of the server.
+    pub server_version: String,
+    /// The newest client version the server hosts. Clients update to it unless they pin another
+    /// version. None if the server hosts no client releases.
+    pub current_client_version: Option<String>,
+    /// Every client binary the server hosts, downloadable from `v1/clients/<version>/<platform>`.
+    pub client_releases: Vec<ClientRelease>,
+    /// The versions of the query API the server supports, each served under `v<version>/`. Servers
+    /// from before the API was versioned only serve version 1.
+    #[serde(default = "legacy_api_versions")]
+    pub api_versions: Vec<u32>,
+}
+
+/// The versions of the query API this build supports, oldest first (see `InfoResponse::api_versions`).
+pub const API_VERSIONS: &[u32] = &[1];
+
+fn legacy_api_versions() -> Vec<u32> {
+    vec!(1)
+}
+
+/// A signed client executable hosted by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientRelease {
+    pub version: String,
+    /// The platform the executable runs on, as returned by `client_platform`.
+    pub platform: String,
+    /// The base64 encoded ed25519 signature of `client_release_message` for the executable.
+    pub signature: String,
+}
+
+/// The platform client executables are built for, eg. `linux-x86_64` or `windows-x86_64`.
+pub fn client_platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// The message a client release signature is made over. The version and platform are signed along
+/// with the executable, so a signed executable can't be served as another version or platform.
+pub fn client_release_message(version: &str, platform: &str, executable: &[u8]) -> Vec<u8> {
+    let mut message = format!("globalgraph-client-release;{};{};", version, platform).into_bytes();
+    message.extend_from_slice(executable);
+    message
+}
+
+/// The prefix of the version marker embedded in every client executable. The marker is the prefix,
+/// followed by the version and a ';'.
+pub const CLIENT_VERSION_MARKER_PREFIX: &str = "globalgraph-client-version=";
+
+/// Reads the client version embedded in an executable, or None if it has none.
+pub fn find_client_version(executable: &[u8]) -> Option<String> {
+    let prefix = CLIENT_VERSION_MARKER_PREFIX.as_bytes();
+
+    // The prefix can also occur on its own in the executable, so look for an occurrence followed by
+    // a version and the terminating ';'.
+    let mut start = 0;
+    while let Some(offset) = executable[start..].windows(prefix.len()).position(|window| window == prefix) {
+        let version_start = start + offset + prefix.len();
+        let version: Vec<u8> = executable[version_start..].iter()
+            .take(64)
+            .take_while(|&&byte| byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-' || byte == b'+')
+            .cloned()
+            .collect();
+
+        if !version.is_empty() && executable.get(version_start + version.len()) == Some(&b';') {
+            return Some(String::from_utf8_lossy(&version).into_owned());
+        }
+        start = version_start;
+    }
+
+    None
+}
+
+/// Compares two versions like `0.2.10` component by component, numerically where both components
+/// are numbers.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_components = a.split(|c| c == '.' || c == '-');
+    let mut b_components = b.split(|c| c == '.' || c == '-');
+    loop {
+        let ordering = match (a_components.next(), b_components.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
 }
 
 pub trait RepositoryExtensions {
@@ -244,11 +573,11 @@ mod test {
     fn mapping_global_branch_to_local() -> Result<(), Error> {
         assert_eq!(
             map_branch_to_local(&ReferencePath::new("refs/heads/john_desktopmachine_abcdef/mybranch"))?,
-            (ClientSyncConfig { repo_uuid: "john_desktopmachine_abcdef".into() }, ReferencePath::new("refs/heads/mybranch")));
+            (ClientSyncConfig { repo_uuid: "john_desktopmachine_abcdef".into(), worktree: None }, ReferencePath::new("refs/heads/mybranch")));
 
         assert_eq!(
             map_branch_to_local(&ReferencePath::new("refs/heads/john_desktopmachine_abcdef/mynamespace/mybranch"))?,
-            (ClientSyncConfig { repo_uuid: "john_desktopmachine_abcdef".into() }, ReferencePath::new("refs/heads/mynamespace/mybranch")));
+            (ClientSyncConfig { repo_uuid: "john_desktopmachine_abcdef".into(), worktree: None }, ReferencePath::new("refs/heads/mynamespace/mybranch")));
 
         let result: Result<_, _> = map_branch_to_local(&ReferencePath::new("refs/heads/mybranch"));
         assert!(result.is_err());
@@ -262,7 +591,8 @@ mod test {
     #[test]
     fn mapping_local_branch_to_global() -> Result<(), Error> {
         let client = ClientSyncConfig {
-            repo_uuid: "john_desktopmachine_abcdef".into()
+            repo_uuid: "john_desktopmachine_abcdef".into(),
+            worktree: None,
         };
 
         assert_eq!(
@@ -282,6 +612,61 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn mapping_tracking_references() -> Result<(), Error> {
+        let client = ClientSyncConfig {
+            repo_uuid: "john_desktopmachine_abcdef".into(),
+            worktree: None,
+        };
+
+        assert_eq!(
+            client.map_branch_to_tracking(&ReferencePath::new("refs/heads/mynamespace/mybranch"))?,
+            ReferencePath::new("refs/remotes/globalgraph/john_desktopmachine_abcdef/mynamespace/mybranch"));
+
+        assert_eq!(
+            client.map_tracking_to_branch(&ReferencePath::new("refs/remotes/globalgraph/john_desktopmachine_abcdef/mynamespace/mybranch"))?,
+            ReferencePath::new("refs/heads/mynamespace/mybranch"));
+
+        let result: Result<_, _> = client.map_tracking_to_branch(&ReferencePath::new("refs/remotes/globalgraph/other_desktopmachine_abcdef/mybranch"));
+        assert!(result.is_err());
+
+        let result: Result<_, _> = client.map_tracking_to_branch(&ReferencePath::new("refs/remotes/globalgraph/john_desktopmachine_abcdef/"));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_references_and_messages() -> Result<(), Error> {
+        let client = ClientSyncConfig {
+            repo_uuid: "john_desktopmachine_abcdef".into(),
+            worktree: None,
+        };
+
+        assert_eq!(
+            map_snapshot_to_client(INDEX_REFS_NAMESPACE, &client.index_reference()),
+            Some("john_desktopmachine_abcdef".to_string()));
+        assert_eq!(map_snapshot_to_client(INDEX_REFS_NAMESPACE, &ReferencePath::new("refs/gg-index/")), None);
+        assert_eq!(map_snapshot_to_client(INDEX_REFS_NAMESPACE, &ReferencePath::new("refs/heads/master")), None);
+        assert_eq!(map_snapshot_to_client(INDEX_REFS_NAMESPACE, &client.worktree_reference()), None);
+
+        let linked = ClientSyncConfig {
+            repo_uuid: "john_desktopmachine_abcdef".into(),
+            worktree: Some("feature x".into()),
+        };
+        assert_eq!(linked.index_reference(), ReferencePath::new("refs/gg-index/john_desktopmachine_abcdef+feature_x"));
+        assert_eq!(linked.worktree_tracking_reference(), ReferencePath::new("refs/remotes/globalgraph/gg-worktree/john_desktopmachine_abcdef+feature_x"));
+        assert_eq!(
+            map_snapshot_to_client(INDEX_REFS_NAMESPACE, &linked.index_reference()),
+            Some("john_desktopmachine_abcdef".to_string()));
+
+        let branch = ReferencePath::new("refs/heads/mynamespace/mybranch");
+        assert_eq!(parse_snapshot_branch(&snapshot_message("Staged changes.", Some(&branch))), Some(branch));
+        assert_eq!(parse_snapshot_branch(&snapshot_message("Staged changes.", None)), None);
+
+        Ok(())
+    }
+
     #[test]
     fn break_repo_uuid_tests() -> Result<(), Error> {
         assert!(break_repo_uuid("xx_testusera_desktop_29d519f0").is_err());
@@ -303,7 +688,34 @@ mod test {
 
         Ok(())
     }
-}
 
+    #[test]
+    fn comparing_versions() {
+        assert_eq!(compare_versions("0.1.0", "0.1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("0.2.0", "0.10.0"), Ordering::Less);
+        assert_eq!(compare_versions("1.0.0", "0.9.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+    }
 
+    #[test]
+    fn finding_client_version() {
+        assert_eq!(find_client_version(b"\x00globalgraph-client-version=0.2.1;\x00"), Some("0.2.1".to_string()));
+        assert_eq!(find_client_version(b"globalgraph-client-version=globalgraph-client-version=1.0.0;"), Some("1.0.0".to_string()));
+        assert_eq!(find_client_version(b"globalgraph-client-version=0.2.1"), None);
+        assert_eq!(find_client_version(b"no marker"), None);
+    }
+
+    #[test]
+    fn comparing_remote_urls() {
+        assert!(same_remote_url("git@server.com:team/project.git", "git@server.com:team/project"));
+        assert!(same_remote_url("https://server.com/team/project/", "https://server.com/team/project.git"));
+        assert!(!same_remote_url("https://server.com/team/project", "https://server.com/team/other"));
+    }
 
+    #[test]
+    fn deserializing_unknown_change_kinds() -> Result<(), Error> {
+        assert_eq!(serde_json::from_str::<ChangeKind>("\"WorkingTree\"")?, ChangeKind::WorkingTree);
+        assert_eq!(serde_json::from_str::<ChangeKind>("\"AddedInAFutureVersion\"")?, ChangeKind::Unknown);
+        Ok(())
+    }
+}
@@ -5,11 +5,17 @@ use crate::http::StatusCode;
 use actix_web::{HttpMessage};
 use actix_web::test::TestServer;
 use std::str;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
 use json::JsonValue;
 use serde_json::value::Value;
 use shared::GitPath;
 use shared::ReferencePath;
 use shared::CommitSha;
+use shared::ChangeKind;
+use client::doctor::CheckStatus;
+use client::query_client::QueryClient;
 use json::object;
 
 use failure::Error;
@@ -33,6 +39,263 @@ fn single_push() -> Result<(), Error> {
     })
 }
 
+/// Only branches that moved should be pushed, and branches deleted locally should be removed from the
+/// client's namespace in the global graph on the next synchronization.
+#[test]
+fn synchronize_changed_branches_only() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        git_cmd(harness.local_repo_a, &["branch", "feature"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+
+        let uuid = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_ok());
+        assert!(harness.local_repo_a.find_reference(&format!("refs/remotes/globalgraph/{}/feature", uuid)).is_ok());
+
+        git_cmd(harness.local_repo_a, &["branch", "-D", "feature"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./fileb.bin"), "new text b!")])?;
+
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_err());
+        assert!(harness.local_repo_a.find_reference(&format!("refs/remotes/globalgraph/{}/feature", uuid)).is_err());
+        assert_eq!(
+            harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target(),
+            harness.local_repo_a.head()?.target());
+
+        Ok(())
+    })
+}
+
+/// All branches are sent in a single atomic push: if the global graph rejects one update, none of them are applied,
+/// and all are retried on the next synchronization.
+#[test]
+fn synchronize_atomically() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        git_cmd(harness.local_repo_a, &["branch", "feature"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        let uuid = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        let synchronized = harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target();
+
+        harness.global_graph.config()?.set_bool("receive.denyDeletes", true)?;
+        git_cmd(harness.local_repo_a, &["branch", "-D", "feature"])?;
+        // The pre-commit hook would refuse the commit, as it can't synchronize.
+        fs::write(harness.local_repo_a.workdir().unwrap().join("fileb.bin"), "new text b!")?;
+        git_cmd(harness.local_repo_a, &["add", "fileb.bin"])?;
+        git_cmd(harness.local_repo_a, &["commit", "--no-verify", "-m", "Commit while the global graph denies deletions."])?;
+
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_ok());
+        assert_eq!(harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target(), synchronized);
+
+        // The failed synchronization was left to the sync agent, which retries it once deletions are allowed.
+        harness.global_graph.config()?.set_bool("receive.denyDeletes", false)?;
+        let queue = client::sync_queue::SyncQueue::open(harness.local_repo_a)?;
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while queue.status()?.queue_depth > 0 {
+            assert!(Instant::now() < deadline, "The sync agent didn't retry the synchronization.");
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_err());
+        assert_eq!(
+            harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target(),
+            harness.local_repo_a.head()?.target());
+
+        Ok(())
+    })
+}
+
+/// With the default background synchronization, hooks return without pushing and leave the queue to the detached sync
+/// agent, which drains it to the global graph and releases its lock. Requests queued while it runs are drained too.
+#[test]
+fn background_sync_agent_drains_queue() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        git_cmd(harness.local_repo_a, &["config", "--unset", "globalgraph.backgroundsync"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        git_cmd(harness.local_repo_a, &["branch", "feature"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./fileb.bin"), "new text b!")])?;
+
+        let queue = client::sync_queue::SyncQueue::open(harness.local_repo_a)?;
+        let deadline = Instant::now() + Duration::from_secs(60);
+        loop {
+            let status = queue.status()?;
+            if status.queue_depth == 0 && !status.agent_running {
+                break;
+            }
+            assert!(Instant::now() < deadline, "The sync agent didn't drain the queue.");
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let uuid = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_ok());
+        assert_eq!(
+            harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target(),
+            harness.local_repo_a.head()?.target());
+
+        let state = queue.state()?;
+        assert!(state.last_success.is_some());
+        assert_eq!(state.consecutive_failures, 0);
+
+        Ok(())
+    })
+}
+
+/// The committed `.globalgraph.toml` filters the synchronized branches and adds lockable files, and the local git
+/// config overrides it.
+#[test]
+fn team_config_filters_branches() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let team_config = "lockable = [\"Content\"]\n\n[branches]\nexclude = [\"wip/*\"]\n";
+        git_cmd(harness.local_repo_a, &["branch", "wip/experiment"])?;
+        git_cmd(harness.local_repo_a, &["branch", "feature"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./.globalgraph.toml"), team_config)])?;
+
+        let uuid = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_ok());
+        assert!(harness.global_graph.find_branch(&format!("{}/wip/experiment", uuid), BranchType::Local).is_err());
+
+        assert!(client::is_lockable(harness.local_repo_a, &GitPath::new("Content/level.map"))?);
+        assert!(!client::is_lockable(harness.local_repo_a, &GitPath::new("Readme.md"))?);
+
+        // Only synchronizing master leaves feature out, which removes it from the global graph.
+        git_cmd(harness.local_repo_a, &["config", "globalgraph.branches", "master"])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local).is_err());
+        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local).is_ok());
+
+        Ok(())
+    })
+}
+
+/// Branches that move without a commit should still be synchronized, by the reference-transaction hook.
+#[test]
+fn synchronize_after_reset() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./fileb.bin"), "new text b!")])?;
+        let uuid = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+
+        // The commit was already queued by the reference-transaction hook, so post-commit doesn't queue it again.
+        let head = harness.local_repo_a.head()?.target().unwrap();
+        assert!(client::sync_queue::SyncQueue::open(harness.local_repo_a)?.is_tip_queued(&head.to_string())?);
+
+        git_cmd(harness.local_repo_a, &["reset", "--hard", "HEAD~1"])?;
+        assert_eq!(
+            harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local)?.get().target(),
+            harness.local_repo_a.head()?.target());
+
+        git_cmd(harness.local_repo_a, &["branch", "-f", "feature", "HEAD~1"])?;
+        assert_eq!(
+            harness.global_graph.find_branch(&format!("{}/feature", uuid), BranchType::Local)?.get().target(),
+            Some(harness.local_repo_a.revparse_single("HEAD~1")?.id()));
+
+        Ok(())
+    })
+}
+
+/// Commits replaced by an amend should count as integrated by heads containing the amended commit, and conflicts
+/// should point to the newest version of a rewritten commit.
+#[test]
+fn rewritten_commits_are_integrated() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        let original = harness.local_repo_a.head()?.peel_to_commit()?.id();
+
+        // Keep the original commit around on another branch, then replace it.
+        git_cmd(harness.local_repo_a, &["branch", "original"])?;
+        git_cmd(harness.local_repo_a, &["commit", "--amend", "-m", "Amend the change to filea.bin."])?;
+        let amended = harness.local_repo_a.head()?.peel_to_commit()?.id();
+        assert!(harness.global_graph.find_reference(&format!("{}{}", shared::REWRITE_REFS_NAMESPACE, original)).is_ok());
+
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        let repo_b = harness.local_repo_b;
+        let uuid_b = client::get_or_create_client_uuid(repo_b)?;
+        let request = || -> Result<shared::ConflictsAfterCommitRequest, Error> {
+            Ok(shared::ConflictsAfterCommitRequest {
+                repo_uuid: uuid_b.clone(),
+                files: vec![GitPath::new("filea.bin")],
+                repo_head_commit: Some(CommitSha::new(&repo_b.head()?.peel_to_commit()?.id().to_string())),
+                other_parents: vec!(),
+                applied_commits: vec!(),
+            })
+        };
+
+        // Repo b integrates neither version, so both branches conflict. The original points to its replacement.
+        let response = make_conflicts_after_commit_request(harness.server, &request()?);
+        let conflicts = response["conflicts"].as_array().unwrap();
+        assert_eq!(conflicts.len(), 2);
+        let original_conflict = conflicts.iter().find(|conflict| conflict["commit"] == original.to_string()).unwrap();
+        assert_eq!(original_conflict["rewritten_as"], amended.to_string());
+        assert_eq!(original_conflict["repo_uuid"], uuid_a);
+
+        // Once repo b contains the amended commit, the original is integrated too.
+        git_cmd(harness.local_repo_a, &["push", "origin", "master"])?;
+        git_cmd(repo_b, &["pull", "origin", "master"])?;
+        let response = make_conflicts_after_commit_request(harness.server, &request()?);
+        assert_eq!(
+            response,
+            serde_json::to_value(shared::ConflictsAfterCommitResponse {
+                conflicts: vec!()
+            })?);
+
+        Ok(())
+    })
+}
+
+/// The global graph only records rewrites of commits the requesting client published, into commits it has.
+#[test]
+fn rewrites_of_unpublished_commits_are_rejected() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        let original = harness.local_repo_a.head()?.peel_to_commit()?.id();
+
+        // The amend force pushes master away from the original commit, which still counts as published by repo a.
+        git_cmd(harness.local_repo_a, &["commit", "--amend", "-m", "Amend the change to filea.bin."])?;
+        let amended = harness.local_repo_a.head()?.peel_to_commit()?.id();
+        assert!(harness.global_graph.find_reference(&format!("{}{}", shared::REWRITE_REFS_NAMESPACE, original)).is_ok());
+
+        // Repo b can't claim repo a's commits were rewritten: neither as repo a, whose secret it doesn't know, nor as itself.
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./fileb.bin"), "new text b!")])?;
+        let commit_b = harness.local_repo_b.head()?.peel_to_commit()?.id();
+        let record = |repo_uuid: String, original: git2::Oid, rewritten: git2::Oid| {
+            QueryClient::for_repository(harness.local_repo_b)?.record_rewrites(&shared::RecordRewritesRequest {
+                repo_uuid,
+                namespace_secret: client::get_or_create_namespace_secret(harness.local_repo_b)?,
+                rewrites: vec![shared::CommitRewrite {
+                    original: CommitSha::new(&original.to_string()),
+                    rewritten: CommitSha::new(&rewritten.to_string()),
+                }],
+            })
+        };
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(record(uuid_a, amended, commit_b).is_err());
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        assert_eq!(record(uuid_b.clone(), amended, commit_b)?.recorded, 0);
+        assert!(harness.global_graph.find_reference(&format!("{}{}", shared::REWRITE_REFS_NAMESPACE, amended)).is_err());
+
+        // Nor rewrite its own commits into commits the global graph doesn't have.
+        let unknown = git2::Oid::from_str("1111111111111111111111111111111111111111")?;
+        assert_eq!(record(uuid_b.clone(), commit_b, unknown)?.recorded, 0);
+        assert!(harness.global_graph.find_reference(&format!("{}{}", shared::REWRITE_REFS_NAMESPACE, commit_b)).is_err());
+
+        // Its own published commits can be rewritten.
+        assert_eq!(record(uuid_b, commit_b, amended)?.recorded, 1);
+
+        Ok(())
+    })
+}
+
 /// The local repo b is cloned from origin. On a successful clone, when the global graph is configured,
 /// we should have synchronized and have a repouuid for local repo b.
 #[test]
@@ -40,45 +303,448 @@ fn synchronize_after_clone() -> Result<(), Error> {
     init_logging();
 
     create_integration_test(|harness| {
-        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./testfile.bin"), "new test")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./testfile.bin"), "new test")])?;
+
+        let uuid = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local).is_ok());
+
+        Ok(())
+    })
+}
+
+/// The doctor should find a freshly synchronized repository correctly configured, and notice branches that
+/// haven't been synchronized yet.
+#[test]
+fn doctor_checks_configuration() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+
+        let status_of = |diagnostics: &[client::doctor::Diagnostic], check: &str| {
+            diagnostics.iter().find(|diagnostic| diagnostic.check == check).map(|diagnostic| diagnostic.status)
+        };
+
+        let diagnostics = client::doctor::run_diagnostics(harness.local_repo_a);
+        assert_eq!(status_of(&diagnostics, "user.name"), Some(CheckStatus::Passed));
+        assert_eq!(status_of(&diagnostics, "globalgraph.repouuid"), Some(CheckStatus::Passed));
+        assert_eq!(status_of(&diagnostics, "query server"), Some(CheckStatus::Passed));
+        assert_eq!(status_of(&diagnostics, "globalgraph remote"), Some(CheckStatus::Passed));
+        assert_eq!(status_of(&diagnostics, "global graph namespace"), Some(CheckStatus::Passed));
+
+        // A branch created without running any hook (libgit2 doesn't run them) isn't synchronized until the next
+        // hook runs.
+        let head = harness.local_repo_a.head()?.peel_to_commit()?;
+        harness.local_repo_a.branch("unsynchronized", &head, false)?;
+        let diagnostics = client::doctor::run_diagnostics(harness.local_repo_a);
+        assert_eq!(status_of(&diagnostics, "global graph namespace"), Some(CheckStatus::Warning));
+
+        return Ok(());
+    })
+}
+
+/// The version marker survives linking into the built client executable, so installed copies report their version.
+#[test]
+fn client_executable_has_version_marker() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let executable = client::client_executable_path(harness.local_repo_a);
+        assert_eq!(shared::find_client_version(&fs::read(&executable)?), Some(client::VERSION.to_string()));
+        assert_eq!(client::doctor::installed_binary_version(&executable)?, Some(client::VERSION.to_string()));
+
+        return Ok(());
+    })
+}
+
+/// The edit watcher checks a lockable file on its first edit, and again on the first edit after the file was
+/// committed or reverted.
+#[test]
+fn watcher_checks_first_edits() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let workdir = harness.local_repo_a.workdir().unwrap().to_owned();
+        let filea = GitPath::new("filea.bin");
+        let mut edits = client::watcher::EditTracker::new();
+
+        fs::write(workdir.join("filea.bin"), "editing a!")?;
+        assert!(edits.is_first_edit(harness.local_repo_a, &filea)?);
+        fs::write(workdir.join("filea.bin"), "still editing a!")?;
+        assert!(!edits.is_first_edit(harness.local_repo_a, &filea)?);
+
+        fs::write(workdir.join("Readme.md"), "Not lockable")?;
+        assert!(!edits.is_first_edit(harness.local_repo_a, &GitPath::new("Readme.md"))?);
+
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "committed a!")])?;
+        assert!(!edits.is_first_edit(harness.local_repo_a, &filea)?);
+        fs::write(workdir.join("filea.bin"), "editing a again!")?;
+        assert!(edits.is_first_edit(harness.local_repo_a, &filea)?);
+
+        fs::write(workdir.join("filea.bin"), "committed a!")?;
+        assert!(!edits.is_first_edit(harness.local_repo_a, &filea)?);
+        fs::write(workdir.join("filea.bin"), "editing a once more!")?;
+        assert!(edits.is_first_edit(harness.local_repo_a, &filea)?);
+
+        Ok(())
+    })
+}
+
+/// The conflicts server should return the correct response when querying about a conflicting file.
+#[test]
+fn has_conflict_standard() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text a!")])?;
+
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+
+        let request = shared::ConflictsAfterCommitRequest {
+            repo_uuid: uuid_b,
+            files: vec![GitPath::new("filea.bin")],
+            repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+            other_parents: vec!(),
+            applied_commits: vec!(),
+        };
+
+        // When attempting to commit a change to filea.bin on repo b, there should be a conflict at the head of
+        // repo A.
+        let response = make_conflicts_after_commit_request(harness.server, &request);
+        let repo_a_head = CommitSha::new(&harness.local_repo_a.head()?.peel_to_commit()?.id().to_string());
+        assert_eq!(
+            response,
+            serde_json::to_value(shared::ConflictsAfterCommitResponse {
+                conflicts: vec!(shared::UnintegratedChange {
+                    file: GitPath::new("filea.bin"),
+                    commit: repo_a_head,
+                    branch: ReferencePath("refs/heads/master".into()),
+                    repo_uuid: uuid_a,
+                    kind: ChangeKind::Committed,
+                    rewritten_as: None,
+                })
+            })?);
+
+        return Ok(());
+    })
+}
+
+/// Listing unintegrated changes should return every file another repository changed that the head doesn't integrate.
+#[test]
+fn list_unintegrated_changes() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text b!")])?;
+
+        // Repo B's own work on other branches and in its index isn't listed.
+        git_cmd(harness.local_repo_b, &["checkout", "-b", "feature"])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_feature.bin"), "feature text b!")])?;
+        git_cmd(harness.local_repo_b, &["checkout", "master"])?;
+        fs::write(harness.local_repo_b.workdir().unwrap().join("file_staged.bin"), "staged text b!")?;
+        git_cmd(harness.local_repo_b, &["add", "file_staged.bin"])?;
+        client::synchronize_local_repository(harness.local_repo_b.workdir().unwrap())?;
+
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/feature", uuid_b), BranchType::Local).is_ok());
+        assert!(harness.global_graph.find_reference(&format!("refs/gg-index/{}", uuid_b)).is_ok());
+
+        let request = shared::UnintegratedChangesRequest {
+            repo_uuid: uuid_b,
+            repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+        };
+
+        // Repo B doesn't integrate the change to filea.bin committed on repo A.
+        let response = make_unintegrated_changes_request(harness.server, &request);
+        let repo_a_head = CommitSha::new(&harness.local_repo_a.head()?.peel_to_commit()?.id().to_string());
+        assert_eq!(
+            response,
+            serde_json::to_value(shared::UnintegratedChangesResponse {
+                changes: vec!(shared::UnintegratedChange {
+                    file: GitPath::new("filea.bin"),
+                    commit: repo_a_head,
+                    branch: ReferencePath("refs/heads/master".into()),
+                    repo_uuid: uuid_a,
+                    kind: ChangeKind::Committed,
+                    rewritten_as: None,
+                })
+            })?);
+
+        return Ok(());
+    })
+}
+
+/// A head the global graph doesn't have (eg. a detached commit, or one whose synchronization is still queued)
+/// integrates nothing, so every change of other repositories is listed.
+#[test]
+fn list_unintegrated_changes_unsynchronized_head() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+
+        // A commit no branch points to is never synchronized.
+        let head = harness.local_repo_b.head()?.peel_to_commit()?;
+        let signature = git2::Signature::now("Test", "test@example.com")?;
+        let unsynchronized = harness.local_repo_b.commit(None, &signature, &signature, "Not synchronized.", &head.tree()?, &[&head])?;
+        git_cmd(harness.local_repo_b, &["checkout", "--detach", &unsynchronized.to_string()])?;
+        assert!(harness.global_graph.find_commit(unsynchronized).is_err());
+
+        let request = shared::UnintegratedChangesRequest {
+            repo_uuid: harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?,
+            repo_head_commit: Some(CommitSha::new(&unsynchronized.to_string())),
+        };
+        let response = make_unintegrated_changes_request(harness.server, &request);
+        assert_eq!(response["changes"].as_array().unwrap().len(), 1);
+        assert_eq!(response["changes"][0]["file"], "filea.bin");
+
+        // `git globalgraph status` lists them too.
+        let changes = client::query::list_unintegrated_changes(harness.local_repo_b, client::query::head_commit(harness.local_repo_b)?)?;
+        assert_eq!(changes.len(), 1);
+
+        return Ok(());
+    })
+}
+
+/// Deleting a repository's namespace removes its branches from the global graph, so its changes stop conflicting.
+/// Only the clone that claimed the namespace can delete it.
+#[test]
+fn delete_namespace() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid_a), BranchType::Local).is_ok());
+        client::query::claim_namespace(harness.local_repo_a)?;
+
+        // Repo b knows repo a's uuid, but not its secret.
+        let forged_request = shared::DeleteNamespaceRequest {
+            repo_uuid: uuid_a.clone(),
+            namespace_secret: client::get_or_create_namespace_secret(harness.local_repo_b)?,
+        };
+        assert!(QueryClient::for_repository(harness.local_repo_b)?.delete_namespace(&forged_request).is_err());
+        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid_a), BranchType::Local).is_ok());
+
+        let response = make_delete_namespace_request(harness.server, &shared::DeleteNamespaceRequest {
+            repo_uuid: uuid_a.clone(),
+            namespace_secret: client::get_or_create_namespace_secret(harness.local_repo_a)?,
+        });
+        assert!(response["deleted"].as_u64().unwrap() >= 1);
+        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid_a), BranchType::Local).is_err());
+
+        // Repo b can change filea.bin now.
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./filea.bin"), "new text b!")])?;
+
+        return Ok(());
+    })
+}
+
+/// Clones with hooks from a git template look up the project they were cloned from, and only the
+/// registered projects are served.
+#[test]
+fn project_lookup() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let request = shared::ProjectLookupRequest { origin_url: "git@server.com:team/project.git".to_string() };
+        assert!(make_project_lookup_request(harness.server, &request)["global_graph_url"].is_null());
+
+        fs::write(harness.server_work_directory.join(server::PROJECTS_FILE), r#"{
+            "global_graph_url": "git@server.com:globalgraph.git",
+            "origins": ["git@server.com:team/project"]
+        }"#)?;
+        assert_eq!(make_project_lookup_request(harness.server, &request)["global_graph_url"], "git@server.com:globalgraph.git");
+
+        let other = shared::ProjectLookupRequest { origin_url: "git@server.com:team/other.git".to_string() };
+        assert!(make_project_lookup_request(harness.server, &other)["global_graph_url"].is_null());
+
+        return Ok(());
+    })
+}
+
+/// A clone with hooks from a git template doesn't look its project up again for a while after a known server
+/// couldn't be reached, nor is it marked unregistered.
+#[test]
+fn unreachable_known_server_is_retried_later() -> Result<(), Error> {
+    init_logging();
+
+    let directory = tempfile::Builder::new().prefix("template_clone").tempdir()?;
+    let repo = git2::Repository::init(directory.path())?;
+    repo.remote("origin", "git@server.com:team/project.git")?;
+    repo.config()?.set_str(client::template::KNOWN_SERVERS_CONFIG, "http://127.0.0.1:1/")?;
+
+    assert!(!client::template::ensure_configured(&repo));
+    let retry_after = repo.config()?.get_i64(client::template::LOOKUP_RETRY_AFTER_CONFIG)?;
+    assert!(repo.config()?.get_bool(client::template::UNREGISTERED_CONFIG).is_err());
+
+    // Until then, the known servers aren't asked again: another failure would move the retry time.
+    repo.config()?.set_i64(client::template::LOOKUP_RETRY_AFTER_CONFIG, retry_after + 1000)?;
+    assert!(!client::template::ensure_configured(&repo));
+    assert_eq!(repo.config()?.get_i64(client::template::LOOKUP_RETRY_AFTER_CONFIG)?, retry_after + 1000);
+
+    // Transactions that aren't committed do nothing, so they don't look the project up at all.
+    assert!(client::hooks::does_nothing("reference-transaction", &["prepared".to_string()]));
+    assert!(!client::hooks::does_nothing("reference-transaction", &["committed".to_string()]));
+    assert!(!client::hooks::does_nothing("post-commit", &[]));
+
+    Ok(())
+}
+
+/// A clone with hooks from a git template whose `.globalgraph.toml` points at a server that isn't known isn't
+/// configured from that file, and doesn't synchronize anything.
+#[test]
+fn team_config_of_unknown_server_is_ignored() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let server_url = harness.local_repo_a.config()?.get_string("globalgraph.server")?;
+        let global_graph_url = harness.local_repo_a.find_remote(shared::GLOBALGRAPH_REPO_NAME)?.url().unwrap().to_string();
+        let references = harness.global_graph.references()?.count();
+
+        let directory = tempfile::Builder::new().prefix("template_clone").tempdir()?;
+        let clone = git2::Repository::clone(&harness.origin_repo.path().to_string_lossy(), directory.path())?;
+        install_all_hooks(&clone)?;
+        git_cmd(&clone, &["config", "user.name", "Test User C"])?;
+        git_cmd(&clone, &["config", client::template::KNOWN_SERVERS_CONFIG, "http://127.0.0.1:1/"])?;
+
+        let team_config = format!("server = \"{}\"\nremote = \"{}\"\n", server_url, global_graph_url);
+        change_and_commit(&clone, &[(&PathBuf::from("./.globalgraph.toml"), team_config.as_str())])?;
+        change_and_commit(&clone, &[(&PathBuf::from("./filea.bin"), "new text c!")])?;
+
+        assert!(clone.config()?.get_string("globalGraph.installed").is_err());
+        assert!(clone.config()?.get_string("globalgraph.repouuid").is_err());
+        assert_eq!(harness.global_graph.references()?.count(), references);
+
+        return Ok(());
+    })
+}
+
+/// The query client negotiates the API version with the server, and authenticates to servers that
+/// require a token.
+#[test]
+fn query_client() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text b!")])?;
 
-        let uuid = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
-        assert!(harness.global_graph.find_branch(&format!("{}/master", uuid), BranchType::Local).is_ok());
+        let mut query_client = QueryClient::for_repository(harness.local_repo_b)?;
+        let info = query_client.negotiate_version()?;
+        assert!(info.api_versions.contains(&query_client.api_version()));
 
-        Ok(())
+        let request = shared::UnintegratedChangesRequest {
+            repo_uuid: harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?,
+            repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+        };
+        let changes = query_client.unintegrated_changes(&request)?.changes;
+        assert_eq!(changes.into_iter().map(|change| change.file).collect::<Vec<_>>(), vec!(GitPath::new("filea.bin")));
+
+        // Once the server requires a token, only clients with a valid one are answered.
+        fs::write(harness.server_work_directory.join(server::AUTH_TOKENS_FILE), "# Asset pipeline\nsecret-token\n")?;
+        assert!(query_client.unintegrated_changes(&request).is_err());
+        assert!(query_client.with_auth_token("wrong-token").unintegrated_changes(&request).is_err());
+
+        git_cmd(harness.local_repo_b, &["config", client::query_client::AUTH_TOKEN_CONFIG, "secret-token"])?;
+        assert_eq!(QueryClient::for_repository(harness.local_repo_b)?.unintegrated_changes(&request)?.changes.len(), 1);
+
+        return Ok(());
     })
 }
 
-/// The conflicts server should return the correct response when querying about a conflicting file.
+/// Lockable files staged in another clone count as in-flight work, and conflict until they are committed
+/// and integrated.
 #[test]
-fn has_conflict_standard() -> Result<(), Error> {
+fn has_conflict_staged() -> Result<(), Error> {
     init_logging();
 
     create_integration_test(|harness| {
-        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "new text a!")])?;
-        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text a!")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text b!")])?;
+
+        // Stage a change to filea.bin in repo A without committing it.
+        fs::write(harness.local_repo_a.workdir().unwrap().join("filea.bin"), "staged text a!")?;
+        git_cmd(harness.local_repo_a, &["add", "filea.bin"])?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
 
-        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
         let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        let snapshot = harness.global_graph.find_reference(&format!("refs/gg-index/{}", uuid_a))?.peel_to_commit()?;
 
         let request = shared::ConflictsAfterCommitRequest {
             repo_uuid: uuid_b,
             files: vec![GitPath::new("filea.bin")],
             repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+            other_parents: vec!(),
+            applied_commits: vec!(),
         };
+        let response = make_conflicts_after_commit_request(harness.server, &request);
+        assert_eq!(
+            response,
+            serde_json::to_value(shared::ConflictsAfterCommitResponse {
+                conflicts: vec!(shared::UnintegratedChange {
+                    file: GitPath::new("filea.bin"),
+                    commit: CommitSha::new(&snapshot.id().to_string()),
+                    branch: ReferencePath("refs/heads/master".into()),
+                    repo_uuid: uuid_a.clone(),
+                    kind: ChangeKind::Staged,
+                    rewritten_as: None,
+                })
+            })?);
 
-        // When attempting to commit a change to filea.bin on repo b, there should be a conflict at the head of
-        // repo A.
+        // Once committed, the snapshot is removed from the global graph.
+        git_cmd(harness.local_repo_a, &["commit", "-m", "Commit the staged change."])?;
+        assert!(harness.global_graph.find_reference(&format!("refs/gg-index/{}", uuid_a)).is_err());
+
+        return Ok(());
+    })
+}
+
+/// With working tree synchronization enabled, modified lockable files are reported as being edited,
+/// while modified files that aren't lockable are not synchronized.
+#[test]
+fn has_conflict_working_tree() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "new text b!")])?;
+
+        git_cmd(harness.local_repo_a, &["config", "globalgraph.worktreesync", "true"])?;
+        fs::write(harness.local_repo_a.workdir().unwrap().join(".gitattributes"), "*.bin lockable\nfilea.bin text")?;
+        fs::write(harness.local_repo_a.workdir().unwrap().join("filea.bin"), "editing a!\r\n")?;
+        fs::write(harness.local_repo_a.workdir().unwrap().join("filea.txt"), "editing a!")?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
+
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        let snapshot = harness.global_graph.find_reference(&format!("refs/gg-worktree/{}", uuid_a))?.peel_to_commit()?;
+
+        // The snapshot has the version of the file a commit would have, after its clean filters.
+        let snapshot_blob = harness.global_graph.find_blob(snapshot.tree()?.get_path(&PathBuf::from("filea.bin"))?.id())?;
+        assert_eq!(snapshot_blob.content(), b"editing a!\n");
+
+        let request = shared::ConflictsAfterCommitRequest {
+            repo_uuid: uuid_b,
+            files: vec![GitPath::new("filea.bin"), GitPath::new("filea.txt")],
+            repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+            other_parents: vec!(),
+            applied_commits: vec!(),
+        };
         let response = make_conflicts_after_commit_request(harness.server, &request);
-        let repo_a_head = CommitSha::new(&harness.local_repo_a.head()?.peel_to_commit()?.id().to_string());
         assert_eq!(
             response,
             serde_json::to_value(shared::ConflictsAfterCommitResponse {
                 conflicts: vec!(shared::UnintegratedChange {
                     file: GitPath::new("filea.bin"),
-                    commit: repo_a_head,
+                    commit: CommitSha::new(&snapshot.id().to_string()),
                     branch: ReferencePath("refs/heads/master".into()),
                     repo_uuid: uuid_a,
+                    kind: ChangeKind::WorkingTree,
+                    rewritten_as: None,
                 })
             })?);
 
@@ -86,7 +752,6 @@ fn has_conflict_standard() -> Result<(), Error> {
     })
 }
 
-
 /// Verifies conflicts are found by the pre-commit hook and the hook aborts properly.
 #[test]
 fn has_conflict_hooks_only() -> Result<(), Error> {
@@ -108,6 +773,410 @@ fn has_conflict_hooks_only() -> Result<(), Error> {
     })
 }
 
+/// Commits that skipped the pre-commit hook should still be stopped by the pre-push hook before they reach origin.
+#[test]
+fn has_conflict_pre_push() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+
+        // Commit a conflicting change to filea.bin in local_repo_b without running the pre-commit hook.
+        fs::write(harness.local_repo_b.workdir().unwrap().join("filea.bin"), "conflicting text in a")?;
+        git_cmd(harness.local_repo_b, &["add", "filea.bin"])?;
+        git_cmd(harness.local_repo_b, &["commit", "--no-verify", "-m", "Skip the conflicts check."])?;
+
+        match git_cmd(harness.local_repo_b, &["push", "origin", "master"]) {
+            Ok(_) => panic!("Local repo b should have returned an error when trying to push."),
+            // Unlike the pre-commit hook's, git doesn't redirect the pre-push hook's output to stderr.
+            Err(e) => assert!(String::from_utf8_lossy(&e.output.stdout).contains("Exiting with status: [2]"))
+        }
+
+        // Changes to files that aren't lockable can still be pushed.
+        git_cmd(harness.local_repo_b, &["reset", "--hard", "origin/master"])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./Readme.md"), "Not lockable")])?;
+        git_cmd(harness.local_repo_b, &["push", "origin", "master"])?;
+
+        return Ok(());
+    })
+}
+/// The dispatcher passes the hook's arguments and input to every hook in `<hook>.d/`, and stops a blocking hook on
+/// the first failure.
+#[test]
+fn dispatcher_forwards_arguments_and_input() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let hooks_dir = harness.local_repo_b.path().join("hooks");
+        let hookd_dir = hooks_dir.join("pre-push.d");
+        fs::create_dir(&hookd_dir)?;
+
+        // Installed the way the installer does: a dispatcher, and a hook script that runs the client executable.
+        fs::remove_file(hooks_dir.join(format!("pre-push{}", std::env::consts::EXE_SUFFIX)))?;
+        fs::write(hooks_dir.join("pre-push"), client::dispatcher::dispatcher_script("pre-push"))?;
+        make_executable(&hooks_dir.join("pre-push"))?;
+        fs::write(hookd_dir.join("globalgraph"), client::dispatcher::hook_script("pre-push"))?;
+        make_executable(&hookd_dir.join("globalgraph"))?;
+
+        // A hook the repository had before, which records what it was given.
+        let log_path = harness.local_repo_b.path().join("pre-push.log");
+        fs::write(hookd_dir.join("custom"), format!(
+            "#!/bin/sh\necho \"$1\" > '{log}'\ncat >> '{log}'\nexit $(cat '{log}.status' 2>/dev/null || echo 0)\n",
+            log = log_path.to_string_lossy().replace('\\', "/")))?;
+        make_executable(&hookd_dir.join("custom"))?;
+
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "some other text")])?;
+        git_cmd(harness.local_repo_b, &["push", "origin", "master"])?;
+
+        let log = fs::read_to_string(&log_path)?;
+        assert!(log.starts_with("origin\n"));
+        assert!(log.contains("refs/heads/master"));
+
+        // A failing hook stops the push.
+        fs::write(harness.local_repo_b.path().join("pre-push.log.status"), "1")?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./Readme.md"), "Not lockable")])?;
+        assert!(git_cmd(harness.local_repo_b, &["push", "origin", "master"]).is_err());
+
+        return Ok(());
+    })
+}
+
+/// The install manifest records the installed files and config, and finds the ones removed or changed since.
+#[test]
+fn install_manifest_finds_changes() -> Result<(), Error> {
+    use client::manifest::{InstallManifest, InstallationProblem, InstalledFileKind};
+    init_logging();
+
+    create_integration_test(|harness| {
+        let repo = harness.local_repo_b;
+        let hooks_dir = repo.path().join("hooks");
+        fs::create_dir(hooks_dir.join("pre-push.d"))?;
+        fs::write(hooks_dir.join("pre-push"), client::dispatcher::dispatcher_script("pre-push"))?;
+        fs::write(hooks_dir.join("pre-push.d").join("globalgraph"), client::dispatcher::hook_script("pre-push"))?;
+
+        let mut manifest = InstallManifest::new();
+        manifest.add_file(repo, InstalledFileKind::ClientExecutable)?;
+        manifest.add_file(repo, InstalledFileKind::Dispatcher("pre-push".to_string()))?;
+        manifest.add_file(repo, InstalledFileKind::HookScript("pre-push".to_string()))?;
+        manifest.add_config(repo, "globalgraph.server")?;
+        client::manifest::write_manifest(repo, &manifest)?;
+
+        let manifest = client::manifest::read_manifest(repo)?.expect("The manifest was written.");
+        assert_eq!(manifest.client_version.as_ref().map(|version| version.as_str()), Some(client::VERSION));
+        assert_eq!(manifest.files[2].path, "hooks/pre-push.d/globalgraph");
+        assert!(client::manifest::find_problems(repo, &manifest)?.is_empty());
+
+        fs::write(hooks_dir.join("pre-push.d").join("globalgraph"), "#!/bin/sh\nexit 0\n")?;
+        fs::remove_file(hooks_dir.join("pre-push"))?;
+        git_cmd(repo, &["config", "globalgraph.server", "http://localhost:1"])?;
+
+        let problems = client::manifest::find_problems(repo, &manifest)?;
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], InstallationProblem::MissingFile(manifest.files[1].clone()));
+        assert_eq!(problems[1], InstallationProblem::ModifiedFile(manifest.files[2].clone()));
+        match problems[2] {
+            InstallationProblem::ChangedConfig { ref name, ref actual, .. } => {
+                assert_eq!(name, "globalgraph.server");
+                assert_eq!(actual.as_ref().map(|actual| actual.as_str()), Some("http://localhost:1"));
+            }
+            ref problem => panic!("Unexpected problem: {}", problem),
+        }
+
+        // The client records the files it replaces itself, which aren't reported.
+        client::manifest::record_files(repo, &[InstalledFileKind::HookScript("pre-push".to_string())])?;
+        let manifest = client::manifest::read_manifest(repo)?.expect("The manifest was written.");
+        assert_eq!(client::manifest::find_problems(repo, &manifest)?.len(), 2);
+
+        return Ok(());
+    })
+}
+
+/// The server advertises the newest signed client release it hosts, and serves its executable to clients updating
+/// themselves.
+#[test]
+fn server_hosts_client_releases() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let response = make_info_request(harness.server);
+        assert_eq!(response["current_client_version"], Value::Null);
+
+        for version in &["0.9.0", "0.10.0"] {
+            let release_directory = server::client_release_directory(harness.server_work_directory, version, "linux-x86_64");
+            fs::create_dir_all(&release_directory)?;
+            fs::write(release_directory.join("globalgraph"), format!("globalgraph-client-version={};", version))?;
+            fs::write(release_directory.join("globalgraph.sig"), "c2lnbmF0dXJl\n")?;
+        }
+
+        // Releases without a signature aren't advertised.
+        let unsigned_directory = server::client_release_directory(harness.server_work_directory, "1.0.0", "linux-x86_64");
+        fs::create_dir_all(&unsigned_directory)?;
+        fs::write(unsigned_directory.join("globalgraph"), "globalgraph-client-version=1.0.0;")?;
+
+        let response = make_info_request(harness.server);
+        assert_eq!(response["current_client_version"], "0.10.0");
+        assert_eq!(response["client_releases"].as_array().unwrap().len(), 2);
+        assert_eq!(response["client_releases"][1]["signature"], "c2lnbmF0dXJl");
+
+        let release: shared::ClientRelease = serde_json::from_value(response["client_releases"][1].clone())?;
+        assert!(client::update::verify_release("rIdcHw6bx9k5Ifa3LUFbL3i+bQfg5U3OMpCPyA6yA+g=", &release, b"globalgraph-client-version=0.10.0;").is_err());
+
+        // Signed with the ed25519 key whose seed is 32 bytes of 7.
+        let signed_release = shared::ClientRelease {
+            signature: "daNBgEVe4BeaF5DAS1mvWdmYU/W/jlMRY3BYcPWbzbDVdnWBBenEbgkfwzTrx5iPzfCIrXjLlpkdmqQegXUBAw==".to_string(),
+            ..release.clone()
+        };
+        let public_key = "6kpsY+KcUgq+9VB7Ey7F+ZVHdq6+vnuSQh7qaRRG0iw=";
+        assert!(client::update::verify_release(public_key, &signed_release, b"globalgraph-client-version=0.10.0;").is_ok());
+        assert!(client::update::verify_release(public_key, &signed_release, b"globalgraph-client-version=0.9.0;").is_err());
+
+        let request = harness.server.client(http::Method::GET, "/v1/clients/0.10.0/linux-x86_64").finish().unwrap();
+        let response = harness.server.execute(request.send()).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = harness.server.execute(response.body()).unwrap();
+        assert_eq!(shared::find_client_version(&bytes), Some("0.10.0".to_string()));
+
+        let request = harness.server.client(http::Method::GET, "/v1/clients/0.10.0/windows-x86_64").finish().unwrap();
+        let response = harness.server.execute(request.send()).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        return Ok(());
+    })
+}
+
+/// Committing a merge should count the merged head as integrated, so files the merge brings in don't conflict.
+#[test]
+fn merge_commit_integrates_merge_head() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+        git_cmd(harness.local_repo_a, &["push", "origin", "master"])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "some other text")])?;
+
+        // The pre-commit hook sees filea.bin staged by the merge, which only MERGE_HEAD integrates.
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        git_cmd(harness.local_repo_b, &["merge", "--no-commit", "--no-ff", "origin/master"])?;
+        let next_commit = client::query::NextCommit::in_progress(harness.local_repo_b)?;
+        assert_eq!(next_commit.other_parents, vec![CommitSha::new(&harness.local_repo_a.head()?.target().unwrap().to_string())]);
+        let files = vec![GitPath::new("filea.bin")];
+        let head_only = client::query::NextCommit::on(next_commit.head.clone());
+        assert_eq!(client::query::find_unintegrated_changes(harness.local_repo_b, head_only, files.clone())?.len(), 1);
+        assert!(client::query::find_unintegrated_changes(harness.local_repo_b, next_commit, files)?.is_empty());
+
+        git_cmd(harness.local_repo_b, &["commit", "-m", "Merge origin/master."])?;
+        assert_eq!(harness.local_repo_b.head()?.peel_to_commit()?.parent_count(), 2);
+
+        return Ok(());
+    })
+}
+
+/// Cherry-picking another user's change integrates it, even though the original commit isn't in the head's history.
+#[test]
+fn cherry_picked_change_is_integrated() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+        let change = harness.local_repo_a.head()?.peel_to_commit()?.id().to_string();
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./Readme.md"), "Not lockable")])?;
+        git_cmd(harness.local_repo_a, &["push", "origin", "master:textures"])?;
+
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        git_cmd(harness.local_repo_b, &["cherry-pick", &change])?;
+
+        // Repo b has the same version of filea.bin now, so it can change it too.
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./filea.bin"), "new text in b")])?;
+
+        return Ok(());
+    })
+}
+
+/// Cherry-picking a whole long-lived branch integrates it, including the older commits other branches still point at.
+#[test]
+fn cherry_picked_long_lived_branch_is_integrated() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        // Repo a leaves a branch behind at each commit of its long-lived branch.
+        let start = harness.local_repo_a.head()?.target().unwrap().to_string();
+        git_cmd(harness.local_repo_a, &["checkout", "-b", "textures"])?;
+        for i in 0..5 {
+            let (text_a, text_b) = (format!("filea version {}", i), format!("fileb version {}", i));
+            change_and_commit(harness.local_repo_a, &[
+                (&PathBuf::from("./filea.bin"), text_a.as_str()),
+                (&PathBuf::from("./fileb.bin"), text_b.as_str()),
+            ])?;
+            git_cmd(harness.local_repo_a, &["branch", &format!("textures-{}", i)])?;
+        }
+        git_cmd(harness.local_repo_a, &["push", "origin", "textures"])?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
+
+        // Repo b picks every commit of the branch, so each branch's latest change is equivalent to one of its own.
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        git_cmd(harness.local_repo_b, &["cherry-pick", &format!("{}..origin/textures", start)])?;
+        let head = client::query::head_commit(harness.local_repo_b)?;
+        let files = vec![GitPath::new("filea.bin"), GitPath::new("fileb.bin")];
+        assert!(client::query::find_unintegrated_changes(harness.local_repo_b, client::query::NextCommit::on(head.clone()), files.clone())?.is_empty());
+
+        // A later change to the branch isn't integrated.
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "filea version 5")])?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
+        let conflicts = client::query::find_unintegrated_changes(harness.local_repo_b, client::query::NextCommit::on(head), files)?;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].file, GitPath::new("filea.bin"));
+
+        return Ok(());
+    })
+}
+
+/// Reverting another user's change integrates it, like cherry-picking it would.
+#[test]
+fn reverted_change_is_integrated() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+        let change = harness.local_repo_a.head()?.peel_to_commit()?.id().to_string();
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "more text in a")])?;
+        git_cmd(harness.local_repo_a, &["push", "origin", &format!("{}:textures", change)])?;
+
+        // Repo b takes the change, then repo a changes filea.bin again before b reverts it.
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        git_cmd(harness.local_repo_b, &["merge", "--ff-only", "origin/textures"])?;
+        git_cmd(harness.local_repo_b, &["revert", "--no-commit", "HEAD"])?;
+
+        let next_commit = client::query::NextCommit::in_progress(harness.local_repo_b)?;
+        assert_eq!(next_commit.applied_commits, vec![CommitSha::new(&change)]);
+        let conflicts = client::query::find_unintegrated_changes(harness.local_repo_b, next_commit, vec![GitPath::new("filea.bin")])?;
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].commit, CommitSha::new(&harness.local_repo_a.head()?.target().unwrap().to_string()));
+
+        return Ok(());
+    })
+}
+
+/// A patch made from another user's commit and applied with `git am` integrates the commit.
+#[test]
+fn patch_applied_with_am_is_integrated() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[
+            (&PathBuf::from("./filea.bin"), "some text in a"),
+            (&PathBuf::from("./Readme.md"), "Readme from a"),
+        ])?;
+        let change = harness.local_repo_a.head()?.peel_to_commit()?.id().to_string();
+        let patches = harness.local_repo_a.workdir().unwrap().parent().unwrap().join("patches");
+        git_cmd(harness.local_repo_a, &["format-patch", "-1", "-o", &patches.to_string_lossy()])?;
+
+        // The patch doesn't apply on top of repo b's Readme.md, so `git am` stops before committing.
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./Readme.md"), "Readme from b")])?;
+        let patch = fs::read_dir(&patches)?.next().unwrap()?.path();
+        assert!(git_cmd(harness.local_repo_b, &["am", &patch.to_string_lossy()]).is_err());
+
+        let next_commit = client::query::NextCommit::in_progress(harness.local_repo_b)?;
+        assert_eq!(next_commit.applied_commits, vec![CommitSha::new(&change)]);
+        let files = vec![GitPath::new("filea.bin")];
+        let head_only = client::query::NextCommit::on(next_commit.head.clone());
+        assert_eq!(client::query::find_unintegrated_changes(harness.local_repo_b, head_only, files.clone())?.len(), 1);
+        assert!(client::query::find_unintegrated_changes(harness.local_repo_b, next_commit, files)?.is_empty());
+
+        return Ok(());
+    })
+}
+
+/// A linked worktree shares the branches of its repository, but synchronizes its own snapshots, on
+/// top of its own HEAD. The snapshots of a removed worktree are deleted from the global graph.
+#[test]
+fn linked_worktree_has_its_own_snapshots() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        let worktree_path = harness.local_repo_a.workdir().unwrap().parent().unwrap().join("feature");
+        git_cmd(harness.local_repo_a, &["worktree", "add", "-b", "feature", &worktree_path.to_string_lossy()])?;
+        let worktree = git2::Repository::open(&worktree_path)?;
+
+        // The hooks of the repository run in the worktree.
+        change_and_commit(&worktree, &[(&PathBuf::from("./fileb.bin"), "worktree text!")])?;
+        let uuid_a = harness.local_repo_a.config()?.get_string("globalgraph.repouuid")?;
+        let feature = harness.global_graph.find_branch(&format!("{}/feature", uuid_a), BranchType::Local)?;
+        assert_eq!(feature.get().target(), Some(worktree.head()?.peel_to_commit()?.id()));
+
+        fs::write(harness.local_repo_a.workdir().unwrap().join("filea.bin"), "staged in the main working tree!")?;
+        git_cmd(harness.local_repo_a, &["add", "filea.bin"])?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
+
+        fs::write(worktree_path.join("filea.bin"), "staged in the worktree!")?;
+        git_cmd(&worktree, &["add", "filea.bin"])?;
+        client::synchronize_local_repository(&worktree_path)?;
+
+        let main_snapshot = harness.global_graph.find_reference(&format!("refs/gg-index/{}", uuid_a))?.peel_to_commit()?;
+        assert_eq!(main_snapshot.parent_ids().next(), Some(harness.local_repo_a.head()?.peel_to_commit()?.id()));
+        let worktree_snapshot = harness.global_graph.find_reference(&format!("refs/gg-index/{}+feature", uuid_a))?.peel_to_commit()?;
+        assert_eq!(worktree_snapshot.parent_ids().next(), Some(worktree.head()?.peel_to_commit()?.id()));
+        assert_eq!(shared::parse_snapshot_branch(worktree_snapshot.message().unwrap()), Some(ReferencePath::new("refs/heads/feature")));
+
+        git_cmd(harness.local_repo_a, &["worktree", "remove", "--force", &worktree_path.to_string_lossy()])?;
+        client::synchronize_local_repository(harness.local_repo_a.workdir().unwrap())?;
+        assert!(harness.global_graph.find_reference(&format!("refs/gg-index/{}+feature", uuid_a)).is_err());
+        assert!(harness.global_graph.find_reference(&format!("refs/gg-index/{}", uuid_a)).is_ok());
+
+        return Ok(());
+    })
+}
+
+/// Rebasing onto a branch that contains another user's change integrates it, once the rebased branch is synchronized.
+#[test]
+fn rebase_onto_other_users_change() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+        git_cmd(harness.local_repo_a, &["push", "origin", "master"])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./file_other.bin"), "some other text")])?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./Readme.md"), "Not lockable")])?;
+
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        let output = git_cmd_output(harness.local_repo_b, &["rebase", "origin/master"])?;
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("conflicts with work in other repositories"));
+
+        let uuid_b = harness.local_repo_b.config()?.get_string("globalgraph.repouuid")?;
+        let synchronized = harness.global_graph.find_branch(&format!("{}/master", uuid_b), BranchType::Local)?.get().peel_to_commit()?.id();
+        assert_eq!(synchronized, harness.local_repo_b.head()?.peel_to_commit()?.id());
+
+        // Repo b contains the change to filea.bin now, so it can change it too. A branch that no client pushed doesn't
+        // stop the check.
+        let stray_commit = harness.global_graph.find_commit(synchronized)?;
+        harness.global_graph.branch("not_in_a_namespace", &stray_commit, false)?;
+        change_and_commit(harness.local_repo_b, &[(&PathBuf::from("./filea.bin"), "new text in b")])?;
+
+        return Ok(());
+    })
+}
+
+/// Commits replayed by a rebase skip the pre-commit hook, so each one is checked against the commit the branch is
+/// rebased onto.
+#[test]
+fn rebase_replays_conflicting_commit() -> Result<(), Error> {
+    init_logging();
+
+    create_integration_test(|harness| {
+        change_and_commit(harness.local_repo_a, &[(&PathBuf::from("./filea.bin"), "some text in a")])?;
+
+        // Commit a conflicting change to filea.bin in local_repo_b without running the pre-commit hook.
+        fs::write(harness.local_repo_b.workdir().unwrap().join("filea.bin"), "conflicting text in a")?;
+        git_cmd(harness.local_repo_b, &["add", "filea.bin"])?;
+        git_cmd(harness.local_repo_b, &["commit", "--no-verify", "-m", "Skip the conflicts check."])?;
+
+        // origin/master doesn't contain the change from local_repo_a either.
+        git_cmd(harness.local_repo_b, &["fetch", "origin"])?;
+        let output = git_cmd_output(harness.local_repo_b, &["rebase", "--force-rebase", "origin/master"])?;
+        assert!(String::from_utf8_lossy(&output.stderr).contains("The replayed commit conflicts with work in other repositories"));
+
+        return Ok(());
+    })
+}
 
 /// Heads that are detached should still properly block commits if there is a conflict in a branch in the Global Graph
 #[test]
@@ -148,6 +1217,8 @@ fn no_conflicts() -> Result<(), Error> {
             repo_uuid: uuid,
             files: vec![GitPath::new("./filea.bin")],
             repo_head_commit: Some(CommitSha::new(&harness.local_repo_b.head()?.peel_to_commit()?.id().to_string())),
+            other_parents: vec!(),
+            applied_commits: vec!(),
         };
         let response = make_conflicts_after_commit_request(harness.server, &request);
         assert_eq!(
@@ -214,6 +1285,55 @@ fn make_conflicts_after_commit_request(test_server: &mut TestServer, payload: &s
     return to_serde(body);
 }
 
+fn make_unintegrated_changes_request(test_server: &mut TestServer, payload: &shared::UnintegratedChangesRequest) -> Value {
+    let request = test_server.client(http::Method::POST, "/v1/unintegrated_changes")
+        .content_type("application/json")
+        .body(serde_json::to_string(payload).unwrap()).unwrap();
+
+    let response = test_server.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    let body = json::parse(str::from_utf8(&bytes).unwrap()).unwrap();
+    return to_serde(body);
+}
+
+fn make_info_request(test_server: &mut TestServer) -> Value {
+    let request = test_server.client(http::Method::GET, "/v1/info").finish().unwrap();
+
+    let response = test_server.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    let body = json::parse(str::from_utf8(&bytes).unwrap()).unwrap();
+    return to_serde(body);
+}
+
+fn make_delete_namespace_request(test_server: &mut TestServer, payload: &shared::DeleteNamespaceRequest) -> Value {
+    let request = test_server.client(http::Method::POST, "/v1/delete_namespace")
+        .content_type("application/json")
+        .body(serde_json::to_string(payload).unwrap()).unwrap();
+
+    let response = test_server.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    let body = json::parse(str::from_utf8(&bytes).unwrap()).unwrap();
+    return to_serde(body);
+}
+
+fn make_project_lookup_request(test_server: &mut TestServer, payload: &shared::ProjectLookupRequest) -> Value {
+    let request = test_server.client(http::Method::POST, "/v1/project_lookup")
+        .content_type("application/json")
+        .body(serde_json::to_string(payload).unwrap()).unwrap();
+
+    let response = test_server.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    let body = json::parse(str::from_utf8(&bytes).unwrap()).unwrap();
+    return to_serde(body);
+}
 
 fn to_serde(value: JsonValue) -> Value {
     let value: Value = serde_json::from_str(&value.to_string()).unwrap();
@@ -32,6 +32,8 @@ pub struct TestHarness<'a> {
     pub origin_repo: &'a Repository,
     pub global_graph: &'a Repository,
     pub server: &'a mut test::TestServer,
+    /// The working directory of the server, eg. for the client releases it hosts.
+    pub server_work_directory: &'a Path,
 }
 
 /// Simple wrapper to create a couple of temporary repositories to run a test with.
@@ -71,6 +73,8 @@ pub fn create_integration_test<F>(test_body: F) -> Result<(), Error>
     git_cmd(&locala_repo, &["remote", "add", "origin", &origin_repo_url.clone().to_string_lossy()])?;
     git_cmd(&locala_repo, &["config", "user.name", "Test User A"])?;
     git_cmd(&locala_repo, &["config", "globalgraph.server", &server_url])?;
+    git_cmd(&locala_repo, &["config", "globalgraph.backgroundsync", "false"])?;
+    git_cmd(&locala_repo, &["config", "globalGraph.installed", "installed"])?;
 
     debug!("Creating an origin repo at {:?}", &origin_repo_path);
     let global_repo = Repository::init_bare(&global_repo_path)?;
@@ -90,6 +94,8 @@ pub fn create_integration_test<F>(test_body: F) -> Result<(), Error>
     git_cmd(&localb_repo, &["remote", "add", shared::GLOBALGRAPH_REPO_NAME, &global_repo_url.clone().to_string_lossy()])?;
     git_cmd(&localb_repo, &["config", "user.name", "Test User B"])?;
     git_cmd(&localb_repo, &["config", "globalgraph.server", &server_url])?;
+    git_cmd(&localb_repo, &["config", "globalgraph.backgroundsync", "false"])?;
+    git_cmd(&localb_repo, &["config", "globalGraph.installed", "installed"])?;
 
     trace!("Starting test.");
     test_body(TestHarness {
@@ -98,6 +104,7 @@ pub fn create_integration_test<F>(test_body: F) -> Result<(), Error>
         origin_repo: &origin_repo,
         global_graph: &global_repo,
         server: &mut srv,
+        server_work_directory: &server_work_dir,
     })
 }
 
@@ -183,20 +190,24 @@ impl RepositoryTestExtensions for Repository {
 }
 
 /// Installs all git-sync hooks to a git repository for a test.
-#[cfg(windows)]
 pub fn install_all_hooks(repo: &Repository) -> Result<(), Error> {
     debug!("Installing hooks to [{:?}]", repo.path());
 
-    let hooks_dir = repo.path().join(r"hooks\");
+    let hooks_dir = repo.path().join("hooks");
     install_hook(&hooks_dir, "pre-commit")?;
+    install_hook(&hooks_dir, "pre-push")?;
     install_hook(&hooks_dir, "post-commit")?;
     install_hook(&hooks_dir, "post-rewrite")?;
     install_hook(&hooks_dir, "post-merge")?;
+    install_hook(&hooks_dir, "reference-transaction")?;
+    install_hook(&hooks_dir, client::CLIENT_EXECUTABLE_NAME)?;
 
     Ok(())
 }
 
-#[cfg(windows)]
+/// Installs the client executable built in debug mode for the current platform as a hook. Named after the
+/// hook, the executable runs that hook. Git runs `<hook>.exe` on Windows, and `<hook>` elsewhere, as long as it
+/// is executable.
 pub fn install_hook<P: AsRef<Path>>(hooks_dir: P, hook_name: &str) -> Result<(), Error> {
     let hooks_dir = hooks_dir.as_ref();
 
@@ -204,13 +215,30 @@ pub fn install_hook<P: AsRef<Path>>(hooks_dir: P, hook_name: &str) -> Result<(),
         fs::create_dir(&hooks_dir)?;
     }
 
-    let hook_src = std::env::current_dir().unwrap().join(format!("../../target/debug/{}.exe", hook_name));
-    let hook_dst = hooks_dir.join(format!("{}.exe", hook_name));
+    let executable_name = format!("{}{}", client::CLIENT_EXECUTABLE_NAME, std::env::consts::EXE_SUFFIX);
+    let hook_src = std::env::current_dir().unwrap().join("../../target/debug").join(&executable_name);
+    let hook_dst = hooks_dir.join(format!("{}{}", hook_name, std::env::consts::EXE_SUFFIX));
 
     trace!("Copying [{:?}] to [{:?}]", &hook_src, &hook_dst);
     fs::copy(&hook_src, &hook_dst)
         .context(format!("Couldn't install [[{}] hook. ", hook_name))?;
+    make_executable(&hook_dst)?;
+
+    Ok(())
+}
+
+/// Marks a file as executable, so git runs it as a hook.
+#[cfg(unix)]
+pub fn make_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(0o755);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
 
+#[cfg(not(unix))]
+pub fn make_executable(_path: &Path) -> Result<(), Error> {
     Ok(())
 }
 
@@ -231,6 +259,13 @@ impl fmt::Display for CommandError {
 /// Runs a git command with the embedded git binary. For testing only.
 #[must_use]
 pub fn git_cmd(repo: &Repository, arguments: &[&str]) -> Result<(), CommandError> {
+    git_cmd_output(repo, arguments).map(|_| ())
+}
+
+/// Runs a git command with the embedded git binary, and returns its output, which includes the
+/// logging of any hook it ran. For testing only.
+#[must_use]
+pub fn git_cmd_output(repo: &Repository, arguments: &[&str]) -> Result<Output, CommandError> {
     // Locate the proper git command
     let cmd = git_cmd_path();
 
@@ -252,7 +287,7 @@ pub fn git_cmd(repo: &Repository, arguments: &[&str]) -> Result<(), CommandError
     }
 
     if output.status.success() {
-        return Ok(());
+        return Ok(output);
     } else {
         error!("Git command [{:?}] failed.", arguments);
         error!("Git command stdout:\n{}", String::from_utf8_lossy(&output.stdout));
@@ -269,6 +304,13 @@ fn git_cmd_path() -> PathBuf {
     return PathBuf::from(env!("CARGO_MANIFEST_DIR").to_owned()).join(r"..\..\git_bin\win\bin\git.exe");
 }
 
+/// Returns the git executable on the PATH. There is no embedded git outside of Windows. The
+/// reference-transaction hook needs git 2.28 or later.
+#[cfg(not(windows))]
+fn git_cmd_path() -> PathBuf {
+    return PathBuf::from("git");
+}
+
 pub fn change_and_commit<P: AsRef<Path>>(repo: &Repository, changes: &[(P, &str)]) -> Result<(), Error> {
     for &(ref file, ref text) in changes {
         let filepath = repo.workdir().unwrap().join(file);